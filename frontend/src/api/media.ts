@@ -3,11 +3,66 @@
  */
 
 import type { DiscoveredDevice } from "./discovery";
+import type { MediaStatus } from "./status";
 
 export type ReceiverOptions = "Default" | "YouTube" | "Web";
 
 export type StreamTypeOptions = "Live" | "Buffered" | "None";
 
+export type HlsSegmentFormat =
+  | "Aac"
+  | "Ac3"
+  | "Mp3"
+  | "Ts"
+  | "TsAac"
+  | "EAc3"
+  | "Fmp4";
+
+export type HlsVideoSegmentFormat = "Mpeg2Ts" | "Fmp4";
+
+/**
+ * How media is served to the device through Castiel. `Mp4` and `Hls` remux the
+ * source with ffmpeg.
+ */
+export type RelayMode = "Direct" | "Mp4" | "Hls";
+
+/**
+ * A WebVTT subtitle or caption track to load with media on the Default receiver.
+ */
+export interface TextTrack {
+  url: string;
+  /** BCP 47 language tag, e.g. "en-US" */
+  language?: string;
+  /** The name shown in the receiver's track picker */
+  label?: string;
+  kind?: "Subtitles" | "Captions" | "Descriptions" | "Chapters" | "Metadata";
+  /** Whether the track is shown as soon as playback starts */
+  enabled?: boolean;
+}
+
+/**
+ * How text tracks are drawn. Colors are "#RRGGBBAA" hex strings.
+ */
+export interface TextTrackStyle {
+  foreground_color?: string;
+  background_color?: string;
+  edge_type?: "None" | "Outline" | "DropShadow" | "Raised" | "Depressed";
+  edge_color?: string;
+  window_type?: "None" | "Normal" | "RoundedCorners";
+  window_color?: string;
+  font_scale?: number;
+  font_family?: string;
+  font_generic_family?:
+    | "SansSerif"
+    | "MonospacedSansSerif"
+    | "Serif"
+    | "MonospacedSerif"
+    | "Casual"
+    | "Cursive"
+    | "SmallCapitals";
+  font_style?: "Normal" | "Bold" | "BoldItalic" | "Italic";
+}
+
 /**
  * Sends media data to a specific Chromecast receiver.
  *
@@ -20,8 +75,24 @@ export async function sendMediaToReceiver(
   mediaSettings: {
     receiver: ReceiverOptions;
     mediaUrl: string;
+    /** Left blank to let the server infer the content type. */
     contentType: string;
-    streamType: StreamTypeOptions;
+    /** Left undefined to let the server infer the stream type. */
+    streamType?: StreamTypeOptions;
+    textTracks?: TextTrack[];
+    textTrackStyle?: TextTrackStyle;
+    /** Where to start playback, in seconds */
+    startTime?: number;
+    /** A YouTube playlist to play the video as part of */
+    playlistId?: string;
+    /** Left undefined to let the server infer it from the HLS media playlist. */
+    hlsSegmentFormat?: HlsSegmentFormat;
+    /** Left undefined to let the server infer it from the HLS media playlist. */
+    hlsVideoSegmentFormat?: HlsVideoSegmentFormat;
+    /** Reload the media when playback fails or stops by itself */
+    autoRestart?: boolean;
+    /** Relay the media through Castiel instead of having the device fetch it */
+    relay?: RelayMode;
   }
 ): Promise<void> {
   try {
@@ -35,8 +106,16 @@ export async function sendMediaToReceiver(
         port: device.port,
         receiver: mediaSettings.receiver,
         media_url: mediaSettings.mediaUrl,
-        content_type: mediaSettings.contentType,
+        content_type: mediaSettings.contentType || undefined,
         stream_type: mediaSettings.streamType,
+        text_tracks: mediaSettings.textTracks,
+        text_track_style: mediaSettings.textTrackStyle,
+        start_time: mediaSettings.startTime,
+        playlist_id: mediaSettings.playlistId,
+        hls_segment_format: mediaSettings.hlsSegmentFormat,
+        hls_video_segment_format: mediaSettings.hlsVideoSegmentFormat,
+        auto_restart: mediaSettings.autoRestart,
+        relay: mediaSettings.relay,
       }),
     });
 
@@ -82,3 +161,100 @@ export async function stopMediaAtReceiver(
     throw error;
   }
 }
+
+/**
+ * Enables the given text and audio tracks of the media playing on a device and disables the rest.
+ *
+ * @param device - The Chromecast device playing the media.
+ * @param activeTrackIds - IDs of the tracks to enable, as listed in the media status.
+ * @returns Promise resolving with the updated media status.
+ */
+export async function setActiveTracks(
+  device: DiscoveredDevice,
+  activeTrackIds: number[]
+): Promise<MediaStatus> {
+  const response = await fetch("api/set-active-tracks", {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      ip: device.ip_address,
+      port: device.port,
+      active_track_ids: activeTrackIds,
+    }),
+  });
+
+  if (!response.ok) {
+    throw new Error(
+      `Failed to set active tracks: ${response.status} ${response.statusText}`
+    );
+  }
+
+  return (await response.json()) as MediaStatus;
+}
+
+/**
+ * Changes how the text tracks of the media playing on a device are drawn.
+ *
+ * @param device - The Chromecast device playing the media.
+ * @param style - The style to apply. Fields left out keep their current value.
+ * @returns Promise resolving with the updated media status.
+ */
+export async function setTextTrackStyle(
+  device: DiscoveredDevice,
+  style: TextTrackStyle
+): Promise<MediaStatus> {
+  const response = await fetch("api/set-text-track-style", {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      ip: device.ip_address,
+      port: device.port,
+      style,
+    }),
+  });
+
+  if (!response.ok) {
+    throw new Error(
+      `Failed to set text track style: ${response.status} ${response.statusText}`
+    );
+  }
+
+  return (await response.json()) as MediaStatus;
+}
+
+/**
+ * Adds a video to the queue of the YouTube receiver on a device.
+ *
+ * @param device - The Chromecast device running the YouTube receiver.
+ * @param video - A YouTube video ID or video URL.
+ * @param position - Whether to add the video at the end of the queue or play it next.
+ * @returns Promise resolving when the video is queued.
+ */
+export async function queueYouTubeVideo(
+  device: DiscoveredDevice,
+  video: string,
+  position: "End" | "Next" = "End"
+): Promise<void> {
+  const response = await fetch("api/youtube-queue", {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      ip_address: device.ip_address,
+      port: device.port,
+      media_url: video,
+      position,
+    }),
+  });
+
+  if (!response.ok) {
+    throw new Error(
+      `Failed to queue YouTube video: ${response.status} ${response.statusText}`
+    );
+  }
+}
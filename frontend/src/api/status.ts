@@ -3,6 +3,7 @@ export interface DeviceStatus {
   in_standby: boolean;
   volume: VolumeStatus;
   app_status?: AppStatus;
+  applications: AppStatus[];
 }
 
 interface VolumeStatus {
@@ -10,9 +11,10 @@ interface VolumeStatus {
   muted: boolean;
 }
 
-interface AppStatus {
+export interface AppStatus {
   id: string;
   app_identity: ParsedApp;
+  registered_name?: string;
   display_name: string;
   namespaces: string[];
   session_id: string;
@@ -25,6 +27,7 @@ export type ParsedApp =
   | "DefaultMedia"
   | "YouTube"
   | "WebView"
+  | "Custom"
   | "Unknown";
 
 /**
@@ -54,14 +57,52 @@ export async function fetchDeviceStatus(
   return data as DeviceStatus;
 }
 
-export interface MediaStatus {
+/**
+ * The status of the media sessions on a device. The first session's fields are
+ * repeated at the top level.
+ */
+export interface MediaStatus extends MediaSessionStatus {
+  entries: MediaSessionStatus[];
+}
+
+export interface MediaSessionStatus {
+  media_session_id?: number;
+  media?: MediaInfo;
   current_time?: number;
   playback_rate: number;
   player_state: PlayerState;
+  idle_reason?: IdleReason;
+  supported_media_commands: number;
+  supported_commands: string[];
+  volume?: VolumeStatus;
+  active_track_ids: number[];
+  current_item_id?: number;
+}
+
+export interface MediaInfo {
+  content_id: string;
+  content_url?: string;
+  content_type?: string;
+  stream_type?: "BUFFERED" | "LIVE" | "NONE";
+  duration?: number;
+  metadata?: Record<string, unknown>;
+  tracks: TrackInfo[];
+}
+
+export interface TrackInfo {
+  track_id: number;
+  kind: "TEXT" | "AUDIO" | "VIDEO";
+  track_content_id?: string;
+  track_content_type?: string;
+  name?: string;
+  language?: string;
+  subtype?: string;
 }
 
 export type PlayerState = "Idle" | "Playing" | "Buffering" | "Paused";
 
+export type IdleReason = "Cancelled" | "Interrupted" | "Finished" | "Error";
+
 /**
  * Fetches the status of media playing on a Chromecast device.
  *
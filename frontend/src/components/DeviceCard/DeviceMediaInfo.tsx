@@ -5,6 +5,7 @@ import {
   MonitorPlay,
   ImagesSquare,
   GlobeSimple,
+  AppWindow,
 } from "@phosphor-icons/react";
 import type { JSX } from "react";
 
@@ -48,6 +49,12 @@ export default function DeviceMediaInfo() {
       bgClass: "has-background-link-light",
       textClass: "has-text-link",
     },
+    Custom: {
+      icon: <AppWindow size={48} className="has-text-primary" />,
+      label: status?.app_status?.registered_name ?? "Custom Receiver",
+      bgClass: "has-background-primary-light",
+      textClass: "has-text-primary",
+    },
     Unknown: {
       icon: <Question size={48} className="has-text-white" />,
       label: "Unknown",
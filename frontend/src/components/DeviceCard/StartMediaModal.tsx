@@ -30,7 +30,7 @@ export default function StartMediaModal({
   const [receiverType, setReceiverType] = useState<ReceiverOptions>("Default");
   const [contentId, setContentId] = useState("");
   const [contentType, setContentType] = useState("");
-  const [streamType, setStreamType] = useState<StreamTypeOptions>("None");
+  const [streamType, setStreamType] = useState<StreamTypeOptions | "">("");
 
   const handleSendMedia = async () => {
     try {
@@ -39,7 +39,7 @@ export default function StartMediaModal({
         receiver: receiverType,
         mediaUrl: contentId,
         contentType,
-        streamType,
+        streamType: streamType || undefined,
       });
       // Refresh device status in 500ms.
       setTimeout(() => refreshStatus(), 500);
@@ -116,7 +116,7 @@ export default function StartMediaModal({
                   <input
                     className="input"
                     type="text"
-                    placeholder="e.g. videos/mp4"
+                    placeholder="Detected automatically, e.g. video/mp4"
                     value={contentType}
                     onChange={(e) => setContentType(e.target.value)}
                   />
@@ -129,9 +129,10 @@ export default function StartMediaModal({
                     <select
                       value={streamType}
                       onChange={(e) =>
-                        setStreamType(e.target.value as StreamTypeOptions)
+                        setStreamType(e.target.value as StreamTypeOptions | "")
                       }
                     >
+                      <option value="">Auto-detect</option>
                       <option value="Buffered">Buffered</option>
                       <option value="Live">Live</option>
                       <option value="None">None</option>
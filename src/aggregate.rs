@@ -0,0 +1,358 @@
+//! Collects the status of every known device at once.
+//!
+//! Devices are queried concurrently, each over a single connection and with its own timeout, so
+//! one unresponsive device doesn't hold up the others. When `status_refresh_seconds` is set, a
+//! background task keeps a snapshot of the result up to date.
+
+use std::{
+  sync::{Arc, PoisonError},
+  time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::json;
+use tokio::task::JoinSet;
+
+use crate::{
+  clock::unix_now,
+  config::{ReceiverAppConfig, WebhookEvent},
+  devices::{
+    discovery::{self, DiscoveredDevice},
+    status::{self, DeviceStatus, MediaStatus},
+  },
+  errors::{CastielError, ErrorBody},
+  metrics, mqtt,
+  state::AppState,
+  webhooks::{self, Event, EventDevice},
+};
+
+/// How long a discovery scan listens for devices.
+const DISCOVERY_SECONDS: u64 = 1;
+
+/// How often to check whether background refreshes have been enabled in the settings.
+const DISABLED_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The status of every known device at a point in time.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusSnapshot {
+  /// When the snapshot was collected, in seconds since the Unix epoch.
+  pub generated_at: u64,
+  /// One report per known device.
+  pub devices: Vec<DeviceReport>,
+}
+
+/// The outcome of the most recent discovery scan.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveryScan {
+  /// When the scan finished, in seconds since the Unix epoch.
+  pub finished_at: u64,
+  /// The number of devices found, if the scan succeeded.
+  pub devices_found: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+/// The status of a single device, or the error encountered while fetching it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceReport {
+  pub device: DiscoveredDevice,
+  pub device_status: Option<DeviceStatus>,
+  /// The media status of the foreground app, if it uses the media channel.
+  pub media_status: Option<MediaStatus>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<ErrorBody>,
+}
+
+/// Runs a discovery scan and records the devices found in the state's device registry.
+pub async fn discover_devices(state: &AppState) -> Result<Vec<DiscoveredDevice>, CastielError> {
+  let started = Instant::now();
+  let devices = tokio::task::spawn_blocking(|| discovery::find_chromecasts(DISCOVERY_SECONDS))
+    .await
+    .map_err(|_| CastielError::InternalError)
+    .flatten();
+  metrics::observe_discovery_scan(devices.as_ref().ok().map(Vec::len), started.elapsed());
+  *state
+    .last_discovery
+    .write()
+    .unwrap_or_else(PoisonError::into_inner) = Some(DiscoveryScan {
+    finished_at: unix_now(),
+    devices_found: devices.as_ref().ok().map(Vec::len),
+    error: devices.as_ref().err().map(ToString::to_string),
+  });
+  let devices = devices?;
+  for device in state.devices.record(&devices) {
+    webhooks::notify(
+      state,
+      Event::new(
+        WebhookEvent::DeviceDiscovered,
+        Some(EventDevice::from(&device)),
+        json!({ "id": device.id, "model_name": device.model_name }),
+      ),
+    );
+  }
+  Ok(devices)
+}
+
+/// Queries every known device concurrently. If no devices are known yet, a discovery scan is run
+/// first.
+pub async fn collect_status(state: &AppState) -> StatusSnapshot {
+  if state.devices.is_empty()
+    && let Err(err) = discover_devices(state).await
+  {
+    tracing::warn!("Device discovery failed while collecting status: {err}");
+  }
+
+  let settings = state.settings();
+  let timeout = Duration::from_secs(settings.status_timeout_seconds);
+  let query = full_status_query(settings.receiver_apps);
+
+  build_snapshot(collect_reports(state.devices.devices(), query, timeout).await)
+}
+
+/// Queries a single device, with the same timeout as when collecting the status of every device.
+pub async fn report_device(state: &AppState, device: DiscoveredDevice) -> DeviceReport {
+  let settings = state.settings();
+  let timeout = Duration::from_secs(settings.status_timeout_seconds);
+  device_report(device, full_status_query(settings.receiver_apps), timeout).await
+}
+
+/// Returns the background status snapshot if one is kept, and collects the status of every device
+/// otherwise.
+pub async fn latest_status(state: &AppState) -> StatusSnapshot {
+  let cached = state
+    .status_snapshot
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone();
+  match cached {
+    Some(snapshot) => snapshot,
+    None => collect_status(state).await,
+  }
+}
+
+/// The device and media status of a device, as returned by [`status::get_full_status`].
+type FullStatus = (DeviceStatus, Option<MediaStatus>);
+
+/// Fetches the status of the device at an address. Called on a blocking thread.
+type StatusQuery = Arc<dyn Fn(&str, u16) -> Result<FullStatus, CastielError> + Send + Sync>;
+
+/// Queries devices over the Cast protocol, recognising `receiver_apps` in their status.
+fn full_status_query(receiver_apps: Vec<ReceiverAppConfig>) -> StatusQuery {
+  Arc::new(move |ip, port| status::get_full_status(ip, port, &receiver_apps))
+}
+
+/// Runs `query` for each of `devices` concurrently, and returns the reports in the same order as
+/// they finish.
+async fn collect_reports(
+  devices: Vec<DiscoveredDevice>,
+  query: StatusQuery,
+  timeout: Duration,
+) -> Vec<DeviceReport> {
+  let mut reports = JoinSet::new();
+  for device in devices {
+    reports.spawn(device_report(device, Arc::clone(&query), timeout));
+  }
+  reports.join_all().await
+}
+
+/// Puts `reports` in a snapshot, sorted by the devices' mDNS names so that the order is stable.
+fn build_snapshot(mut reports: Vec<DeviceReport>) -> StatusSnapshot {
+  reports.sort_by(|a, b| a.device.fullname.cmp(&b.device.fullname));
+  StatusSnapshot {
+    generated_at: unix_now(),
+    devices: reports,
+  }
+}
+
+/// Runs `query` for `device`, giving up on it after `timeout`.
+///
+/// Blocking queries can't be cancelled, so one which times out keeps running on its blocking
+/// thread until the device answers or the connection's own connect or read timeout expires, and
+/// its result is then dropped. The read timeout applies to each message, so a device sending a
+/// steady stream of unrelated messages can keep the query running for longer.
+async fn device_report(
+  device: DiscoveredDevice,
+  query: StatusQuery,
+  timeout: Duration,
+) -> DeviceReport {
+  let (ip, port) = (device.ip_address.clone(), device.port);
+  let running = tokio::task::spawn_blocking({
+    let ip = ip.clone();
+    move || query(&ip, port)
+  });
+
+  let result = match tokio::time::timeout(timeout, running).await {
+    Ok(Ok(result)) => result,
+    Ok(Err(_)) => Err(CastielError::InternalError),
+    Err(_) => Err(CastielError::ConnError(rust_cast::errors::Error::Io(
+      std::io::ErrorKind::TimedOut.into(),
+    ))),
+  };
+
+  report(device, result)
+}
+
+/// Combines `device` with the result of querying its status.
+fn report(device: DiscoveredDevice, result: Result<FullStatus, CastielError>) -> DeviceReport {
+  match result {
+    Ok((device_status, media_status)) => DeviceReport {
+      device,
+      device_status: Some(device_status),
+      media_status,
+      error: None,
+    },
+    Err(err) => {
+      let err = err.at_device(&device.ip_address, device.port);
+      DeviceReport {
+        device,
+        device_status: None,
+        media_status: None,
+        error: Some(err.to_body()),
+      }
+    }
+  }
+}
+
+/// Spawns a background task which rediscovers devices and refreshes the cached status snapshot
+/// every `status_refresh_seconds`. The interval is re-read from the settings after every refresh.
+pub fn spawn_status_refresher(state: AppState) {
+  tokio::spawn(async move {
+    loop {
+      let interval = state.settings().status_refresh_seconds;
+      if interval == 0 {
+        // Drop any snapshot left over from before refreshing was disabled, since it won't update
+        state
+          .status_snapshot
+          .write()
+          .unwrap_or_else(PoisonError::into_inner)
+          .take();
+        tokio::time::sleep(DISABLED_POLL_INTERVAL).await;
+        continue;
+      }
+
+      if let Err(err) = discover_devices(&state).await {
+        tracing::warn!("Background device discovery failed: {err}");
+      }
+      let snapshot = collect_status(&state).await;
+      tracing::debug!(
+        "Refreshed status snapshot of {} devices",
+        snapshot.devices.len()
+      );
+      let previous = state
+        .status_snapshot
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .replace(snapshot.clone());
+      if let Some(previous) = previous {
+        webhooks::notify_status_changes(&state, &previous, &snapshot);
+      }
+      mqtt::publish_status(&state, &snapshot);
+
+      tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+
+  fn device(name: &str, ip: &str) -> DiscoveredDevice {
+    DiscoveredDevice {
+      ip_address: ip.to_string(),
+      port: 8009,
+      fullname: format!("{name}._googlecast._tcp.local."),
+      id: None,
+      model_name: None,
+      friendly_name: Some(name.to_string()),
+      txt_properties: HashMap::new(),
+    }
+  }
+
+  fn idle() -> FullStatus {
+    (
+      DeviceStatus::from_reply(serde_json::json!({ "applications": [] })),
+      None,
+    )
+  }
+
+  /// Answers for devices at `.1`, refuses connections at `.2` and never answers at `.3`.
+  fn query() -> StatusQuery {
+    Arc::new(|ip, _| match ip {
+      "192.168.1.1" => Ok(idle()),
+      "192.168.1.2" => Err(CastielError::ConnError(rust_cast::errors::Error::Io(
+        std::io::ErrorKind::ConnectionRefused.into(),
+      ))),
+      _ => {
+        std::thread::sleep(Duration::from_millis(500));
+        Ok(idle())
+      }
+    })
+  }
+
+  #[tokio::test]
+  async fn every_device_gets_a_report() {
+    let devices = vec![
+      device("Slow", "192.168.1.3"),
+      device("Kitchen", "192.168.1.1"),
+      device("Bedroom", "192.168.1.2"),
+    ];
+    let started = Instant::now();
+    let snapshot =
+      build_snapshot(collect_reports(devices, query(), Duration::from_millis(100)).await);
+    assert!(started.elapsed() < Duration::from_millis(400));
+
+    let names: Vec<_> = snapshot
+      .devices
+      .iter()
+      .map(|report| report.device.friendly_name.as_deref().unwrap())
+      .collect();
+    assert_eq!(names, ["Bedroom", "Kitchen", "Slow"]);
+
+    let [bedroom, kitchen, slow] = &snapshot.devices[..] else {
+      panic!("expected three reports");
+    };
+    assert!(kitchen.device_status.is_some() && kitchen.error.is_none());
+    let error = bedroom.error.as_ref().unwrap();
+    assert!(bedroom.device_status.is_none());
+    assert_eq!(error.code, "device_connection_failed");
+    assert_eq!(error.device.as_deref(), Some("192.168.1.2:8009"));
+    assert_eq!(slow.error.as_ref().unwrap().code, "device_timeout");
+  }
+
+  #[test]
+  fn reports_carry_the_status_or_the_error() {
+    let media = MediaStatus::from_reply(serde_json::json!([{ "playerState": "PLAYING" }]));
+    let (device_status, _) = idle();
+    let found = report(
+      device("Kitchen", "192.168.1.1"),
+      Ok((device_status, Some(media))),
+    );
+    assert!(found.device_status.is_some());
+    assert!(found.media_status.is_some());
+    assert!(found.error.is_none());
+
+    let failed = report(
+      device("Kitchen", "192.168.1.1"),
+      Err(CastielError::InternalError),
+    );
+    assert!(failed.device_status.is_none());
+    assert!(failed.media_status.is_none());
+    assert_eq!(failed.error.unwrap().code, "internal_error");
+  }
+
+  #[test]
+  fn snapshots_are_sorted_by_device() {
+    let snapshot = build_snapshot(vec![
+      report(device("b", "192.168.1.2"), Ok(idle())),
+      report(device("a", "192.168.1.1"), Ok(idle())),
+    ]);
+    assert_eq!(
+      snapshot.devices[0].device.fullname,
+      "a._googlecast._tcp.local."
+    );
+    assert!(snapshot.generated_at > 0);
+  }
+}
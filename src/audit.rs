@@ -0,0 +1,717 @@
+//! Keeps a record of every state-changing API call, so that changes to what devices are doing can
+//! be traced back to whoever made them.
+//!
+//! Each call made with a method other than `GET` is recorded with the caller, the address it came
+//! from, the device acted on, a summary of the request body and the result. Calls refused for
+//! lacking credentials or for an oversized body are recorded too. Entries are appended to
+//! a JSON Lines file, kept in memory for `/api/audit`, and deleted once they are older than
+//! `retention_days` or there are more than `max_entries` of them. Commands taken over MQTT are
+//! recorded alongside API calls.
+
+use std::{
+  collections::VecDeque,
+  fs::{self, OpenOptions},
+  io::Write,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, PoisonError},
+  time::{Duration, Instant},
+};
+
+use axum::{
+  body::Body,
+  extract::{ConnectInfo, MatchedPath, Request, State, connect_info::Connected},
+  http::{Extensions, Method, StatusCode},
+  middleware::Next,
+  response::{IntoResponse, Response},
+  serve::IncomingStream,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::net::TcpListener;
+use url::Url;
+
+use crate::{
+  auth::Caller,
+  clock::unix_now,
+  config::{AuditSettings, REDACTED, SharedSettings},
+  errors::CastielError,
+  request_tracing::RequestId,
+  state::AppState,
+  tls::TlsListener,
+};
+
+/// The largest request body read, matching the limit of axum's body extractors.
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// The largest error body read from a response.
+const MAX_ERROR_BODY_SIZE: usize = 64 * 1024;
+
+/// Strings in request bodies longer than this many characters are cut short.
+const MAX_STRING_LENGTH: usize = 200;
+
+/// Body fields whose names contain any of these are replaced with [`REDACTED`]. The query strings
+/// of URLs, which often carry access tokens for media, are replaced too.
+const SECRET_FIELDS: [&str; 4] = ["password", "secret", "token", "key"];
+
+/// How often old entries are looked for.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The address a connection to the API came from.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+  fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+    Self(*stream.remote_addr())
+  }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+  fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+    Self(*stream.remote_addr())
+  }
+}
+
+/// A recorded API call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+  /// Increases with every entry, so entries can be paged through.
+  pub id: u64,
+  /// When the call was made, as a Unix timestamp.
+  pub timestamp: u64,
+  /// The name of the token or user which made the call, or [`None`] when authentication is
+  /// disabled.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub actor: Option<String>,
+  /// The IP address the call came from.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source_ip: Option<String>,
+  /// The ID the call was logged with.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub request_id: Option<String>,
+  /// The method and route called, e.g. `POST /api/start-media`.
+  pub action: String,
+  /// The device acted on, as `ip:port` or as listed by GET /api/device-pins.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub device: Option<String>,
+  /// The request body, with secrets hidden and long strings shortened.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub payload: Option<Value>,
+  pub result: AuditResult,
+}
+
+/// The outcome of a recorded API call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditResult {
+  /// The HTTP status code of the response.
+  pub status: u16,
+  /// The `code` of the error returned, if the call failed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error_code: Option<String>,
+  /// The `message` of the error returned, if the call failed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+impl AuditResult {
+  fn failed(err: &CastielError) -> Self {
+    Self {
+      status: err.status_code().as_u16(),
+      error_code: Some(err.code().to_string()),
+      error: Some(err.to_string()),
+    }
+  }
+}
+
+/// A page of entries, newest first.
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+  pub entries: Vec<AuditEntry>,
+  /// The `before` value fetching the next page, if there are older matching entries.
+  pub next_before: Option<u64>,
+}
+
+/// Which entries to return from [`AuditLog::page`].
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+  /// Only return entries with an `id` lower than this.
+  pub before: Option<u64>,
+  /// How many entries to return, at most [`AuditQuery::MAX_LIMIT`].
+  pub limit: Option<usize>,
+  /// Only return entries for this device.
+  pub device: Option<String>,
+  /// Only return entries made by this token or user.
+  pub actor: Option<String>,
+}
+
+impl AuditQuery {
+  const DEFAULT_LIMIT: usize = 50;
+  const MAX_LIMIT: usize = 500;
+}
+
+/// The recorded API calls, oldest first.
+pub struct AuditLog {
+  path: PathBuf,
+  settings: SharedSettings,
+  entries: Mutex<Entries>,
+}
+
+struct Entries {
+  entries: VecDeque<AuditEntry>,
+  next_id: u64,
+  last_pruned: Instant,
+}
+
+impl AuditLog {
+  /// Loads the entries saved at `path`, starting with none if the file doesn't exist yet.
+  ///
+  /// Lines which can't be parsed, such as one cut short by a crash, are skipped.
+  pub fn load(path: &Path, settings: SharedSettings) -> Result<Self, CastielError> {
+    let entries: VecDeque<AuditEntry> = match fs::read_to_string(path) {
+      Ok(contents) => {
+        let lines: Vec<&str> = contents.lines().filter(|line| !line.is_empty()).collect();
+        let entries: VecDeque<AuditEntry> = lines
+          .iter()
+          .filter_map(|line| serde_json::from_str(line).ok())
+          .collect();
+        if entries.len() < lines.len() {
+          tracing::warn!(
+            "Skipped {} unreadable entries in audit log {}",
+            lines.len() - entries.len(),
+            path.display()
+          );
+        }
+        // End a line cut short, so that the next entry starts on its own line
+        if !contents.is_empty() && !contents.ends_with('\n') {
+          OpenOptions::new()
+            .append(true)
+            .open(path)?
+            .write_all(b"\n")?;
+        }
+        entries
+      }
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+      Err(err) => return Err(err.into()),
+    };
+    let next_id = entries.back().map_or(1, |entry| entry.id + 1);
+
+    let log = Self {
+      path: path.to_path_buf(),
+      settings,
+      entries: Mutex::new(Entries {
+        entries,
+        next_id,
+        last_pruned: Instant::now(),
+      }),
+    };
+    log.prune(&mut log.lock());
+    Ok(log)
+  }
+
+  /// Records `entry`, giving it the next ID.
+  ///
+  /// Writing to the file, and now and then rewriting it without old entries, blocks, so this is
+  /// done on a blocking thread.
+  pub async fn append(self: &Arc<Self>, entry: AuditEntry) {
+    let log = Arc::clone(self);
+    if let Err(err) = tokio::task::spawn_blocking(move || log.append_blocking(entry)).await {
+      tracing::warn!("Failed to record an audit log entry: {err}");
+    }
+  }
+
+  fn append_blocking(&self, mut entry: AuditEntry) {
+    let mut entries = self.lock();
+    entry.id = entries.next_id;
+    entries.next_id += 1;
+
+    let result = serde_json::to_string(&entry)
+      .map_err(std::io::Error::from)
+      .and_then(|line| {
+        let mut file = OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(&self.path)?;
+        // A single write keeps concurrent processes from interleaving lines
+        file.write_all(format!("{line}\n").as_bytes())
+      });
+    if let Err(err) = result {
+      tracing::warn!(
+        "Failed to write to audit log {}: {err}",
+        self.path.display()
+      );
+    }
+    entries.entries.push_back(entry);
+
+    // Pruning rewrites the file, so a tenth more than `max_entries` is allowed before it's due
+    let max_entries = self.audit_settings().max_entries;
+    let over_limit = max_entries > 0 && entries.entries.len() > max_entries + max_entries / 10;
+    if over_limit || entries.last_pruned.elapsed() >= PRUNE_INTERVAL {
+      self.prune(&mut entries);
+    }
+  }
+
+  /// Records a command Castiel received other than through the API, such as over MQTT.
+  pub async fn record_command(
+    self: &Arc<Self>,
+    actor: &str,
+    action: String,
+    device: String,
+    payload: Option<Value>,
+    result: &Result<(), CastielError>,
+  ) {
+    self
+      .append(AuditEntry {
+        id: 0,
+        timestamp: unix_now(),
+        actor: Some(actor.to_string()),
+        source_ip: None,
+        request_id: None,
+        action,
+        device: Some(device),
+        payload: payload.map(summarize),
+        result: match result {
+          Ok(()) => AuditResult {
+            status: StatusCode::OK.as_u16(),
+            error_code: None,
+            error: None,
+          },
+          Err(err) => AuditResult::failed(err),
+        },
+      })
+      .await;
+  }
+
+  /// Returns the newest entries matching `query`.
+  pub fn page(&self, query: &AuditQuery) -> AuditPage {
+    let limit = query
+      .limit
+      .unwrap_or(AuditQuery::DEFAULT_LIMIT)
+      .clamp(1, AuditQuery::MAX_LIMIT);
+    let entries = self.lock();
+    let mut matching = entries
+      .entries
+      .iter()
+      .rev()
+      .filter(|entry| query.before.is_none_or(|before| entry.id < before))
+      .filter(|entry| {
+        query
+          .device
+          .as_ref()
+          .is_none_or(|device| entry.device.as_ref() == Some(device))
+      })
+      .filter(|entry| {
+        query
+          .actor
+          .as_ref()
+          .is_none_or(|actor| entry.actor.as_ref() == Some(actor))
+      });
+
+    let page: Vec<AuditEntry> = matching.by_ref().take(limit).cloned().collect();
+    let next_before = match matching.next() {
+      Some(_) => page.last().map(|entry| entry.id),
+      None => None,
+    };
+    AuditPage {
+      entries: page,
+      next_before,
+    }
+  }
+
+  fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+    self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  fn audit_settings(&self) -> AuditSettings {
+    self
+      .settings
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .audit
+      .clone()
+  }
+
+  /// Deletes entries beyond the retention settings, rewriting the file if any were deleted.
+  fn prune(&self, entries: &mut Entries) {
+    entries.last_pruned = Instant::now();
+    let audit = self.audit_settings();
+    let before = entries.entries.len();
+
+    if audit.retention_days > 0 {
+      let cutoff = unix_now().saturating_sub(audit.retention_days * 24 * 60 * 60);
+      while entries
+        .entries
+        .front()
+        .is_some_and(|entry| entry.timestamp < cutoff)
+      {
+        entries.entries.pop_front();
+      }
+    }
+    if audit.max_entries > 0 {
+      let excess = entries.entries.len().saturating_sub(audit.max_entries);
+      entries.entries.drain(..excess);
+    }
+
+    let removed = before - entries.entries.len();
+    if removed == 0 {
+      return;
+    }
+    tracing::debug!("Deleted {removed} old audit log entries");
+
+    // Write to a temporary file first so a crash can't lose the entries being kept
+    let temp_path = self.path.with_extension("jsonl.tmp");
+    let result = entries
+      .entries
+      .iter()
+      .map(|entry| serde_json::to_string(entry).map(|line| line + "\n"))
+      .collect::<Result<String, _>>()
+      .map_err(std::io::Error::from)
+      .and_then(|contents| fs::write(&temp_path, contents))
+      .and_then(|()| fs::rename(&temp_path, &self.path));
+    if let Err(err) = result {
+      tracing::warn!(
+        "Failed to delete old entries from audit log {}: {err}",
+        self.path.display()
+      );
+    }
+  }
+}
+
+/// Middleware recording every call to the routes it wraps, other than `GET` requests.
+pub async fn record(State(state): State<AppState>, request: Request, next: Next) -> Response {
+  if matches!(*request.method(), Method::GET | Method::HEAD) {
+    return next.run(request).await;
+  }
+
+  let (parts, body) = request.into_parts();
+  let route = parts
+    .extensions
+    .get::<MatchedPath>()
+    .map_or_else(|| parts.uri.path(), MatchedPath::as_str)
+    .to_string();
+  let body = match axum::body::to_bytes(body, MAX_BODY_SIZE).await {
+    Ok(body) => body,
+    Err(_) => {
+      let err = CastielError::PayloadTooLarge;
+      let mut entry = new_entry(&parts.method, &route, &parts.extensions, None);
+      entry.result = AuditResult::failed(&err);
+      state.audit.append(entry).await;
+      return err.into_response();
+    }
+  };
+  let payload = serde_json::from_slice::<Value>(&body).ok();
+  let mut entry = new_entry(&parts.method, &route, &parts.extensions, payload);
+
+  let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+  entry.result.status = response.status().as_u16();
+  if response.status().is_success() {
+    state.audit.append(entry).await;
+    return response;
+  }
+
+  // Failed calls are recorded with the error returned
+  let (parts, body) = response.into_parts();
+  let body = axum::body::to_bytes(body, MAX_ERROR_BODY_SIZE)
+    .await
+    .unwrap_or_default();
+  if let Ok(Value::Object(error)) = serde_json::from_slice::<Value>(&body) {
+    let field = |name: &str| error.get(name).and_then(Value::as_str).map(str::to_string);
+    entry.result.error_code = field("code");
+    entry.result.error = field("message");
+  }
+  state.audit.append(entry).await;
+  Response::from_parts(parts, Body::from(body))
+}
+
+/// Starts an entry for a state-changing API call refused with `err` before it reached its route,
+/// such as one without valid credentials, or returns [`None`] for calls which aren't recorded.
+/// Its body is left unread, since the caller is unknown.
+pub fn refused_entry(request: &Request, err: &CastielError) -> Option<AuditEntry> {
+  let path = request.uri().path();
+  if matches!(*request.method(), Method::GET | Method::HEAD) || !path.starts_with("/api/") {
+    return None;
+  }
+  let mut entry = new_entry(request.method(), path, request.extensions(), None);
+  entry.result = AuditResult::failed(err);
+  Some(entry)
+}
+
+/// Starts an entry for a call to `route`, taking the caller and request ID from the request's
+/// `extensions`. The result is filled in once the call is answered.
+fn new_entry(
+  method: &Method,
+  route: &str,
+  extensions: &Extensions,
+  payload: Option<Value>,
+) -> AuditEntry {
+  AuditEntry {
+    id: 0,
+    timestamp: unix_now(),
+    actor: extensions
+      .get::<Caller>()
+      .and_then(|caller| caller.name.clone()),
+    source_ip: extensions
+      .get::<ConnectInfo<ClientAddr>>()
+      .map(|ConnectInfo(addr)| addr.0.ip().to_string()),
+    request_id: extensions
+      .get::<RequestId>()
+      .map(|request_id| request_id.0.clone()),
+    action: format!("{method} {route}"),
+    device: payload.as_ref().and_then(device_of),
+    payload: payload.map(summarize),
+    result: AuditResult {
+      status: 0,
+      error_code: None,
+      error: None,
+    },
+  }
+}
+
+/// Finds the device a request body refers to, from its `ip` (or `ip_address`) and `port`, or its
+/// `device`.
+fn device_of(payload: &Value) -> Option<String> {
+  let ip = payload
+    .get("ip")
+    .or_else(|| payload.get("ip_address"))
+    .and_then(Value::as_str);
+  let port = payload.get("port").and_then(Value::as_u64);
+  match (ip, port) {
+    (Some(ip), Some(port)) => Some(format!("{ip}:{port}")),
+    _ => payload
+      .get("device")
+      .and_then(Value::as_str)
+      .map(str::to_string),
+  }
+}
+
+/// Hides secrets in `value` and shortens its long strings.
+fn summarize(value: Value) -> Value {
+  match value {
+    Value::String(text) => {
+      let text = redact_query(text);
+      if text.chars().count() > MAX_STRING_LENGTH {
+        Value::String(text.chars().take(MAX_STRING_LENGTH).chain(['…']).collect())
+      } else {
+        Value::String(text)
+      }
+    }
+    Value::Array(items) => Value::Array(items.into_iter().map(summarize).collect()),
+    Value::Object(fields) => Value::Object(
+      fields
+        .into_iter()
+        .map(|(name, value)| {
+          let lowercase = name.to_lowercase();
+          let value = if SECRET_FIELDS
+            .iter()
+            .any(|secret| lowercase.contains(secret))
+          {
+            Value::String(REDACTED.to_string())
+          } else {
+            summarize(value)
+          };
+          (name, value)
+        })
+        .collect::<Map<String, Value>>(),
+    ),
+    value => value,
+  }
+}
+
+/// Replaces the query string of `text` with [`REDACTED`] if it is a URL with one, dropping any
+/// fragment along with it.
+fn redact_query(text: String) -> String {
+  match Url::parse(&text) {
+    Ok(mut url) if url.has_host() && url.query().is_some() => {
+      url.set_query(None);
+      url.set_fragment(None);
+      format!("{url}?{REDACTED}")
+    }
+    _ => text,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::RwLock;
+
+  use super::*;
+  use crate::config::CastielSettings;
+
+  fn settings(retention_days: u64, max_entries: usize) -> SharedSettings {
+    let mut settings = CastielSettings::default();
+    settings.audit.retention_days = retention_days;
+    settings.audit.max_entries = max_entries;
+    Arc::new(RwLock::new(settings))
+  }
+
+  fn entry(timestamp: u64, device: &str, actor: &str) -> AuditEntry {
+    AuditEntry {
+      id: 0,
+      timestamp,
+      actor: Some(actor.to_string()),
+      source_ip: None,
+      request_id: None,
+      action: "POST /api/stop-media".to_string(),
+      device: Some(device.to_string()),
+      payload: None,
+      result: AuditResult {
+        status: 200,
+        error_code: None,
+        error: None,
+      },
+    }
+  }
+
+  fn ids(page: &AuditPage) -> Vec<u64> {
+    page.entries.iter().map(|entry| entry.id).collect()
+  }
+
+  #[test]
+  fn pages_run_newest_first_and_can_be_filtered() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = AuditLog::load(&dir.path().join("audit.jsonl"), settings(0, 0)).unwrap();
+    for (device, actor) in [("a", "alice"), ("b", "bob"), ("a", "bob"), ("b", "alice")] {
+      log.append_blocking(entry(unix_now(), device, actor));
+    }
+
+    let query = AuditQuery {
+      limit: Some(3),
+      ..AuditQuery::default()
+    };
+    let page = log.page(&query);
+    assert_eq!(ids(&page), [4, 3, 2]);
+    assert_eq!(page.next_before, Some(2));
+    let page = log.page(&AuditQuery {
+      before: page.next_before,
+      ..query
+    });
+    assert_eq!(ids(&page), [1]);
+    assert_eq!(page.next_before, None);
+
+    let page = log.page(&AuditQuery {
+      device: Some("a".to_string()),
+      ..AuditQuery::default()
+    });
+    assert_eq!(ids(&page), [3, 1]);
+    let page = log.page(&AuditQuery {
+      device: Some("b".to_string()),
+      actor: Some("alice".to_string()),
+      ..AuditQuery::default()
+    });
+    assert_eq!(ids(&page), [4]);
+  }
+
+  #[test]
+  fn entries_are_kept_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let log = AuditLog::load(&path, settings(0, 0)).unwrap();
+    log.append_blocking(entry(unix_now(), "a", "alice"));
+    log.append_blocking(entry(unix_now(), "b", "bob"));
+    // As if a crash cut the last line short
+    OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .write_all(b"{\"id\":")
+      .unwrap();
+
+    let log = AuditLog::load(&path, settings(0, 0)).unwrap();
+    log.append_blocking(entry(unix_now(), "c", "carol"));
+    assert_eq!(ids(&log.page(&AuditQuery::default())), [3, 2, 1]);
+    let log = AuditLog::load(&path, settings(0, 0)).unwrap();
+    assert_eq!(ids(&log.page(&AuditQuery::default())), [3, 2, 1]);
+  }
+
+  #[test]
+  fn old_entries_are_deleted_on_load() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let log = AuditLog::load(&path, settings(0, 0)).unwrap();
+    let day = 24 * 60 * 60;
+    for age in [40, 20, 5, 1] {
+      log.append_blocking(entry(unix_now() - age * day, "a", "alice"));
+    }
+
+    let log = AuditLog::load(&path, settings(30, 2)).unwrap();
+    // Entry 1 is too old, and entry 2 is beyond the newest two
+    assert_eq!(ids(&log.page(&AuditQuery::default())), [4, 3]);
+    let contents = fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.lines().count(), 2);
+  }
+
+  #[test]
+  fn entries_beyond_the_limit_are_deleted_once_a_tenth_over() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let log = AuditLog::load(&path, settings(0, 10)).unwrap();
+    for _ in 0..11 {
+      log.append_blocking(entry(unix_now(), "a", "alice"));
+    }
+    assert_eq!(log.lock().entries.len(), 11);
+
+    log.append_blocking(entry(unix_now(), "a", "alice"));
+    assert_eq!(log.lock().entries.len(), 10);
+    assert_eq!(log.lock().entries.front().unwrap().id, 3);
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 10);
+  }
+
+  #[test]
+  fn secrets_are_hidden_and_long_strings_shortened() {
+    let payload = serde_json::json!({
+      "ip": "192.168.1.20",
+      "port": 8009,
+      "auth": { "api_token": "hunter2", "Password": "hunter2" },
+      "content_id": "x".repeat(MAX_STRING_LENGTH + 1),
+    });
+    assert_eq!(device_of(&payload).as_deref(), Some("192.168.1.20:8009"));
+
+    let summary = summarize(payload);
+    assert_eq!(summary["auth"]["api_token"], REDACTED);
+    assert_eq!(summary["auth"]["Password"], REDACTED);
+    let content_id = summary["content_id"].as_str().unwrap();
+    assert_eq!(content_id.chars().count(), MAX_STRING_LENGTH + 1);
+    assert!(content_id.ends_with('…'));
+  }
+
+  #[test]
+  fn media_url_queries_are_hidden() {
+    let summary = summarize(serde_json::json!({
+      "content_id": "https://cdn.example.com/a.m3u8?sig=abc&expires=1#t=10",
+      "image": "https://cdn.example.com/poster.jpg",
+      "namespace": "urn:x-cast:com.example.app?x",
+    }));
+    assert_eq!(
+      summary["content_id"],
+      format!("https://cdn.example.com/a.m3u8?{REDACTED}")
+    );
+    assert_eq!(summary["image"], "https://cdn.example.com/poster.jpg");
+    assert_eq!(summary["namespace"], "urn:x-cast:com.example.app?x");
+  }
+
+  #[test]
+  fn calls_refused_before_their_route_are_recorded() {
+    let request = |method: Method, path: &str| {
+      Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    let entry = refused_entry(
+      &request(Method::POST, "/api/start-media?x=1"),
+      &CastielError::Unauthorized,
+    )
+    .unwrap();
+    assert_eq!(entry.action, "POST /api/start-media");
+    assert!(entry.actor.is_none());
+    assert!(entry.payload.is_none());
+    assert_eq!(entry.result.status, 401);
+    assert_eq!(entry.result.error_code.as_deref(), Some("unauthorized"));
+
+    for (method, path) in [(Method::GET, "/api/status"), (Method::POST, "/index.html")] {
+      assert!(refused_entry(&request(method, path), &CastielError::Unauthorized).is_none());
+    }
+  }
+}
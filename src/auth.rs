@@ -0,0 +1,311 @@
+//! Authenticates API requests and checks what the caller is allowed to do.
+//!
+//! Callers identify themselves with an API token (`Authorization: Bearer <token>`) or as a user
+//! with HTTP basic auth. Every request is authenticated by [`authenticate`], and routes require a
+//! minimum [`Role`] with [`require_role`]. Handlers acting on a device check it against the
+//! caller's device allowlist through the [`Caller`] extractor.
+
+use axum::{
+  extract::{FromRequestParts, Request, State},
+  http::{HeaderValue, header, request::Parts},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+use crate::{
+  audit,
+  config::{AuthSettings, Role},
+  errors::CastielError,
+  request_tracing,
+  state::AppState,
+};
+
+/// Who made a request and what they may access.
+#[derive(Debug, Clone)]
+pub struct Caller {
+  /// The name of the token or user, or [`None`] when authentication is disabled.
+  pub name: Option<String>,
+  pub role: Role,
+  /// The devices the caller may access, as `ip` or `ip:port`. Every device when empty.
+  devices: Vec<String>,
+}
+
+impl Caller {
+  /// The caller of every request while authentication is disabled.
+  pub fn unrestricted() -> Self {
+    Self {
+      name: None,
+      role: Role::Admin,
+      devices: Vec::new(),
+    }
+  }
+
+  /// Whether the caller may access the device at `ip`:`port`.
+  pub fn can_access(&self, ip: &str, port: u16) -> bool {
+    self.devices.is_empty()
+      || self
+        .devices
+        .iter()
+        .any(|device| device == ip || *device == format!("{ip}:{port}"))
+  }
+
+  /// Fails with [`CastielError::Forbidden`] unless the caller may access the device at
+  /// `ip`:`port`.
+  ///
+  /// Every handler acting on a device checks it here, so the device is also recorded in the
+  /// request's span.
+  pub fn check_device(&self, ip: &str, port: u16) -> Result<(), CastielError> {
+    request_tracing::record_device(ip, port);
+    if self.can_access(ip, port) {
+      Ok(())
+    } else {
+      Err(
+        CastielError::Forbidden("access to this device is not allowed".to_string())
+          .at_device(ip, port),
+      )
+    }
+  }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+  type Rejection = CastielError;
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    parts
+      .extensions
+      .get::<Self>()
+      .cloned()
+      .ok_or(CastielError::Unauthorized)
+  }
+}
+
+/// Middleware identifying the caller of every request.
+///
+/// API requests without valid credentials are rejected. The UI's static files hold no device
+/// data, so they are only protected when users are configured to sign in to it with.
+pub async fn authenticate(
+  State(state): State<AppState>,
+  mut request: Request,
+  next: Next,
+) -> Response {
+  let auth = state
+    .settings
+    .read()
+    .unwrap_or_else(std::sync::PoisonError::into_inner)
+    .auth
+    .clone();
+  let Some(auth) = auth else {
+    request.extensions_mut().insert(Caller::unrestricted());
+    return next.run(request).await;
+  };
+
+  let caller = request
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| identify(&auth, value));
+  match caller {
+    Some(caller) => {
+      tracing::debug!(
+        "Authenticated {} as {:?}",
+        caller.name.as_deref().unwrap_or_default(),
+        caller.role
+      );
+      request.extensions_mut().insert(caller);
+      next.run(request).await
+    }
+    None if is_public(&auth, request.uri().path()) => next.run(request).await,
+    None => {
+      if let Some(entry) = audit::refused_entry(&request, &CastielError::Unauthorized) {
+        state.audit.append(entry).await;
+      }
+      let mut response = CastielError::Unauthorized.into_response();
+      // Prompts browsers to ask for a username and password
+      if !auth.users.is_empty() {
+        response.headers_mut().insert(
+          header::WWW_AUTHENTICATE,
+          HeaderValue::from_static(r#"Basic realm="Castiel", charset="UTF-8""#),
+        );
+      }
+      response
+    }
+  }
+}
+
+/// Middleware rejecting callers without at least the `required` role.
+pub async fn require_role(
+  State(required): State<Role>,
+  caller: Caller,
+  request: Request,
+  next: Next,
+) -> Response {
+  if caller.role < required {
+    return CastielError::Forbidden(format!("the {required:?} role is required")).into_response();
+  }
+  next.run(request).await
+}
+
+/// Whether `path` may be requested without credentials: the UI's static files, unless users are
+/// configured to sign in to it with.
+fn is_public(auth: &AuthSettings, path: &str) -> bool {
+  !path.starts_with("/api/") && auth.users.is_empty()
+}
+
+/// Finds the token or user matching the `authorization` header value.
+fn identify(auth: &AuthSettings, authorization: &str) -> Option<Caller> {
+  let (scheme, credentials) = authorization.split_once(' ')?;
+  let credentials = credentials.trim();
+
+  if scheme.eq_ignore_ascii_case("Bearer") {
+    return auth
+      .tokens
+      .iter()
+      .find(|token| constant_time_eq(token.token.as_bytes(), credentials.as_bytes()))
+      .map(|token| Caller {
+        name: Some(token.name.clone()),
+        role: token.role,
+        devices: token.devices.clone(),
+      });
+  }
+
+  if scheme.eq_ignore_ascii_case("Basic") {
+    let decoded = STANDARD.decode(credentials).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    return auth
+      .users
+      .iter()
+      .find(|user| {
+        user.username == username && constant_time_eq(user.password.as_bytes(), password.as_bytes())
+      })
+      .map(|user| Caller {
+        name: Some(user.username.clone()),
+        role: user.role,
+        devices: user.devices.clone(),
+      });
+  }
+
+  None
+}
+
+/// Compares secrets without returning early, so response times don't reveal how much matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::{ApiTokenConfig, UserConfig};
+
+  fn auth() -> AuthSettings {
+    AuthSettings {
+      tokens: vec![ApiTokenConfig {
+        name: "automation".to_string(),
+        token: "s3cret-token".to_string(),
+        role: Role::Operator,
+        devices: vec!["192.168.1.20".to_string()],
+      }],
+      users: vec![UserConfig {
+        username: "admin".to_string(),
+        password: "hunter2".to_string(),
+        role: Role::Admin,
+        devices: Vec::new(),
+      }],
+    }
+  }
+
+  fn basic(credentials: &str) -> String {
+    format!("Basic {}", STANDARD.encode(credentials))
+  }
+
+  #[test]
+  fn tokens_identify_their_caller() {
+    let caller = identify(&auth(), "Bearer s3cret-token").unwrap();
+    assert_eq!(caller.name.as_deref(), Some("automation"));
+    assert_eq!(caller.role, Role::Operator);
+    assert!(identify(&auth(), "bearer  s3cret-token ").is_some());
+
+    for authorization in [
+      "",
+      "Bearer",
+      "Bearer ",
+      "Bearer s3cret-toke",
+      "Bearer s3cret-token2",
+      "Token s3cret-token",
+      "s3cret-token",
+    ] {
+      assert!(
+        identify(&auth(), authorization).is_none(),
+        "{authorization}"
+      );
+    }
+  }
+
+  #[test]
+  fn users_sign_in_with_basic_auth() {
+    let caller = identify(&auth(), &basic("admin:hunter2")).unwrap();
+    assert_eq!(caller.name.as_deref(), Some("admin"));
+    assert_eq!(caller.role, Role::Admin);
+
+    for credentials in ["admin:hunter3", "admin:", "root:hunter2", "admin"] {
+      assert!(
+        identify(&auth(), &basic(credentials)).is_none(),
+        "{credentials}"
+      );
+    }
+    assert!(identify(&auth(), "Basic not-base64!").is_none());
+  }
+
+  #[test]
+  fn secrets_of_different_lengths_never_match() {
+    assert!(constant_time_eq(b"secret", b"secret"));
+    assert!(!constant_time_eq(b"secret", b"secreT"));
+    assert!(!constant_time_eq(b"secret", b"secret "));
+    assert!(!constant_time_eq(b"secret", b"secre"));
+    assert!(!constant_time_eq(b"", b"secret"));
+  }
+
+  #[test]
+  fn roles_are_ordered_by_what_they_allow() {
+    assert!(Role::Viewer < Role::Operator);
+    assert!(Role::Operator < Role::Admin);
+  }
+
+  #[test]
+  fn device_allowlists_match_addresses_with_or_without_ports() {
+    let caller = Caller {
+      name: None,
+      role: Role::Operator,
+      devices: vec!["192.168.1.20".to_string(), "192.168.1.21:8009".to_string()],
+    };
+    assert!(caller.can_access("192.168.1.20", 8009));
+    assert!(caller.can_access("192.168.1.20", 8010));
+    assert!(caller.can_access("192.168.1.21", 8009));
+    assert!(!caller.can_access("192.168.1.21", 8010));
+    assert!(!caller.can_access("192.168.1.2", 8009));
+    assert!(caller.check_device("192.168.1.21", 8009).is_ok());
+
+    let err = caller.check_device("192.168.1.22", 8009).unwrap_err();
+    assert_eq!(err.code(), "forbidden");
+    assert_eq!(err.to_body().device.as_deref(), Some("192.168.1.22:8009"));
+
+    assert!(Caller::unrestricted().can_access("192.168.1.22", 8009));
+  }
+
+  #[test]
+  fn static_files_are_public_unless_users_sign_in() {
+    let tokens_only = AuthSettings {
+      users: Vec::new(),
+      ..auth()
+    };
+    assert!(is_public(&tokens_only, "/"));
+    assert!(is_public(&tokens_only, "/assets/index.js"));
+    assert!(!is_public(&tokens_only, "/api/status"));
+    assert!(!is_public(&tokens_only, "/api/"));
+
+    assert!(!is_public(&auth(), "/"));
+    assert!(!is_public(&auth(), "/assets/index.js"));
+  }
+}
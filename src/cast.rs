@@ -0,0 +1,51 @@
+//! Starts and stops media on devices for every way Castiel is asked to: the API, presets and MQTT
+//! commands.
+//!
+//! Besides talking to the device, this keeps the media relays and the watches restarting live
+//! streams in step with what each device is playing.
+
+use crate::{
+  devices::{
+    self, AppSelector, DeviceAddress, blocking,
+    media::{self, StartMediaData},
+  },
+  errors::CastielError,
+  live, relay,
+  state::AppState,
+};
+
+/// Starts media on a device, relaying it and keeping it playing if asked to.
+pub async fn start_media(state: &AppState, media_data: StartMediaData) -> Result<(), CastielError> {
+  let (ip, port) = (media_data.ip_address.clone(), media_data.port);
+  let (media_data, relay_id) = relay::prepare(state, media_data).await?;
+  let media_data = devices::sniffing::infer_media_details(media_data).await;
+
+  let media_data = match media::start_from_data(media_data).await {
+    Ok(media_data) => media_data,
+    Err(err) => {
+      if let Some(relay_id) = &relay_id {
+        relay::remove(state, relay_id);
+      }
+      return Err(err);
+    }
+  };
+
+  // The new media replaces whatever an existing watch or relay was keeping alive
+  relay::release(state, &ip, port, relay_id.as_deref());
+  live::unwatch(state, &ip, port);
+  if media_data.auto_restart {
+    live::watch(state, media_data);
+  }
+  Ok(())
+}
+
+/// Stops the app targeted by `app` on a device, along with anything keeping its media alive.
+pub async fn stop_media(
+  state: &AppState,
+  device_addr: DeviceAddress,
+  app: AppSelector,
+) -> Result<(), CastielError> {
+  live::unwatch(state, &device_addr.ip, device_addr.port);
+  relay::release(state, &device_addr.ip, device_addr.port, None);
+  blocking(move || media::stop_media_at_device(&device_addr, &app)).await
+}
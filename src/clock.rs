@@ -0,0 +1,11 @@
+//! Timestamps for what Castiel records and reports.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time in whole seconds since the Unix epoch, or `0` if the clock is set before it.
+pub fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_secs())
+    .unwrap_or_default()
+}
@@ -1,23 +1,552 @@
 //! Configuration settings for the application.
 
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{
+  collections::BTreeMap,
+  fmt,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Settings shared between the API handlers and the config file watcher.
+pub type SharedSettings = Arc<RwLock<CastielSettings>>;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct CastielSettings {
+  /// The address the API and UI listen on.
+  #[serde(default = "default_host")]
+  pub host: String,
   pub port: u16,
+  /// The log filter: a level, optionally followed by per-module levels, e.g.
+  /// `info,castiel::devices=trace,mdns_sd=warn`.
   pub log_level: String,
+  /// How log lines are written.
+  #[serde(default)]
+  pub log_format: LogFormat,
+  /// Also writes logs to a file, rotating it as it grows or ages. Logs only go to stdout unless
+  /// this section is present.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub log_file: Option<LogFileSettings>,
+  /// Custom receiver apps which can be launched and are recognised in device status.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub receiver_apps: Vec<ReceiverAppConfig>,
+  /// How long to wait for each device when collecting the status of all devices, in seconds.
+  #[serde(default = "default_status_timeout_seconds")]
+  pub status_timeout_seconds: u64,
+  /// How often the cached status of all devices is refreshed in the background, in seconds.
+  /// `0` disables background refreshes.
+  #[serde(default)]
+  pub status_refresh_seconds: u64,
+  /// Serves media to devices through Castiel. Relaying is disabled unless this section is present.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub relay: Option<RelaySettings>,
+  /// Who may use the API. Anyone who can reach Castiel has full access unless this section is
+  /// present.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub auth: Option<AuthSettings>,
+  /// Serves the API and UI over HTTPS. Plain HTTP is used unless this section is present.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tls: Option<TlsSettings>,
+  /// How the certificates devices present are checked against the ones seen before.
+  #[serde(default)]
+  pub device_pinning: DevicePinningSettings,
+  /// What `/readyz` checks besides Castiel itself.
+  #[serde(default)]
+  pub health: HealthSettings,
+  /// The directory Castiel's state, such as the devices it knows about, is kept in.
+  #[serde(default = "default_state_dir")]
+  pub state_dir: PathBuf,
+  /// URLs notified when devices or playback change.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub webhooks: Vec<WebhookConfig>,
+  /// Where the record of state-changing API calls is kept, and for how long.
+  #[serde(default)]
+  pub audit: AuditSettings,
+  /// Publishes device status to an MQTT broker, and takes commands from it if enabled. Castiel
+  /// doesn't connect to a broker unless this section is present.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub mqtt: Option<MqttSettings>,
+}
+
+fn default_host() -> String {
+  "127.0.0.1".to_string()
+}
+
+fn default_status_timeout_seconds() -> u64 {
+  5
+}
+
+fn default_state_dir() -> PathBuf {
+  PathBuf::from("state")
+}
+
+fn default_ffmpeg_path() -> String {
+  "ffmpeg".to_string()
+}
+
+fn default_relay_idle_timeout_seconds() -> u64 {
+  300
 }
 
 impl Default for CastielSettings {
   fn default() -> Self {
     Self {
+      host: default_host(),
       port: 3000,
       log_level: "INFO".to_string(),
+      log_format: LogFormat::default(),
+      log_file: None,
+      receiver_apps: Vec::new(),
+      status_timeout_seconds: default_status_timeout_seconds(),
+      status_refresh_seconds: 0,
+      relay: None,
+      auth: None,
+      tls: None,
+      device_pinning: DevicePinningSettings::default(),
+      health: HealthSettings::default(),
+      state_dir: default_state_dir(),
+      webhooks: Vec::new(),
+      audit: AuditSettings::default(),
+      mqtt: None,
+    }
+  }
+}
+
+/// The format log lines are written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LogFormat {
+  /// Human-readable lines.
+  #[default]
+  Text,
+  /// One JSON object per line, for log aggregators.
+  Json,
+}
+
+/// Settings for writing logs to a file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LogFileSettings {
+  /// The file logs are written to. Rotated files are kept next to it as `<path>.1`, `<path>.2` and
+  /// so on, newest first.
+  pub path: PathBuf,
+  /// Rotate the file once it would grow past this many megabytes.
+  pub max_size_mb: Option<u64>,
+  /// Rotate the file at the start of every hour or day, in UTC.
+  pub rotate_every: Option<RotationPeriod>,
+  /// How many rotated files to keep. Older ones are deleted.
+  #[serde(default = "default_max_files")]
+  pub max_files: usize,
+}
+
+fn default_max_files() -> usize {
+  5
+}
+
+/// How often log files are rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RotationPeriod {
+  Hourly,
+  Daily,
+}
+
+/// A custom Cast receiver app registered in the settings file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ReceiverAppConfig {
+  /// The app ID assigned by the Google Cast SDK Developer Console.
+  pub id: String,
+  /// A human-readable name for the app, which can also be used to refer to it in API requests.
+  pub name: String,
+  /// The custom message namespace the app listens on, e.g. `urn:x-cast:com.example.app`.
+  pub namespace: Option<String>,
+  /// If set, messages sent to the app have their `type` field set to this value.
+  pub message_type: Option<String>,
+  /// Top-level fields every message sent to the app must contain.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub required_fields: Vec<String>,
+}
+
+/// Settings for relaying media which devices can't fetch or play by themselves.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RelaySettings {
+  /// The port relayed media is served on. Unlike the API it listens on every interface, so that
+  /// devices can reach it.
+  pub port: u16,
+  /// The host name or address devices reach Castiel at. Defaults to the local address Castiel
+  /// uses to reach each device.
+  pub advertised_host: Option<String>,
+  /// The ffmpeg executable used to remux media.
+  #[serde(default = "default_ffmpeg_path")]
+  pub ffmpeg_path: String,
+  /// How long a relay may go without the device fetching from it before it is stopped, along
+  /// with its ffmpeg process.
+  #[serde(default = "default_relay_idle_timeout_seconds")]
+  pub idle_timeout_seconds: u64,
+  /// Headers and credentials to fetch sources with.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub sources: Vec<RelaySourceConfig>,
+}
+
+/// Headers and credentials used when relaying media from URLs under `url_prefix`: those with the
+/// same scheme, host and port, whose path starts with the prefix's path at a `/`.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct RelaySourceConfig {
+  pub url_prefix: String,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub headers: BTreeMap<String, String>,
+  /// Sent with basic auth for HTTP sources. ffmpeg only takes credentials for other protocols,
+  /// such as RTSP, in the source URL, where other users of the host can see them.
+  pub username: Option<String>,
+  pub password: Option<String>,
+}
+
+impl RelaySourceConfig {
+  /// A copy with header values and the password hidden, for showing to API clients.
+  pub fn redacted(&self) -> Self {
+    Self {
+      url_prefix: self.url_prefix.clone(),
+      headers: self
+        .headers
+        .keys()
+        .map(|name| (name.clone(), REDACTED.to_string()))
+        .collect(),
+      username: self.username.clone(),
+      password: self.password.as_ref().map(|_| REDACTED.to_string()),
+    }
+  }
+}
+
+/// Header values and passwords can hold secrets, so they are left out of logged settings.
+impl fmt::Debug for RelaySourceConfig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let redacted = self.redacted();
+    f.debug_struct("RelaySourceConfig")
+      .field("url_prefix", &redacted.url_prefix)
+      .field("headers", &redacted.headers)
+      .field("username", &redacted.username)
+      .field("password", &redacted.password)
+      .finish()
+  }
+}
+
+/// The certificate and key the API and UI are served over HTTPS with.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsSettings {
+  /// A PEM file holding the certificate chain, starting with Castiel's own certificate.
+  pub cert_path: PathBuf,
+  /// A PEM file holding the certificate's private key.
+  pub key_path: PathBuf,
+  /// Generate a self-signed certificate and key at the paths above if neither exists yet.
+  #[serde(default)]
+  pub self_signed: bool,
+  /// Extra host names and addresses to include in a generated certificate.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub hostnames: Vec<String>,
+  /// A port to listen for plain HTTP on, redirecting every request to HTTPS.
+  pub redirect_port: Option<u16>,
+}
+
+/// Settings for trust-on-first-use pinning of device certificates. Pins are kept in `state_dir`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct DevicePinningSettings {
+  #[serde(default)]
+  pub mode: PinningMode,
+}
+
+/// What happens when a device presents a different certificate than the one pinned for it.
+///
+/// Cast devices regenerate their certificates from time to time, so a change is expected every so
+/// often. See [`crate::devices::pinning`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PinningMode {
+  /// Certificates are neither pinned nor checked.
+  Off,
+  /// The change is logged and recorded with the pin, and the connection goes ahead. Each new
+  /// certificate is logged once.
+  #[default]
+  Warn,
+  /// The change is recorded with the pin and the connection is refused until the pin is reset.
+  /// Devices are locked out whenever they regenerate their certificate.
+  Enforce,
+}
+
+/// Settings for the readiness check.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct HealthSettings {
+  /// How many known devices must have answered in the background status snapshot for Castiel to
+  /// be ready. This needs `status_refresh_seconds` to be set, since readiness probes never connect
+  /// to devices themselves. `0` leaves devices out of the check.
+  #[serde(default)]
+  pub min_reachable_devices: usize,
+}
+
+/// Settings for the audit log of state-changing API calls.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuditSettings {
+  /// The file audit entries are appended to, one JSON object per line. Defaults to
+  /// `audit-log.jsonl` in `state_dir`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub file: Option<PathBuf>,
+  /// Entries older than this many days are deleted. `0` keeps entries forever.
+  #[serde(default = "default_audit_retention_days")]
+  pub retention_days: u64,
+  /// Only the newest entries are kept once there are more than this many. Entries are also kept
+  /// in memory, so this bounds how much they take up. `0` keeps any number of entries.
+  #[serde(default = "default_audit_max_entries")]
+  pub max_entries: usize,
+}
+
+impl Default for AuditSettings {
+  fn default() -> Self {
+    Self {
+      file: None,
+      retention_days: default_audit_retention_days(),
+      max_entries: default_audit_max_entries(),
+    }
+  }
+}
+
+fn default_audit_retention_days() -> u64 {
+  90
+}
+
+fn default_audit_max_entries() -> usize {
+  10_000
+}
+
+/// A URL events are posted to.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct WebhookConfig {
+  /// A name for the webhook, used in logs and delivery statuses.
+  pub name: String,
+  pub url: String,
+  /// Signs every delivery with HMAC-SHA256 in the `X-Castiel-Signature` header.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub secret: Option<String>,
+  /// The events to send. Every event is sent when empty.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub events: Vec<WebhookEvent>,
+  /// How many times to try delivering each event before giving up.
+  #[serde(default = "default_webhook_max_attempts")]
+  pub max_attempts: u32,
+}
+
+fn default_webhook_max_attempts() -> u32 {
+  5
+}
+
+/// Something which happened to a device, which webhooks can be notified of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+  /// Discovery found a device for the first time.
+  DeviceDiscovered,
+  /// A device which answered the previous background status check didn't answer this one.
+  DeviceLost,
+  /// The foreground app of a device changed between background status checks.
+  AppChanged,
+  /// A device's player went idle because playback failed.
+  MediaError,
+  /// Media started with `auto_restart` couldn't be restarted, and is no longer watched.
+  WatchdogRecoveryFailed,
+}
+
+/// Settings for connecting to an MQTT broker.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct MqttSettings {
+  /// The broker's host name or address. Only plain TCP connections are supported.
+  pub host: String,
+  #[serde(default = "default_mqtt_port")]
+  pub port: u16,
+  /// The client ID Castiel connects with, which must be unique on the broker.
+  #[serde(default = "default_mqtt_client_id")]
+  pub client_id: String,
+  pub username: Option<String>,
+  /// Only sent along with `username`, so setting it alone is refused.
+  pub password: Option<String>,
+  /// The topic status is published under and commands are read from, e.g.
+  /// `castiel/<device>/state` and `castiel/<device>/command/play`.
+  #[serde(default = "default_mqtt_topic_prefix")]
+  pub topic_prefix: String,
+  /// Takes commands from `<prefix>/<device>/command/<command>`. Off by default, since anyone
+  /// who can publish to the broker can then control devices.
+  #[serde(default)]
+  pub commands: bool,
+  /// The devices commands may act on, as `ip`, `ip:port` or the name used in topics. Every
+  /// device when empty.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub command_devices: Vec<String>,
+  /// Publishes Home Assistant discovery payloads, so that devices show up in Home Assistant.
+  #[serde(default = "default_mqtt_discovery")]
+  pub discovery: bool,
+  /// The topic prefix Home Assistant reads discovery payloads from.
+  #[serde(default = "default_mqtt_discovery_prefix")]
+  pub discovery_prefix: String,
+  /// How often to ping the broker, in seconds. The broker drops the connection if it hears
+  /// nothing for one and a half times as long.
+  #[serde(default = "default_mqtt_keep_alive_seconds")]
+  pub keep_alive_seconds: u16,
+}
+
+impl RelaySettings {
+  /// Fails with a description of the first source whose `url_prefix` isn't a URL.
+  fn check(&self) -> Result<(), String> {
+    for source in &self.sources {
+      if let Err(err) = url::Url::parse(&source.url_prefix) {
+        return Err(format!(
+          "relay.sources url_prefix {} isn't a valid URL: {err}",
+          source.url_prefix
+        ));
+      }
     }
+    Ok(())
   }
 }
 
+impl MqttSettings {
+  /// Fails with a description of the first setting the broker couldn't be sent.
+  fn check(&self) -> Result<(), String> {
+    if self.password.is_some() && self.username.is_none() {
+      return Err("mqtt.password is set without mqtt.username".to_string());
+    }
+    Ok(())
+  }
+}
+
+fn default_mqtt_port() -> u16 {
+  1883
+}
+
+fn default_mqtt_client_id() -> String {
+  "castiel".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+  "castiel".to_string()
+}
+
+fn default_mqtt_discovery() -> bool {
+  true
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+  "homeassistant".to_string()
+}
+
+fn default_mqtt_keep_alive_seconds() -> u16 {
+  30
+}
+
+/// API tokens and users allowed to access Castiel.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuthSettings {
+  /// Tokens for API clients, sent as `Authorization: Bearer <token>`.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub tokens: Vec<ApiTokenConfig>,
+  /// Users who sign in with HTTP basic auth, which is how browsers reach the UI.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub users: Vec<UserConfig>,
+}
+
+/// What a token or user is allowed to do. Each role can do everything the roles before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Role {
+  /// Can discover devices and read their status.
+  Viewer,
+  /// Can also cast, control and stop media.
+  Operator,
+  /// Can also read Castiel's settings and the audit log.
+  Admin,
+}
+
+/// An API token.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct ApiTokenConfig {
+  /// A name for the token, used in logs.
+  pub name: String,
+  pub token: String,
+  pub role: Role,
+  /// The devices the token may access, as `ip` or `ip:port`. Every device when empty.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub devices: Vec<String>,
+}
+
+/// A user who signs in with HTTP basic auth.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct UserConfig {
+  pub username: String,
+  pub password: String,
+  pub role: Role,
+  /// The devices the user may access, as `ip` or `ip:port`. Every device when empty.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub devices: Vec<String>,
+}
+
+impl fmt::Debug for ApiTokenConfig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ApiTokenConfig")
+      .field("name", &self.name)
+      .field("token", &REDACTED)
+      .field("role", &self.role)
+      .field("devices", &self.devices)
+      .finish()
+  }
+}
+
+impl fmt::Debug for WebhookConfig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("WebhookConfig")
+      .field("name", &self.name)
+      .field("url", &redact_url(&self.url))
+      .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+      .field("events", &self.events)
+      .field("max_attempts", &self.max_attempts)
+      .finish()
+  }
+}
+
+impl fmt::Debug for MqttSettings {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("MqttSettings")
+      .field("host", &self.host)
+      .field("port", &self.port)
+      .field("client_id", &self.client_id)
+      .field("username", &self.username)
+      .field("password", &self.password.as_ref().map(|_| REDACTED))
+      .field("topic_prefix", &self.topic_prefix)
+      .field("commands", &self.commands)
+      .field("command_devices", &self.command_devices)
+      .field("discovery", &self.discovery)
+      .field("discovery_prefix", &self.discovery_prefix)
+      .field("keep_alive_seconds", &self.keep_alive_seconds)
+      .finish()
+  }
+}
+
+impl fmt::Debug for UserConfig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("UserConfig")
+      .field("username", &self.username)
+      .field("password", &REDACTED)
+      .field("role", &self.role)
+      .field("devices", &self.devices)
+      .finish()
+  }
+}
+
+/// Hides the path and query of `url`, which services like Slack use as a credential.
+fn redact_url(url: &str) -> String {
+  match reqwest::Url::parse(url) {
+    Ok(url) => format!(
+      "{}://{}/{REDACTED}",
+      url.scheme(),
+      url.host_str().unwrap_or_default()
+    ),
+    Err(_) => REDACTED.to_string(),
+  }
+}
+
+/// Shown in place of secret setting values.
+pub const REDACTED: &str = "<redacted>";
+
 impl CastielSettings {
   /// Used to initialize settings from the file at `config_path`. It will create a default config
   /// at that path if none exists.
@@ -32,8 +561,7 @@ impl CastielSettings {
       std::fs::write(config_path, toml_string)?;
     }
 
-    let file_content = std::fs::read_to_string(config_path)?;
-    let settings: Self = toml::from_str(&file_content)?;
+    let settings = Self::load(config_path)?;
 
     // Possibly log initialization depending on level
     if settings.log_level.eq_ignore_ascii_case("INFO")
@@ -44,4 +572,110 @@ impl CastielSettings {
 
     Ok(settings)
   }
+
+  /// Reads and parses the settings file at `config_path` without creating it.
+  pub fn load(config_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    let file_content = std::fs::read_to_string(config_path)?;
+    let settings: Self = toml::from_str(&file_content)?;
+    if let Some(relay) = &settings.relay {
+      relay.check()?;
+    }
+    if let Some(mqtt) = &settings.mqtt {
+      mqtt.check()?;
+    }
+    Ok(settings)
+  }
+
+  /// A copy of the settings with secrets hidden, for showing to API clients.
+  pub fn redacted(&self) -> Self {
+    let mut settings = self.clone();
+    if let Some(relay) = &mut settings.relay {
+      relay.sources = relay
+        .sources
+        .iter()
+        .map(RelaySourceConfig::redacted)
+        .collect();
+    }
+    for webhook in &mut settings.webhooks {
+      webhook.url = redact_url(&webhook.url);
+      if webhook.secret.is_some() {
+        webhook.secret = Some(REDACTED.to_string());
+      }
+    }
+    if let Some(mqtt) = &mut settings.mqtt
+      && mqtt.password.is_some()
+    {
+      mqtt.password = Some(REDACTED.to_string());
+    }
+    if let Some(auth) = &mut settings.auth {
+      for token in &mut auth.tokens {
+        token.token = REDACTED.to_string();
+      }
+      for user in &mut auth.users {
+        user.password = REDACTED.to_string();
+      }
+    }
+    settings
+  }
+
+  /// Returns the names of the top-level settings whose values differ between `self` and `other`.
+  pub fn changed_keys(&self, other: &Self) -> Vec<String> {
+    let (Ok(old), Ok(new)) = (toml::Table::try_from(self), toml::Table::try_from(other)) else {
+      return Vec::new();
+    };
+
+    let mut changed: Vec<String> = old
+      .keys()
+      .chain(new.keys().filter(|key| !old.contains_key(*key)))
+      .filter(|key| old.get(*key) != new.get(*key))
+      .cloned()
+      .collect();
+    changed.sort();
+    changed
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Loads the default settings with `mqtt` appended.
+  fn load_with_mqtt(mqtt: &str) -> Result<CastielSettings, Box<dyn std::error::Error>> {
+    let defaults = toml::to_string(&CastielSettings::default()).unwrap();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), format!("{defaults}\n[mqtt]\n{mqtt}")).unwrap();
+    CastielSettings::load(file.path())
+  }
+
+  #[test]
+  fn mqtt_passwords_need_a_username() {
+    let err = load_with_mqtt("host = \"broker\"\npassword = \"secret\"\n").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "mqtt.password is set without mqtt.username"
+    );
+
+    let settings =
+      load_with_mqtt("host = \"broker\"\nusername = \"castiel\"\npassword = \"secret\"\n").unwrap();
+    assert_eq!(settings.mqtt.unwrap().password.as_deref(), Some("secret"));
+  }
+  #[test]
+  fn relay_source_prefixes_must_be_urls() {
+    let mut relay = RelaySettings {
+      port: 8090,
+      advertised_host: None,
+      ffmpeg_path: default_ffmpeg_path(),
+      idle_timeout_seconds: default_relay_idle_timeout_seconds(),
+      sources: vec![RelaySourceConfig {
+        url_prefix: "https://cam.example.com/live".to_string(),
+        headers: BTreeMap::new(),
+        username: None,
+        password: None,
+      }],
+    };
+    assert!(relay.check().is_ok());
+
+    relay.sources[0].url_prefix = "cam.example.com".to_string();
+    assert!(relay.check().unwrap_err().contains("cam.example.com"));
+  }
 }
@@ -7,14 +7,14 @@ use std::{
 
 use flume::RecvTimeoutError;
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::errors::CastielError;
 
 /// Used to inform the mdns browse command on what services are being searched for.
 const SERVICE_TYPE: &str = "_googlecast._tcp.local.";
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiscoveredDevice {
   /// IPv4 or IPv6 address of the discovered Chromecast device.
   pub ip_address: String,
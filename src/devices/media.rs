@@ -1,102 +1,167 @@
 //! Defines functionality for starting media playback and display on Chromecast devices.
 
-use rust_cast::{
-  CastDevice,
-  channels::{
-    media::{Media, StreamType},
-    receiver::CastDeviceApp,
-  },
-};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use crate::{
-  devices::DeviceAddress,
-  devices::app_ids::{WEBVIEW_ID, WEBVIEW_NAMESPACE},
+  config::ReceiverAppConfig,
+  devices::app_ids::{DEFAULT_MEDIA_ID, WEBVIEW_ID, WEBVIEW_NAMESPACE},
+  devices::raw::{MEDIA_NAMESPACE, PLATFORM_RECEIVER_ID, RECEIVER_NAMESPACE, RawCastConnection},
+  devices::status::{self, MediaStatus},
+  devices::tracks::{self, TextTrackData, TextTrackStyle},
+  devices::validation::FieldError,
+  devices::youtube::{self, YouTubeVideo},
+  devices::{AppSelector, DeviceAddress},
   errors::CastielError,
+  relay::RelayMode,
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 pub enum ReceiverOptions {
   Default,
   YouTube,
   Web,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "UPPERCASE"))]
 pub enum StreamTypeOptions {
   Live,
   Buffered,
   None,
 }
 
-impl From<StreamTypeOptions> for StreamType {
-  fn from(opts: StreamTypeOptions) -> Self {
-    match opts {
-      StreamTypeOptions::Live => Self::Live,
-      StreamTypeOptions::Buffered => Self::Buffered,
-      StreamTypeOptions::None => Self::None,
-    }
-  }
+/// The container format of HLS segments, or of the audio segments if video is segmented
+/// separately.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "snake_case"))]
+pub enum HlsSegmentFormat {
+  Aac,
+  Ac3,
+  Mp3,
+  Ts,
+  TsAac,
+  /// Cast spells this one with a hyphen.
+  #[serde(rename(serialize = "e-ac3"))]
+  EAc3,
+  Fmp4,
 }
 
-#[derive(Debug, Deserialize)]
+/// The container format of HLS video segments.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "snake_case"))]
+pub enum HlsVideoSegmentFormat {
+  Mpeg2Ts,
+  Fmp4,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct StartMediaData {
   pub ip_address: String,
   pub port: u16,
   pub receiver: ReceiverOptions,
   pub media_url: String,
-  pub content_type: String,
-  pub stream_type: StreamTypeOptions,
+  /// The MIME type of the media. Inferred from the media URL when omitted.
+  pub content_type: Option<String>,
+  /// How the media is streamed. Inferred from the content type when omitted.
+  pub stream_type: Option<StreamTypeOptions>,
+  /// Subtitles or captions to load with the media. Only supported by the Default receiver.
+  #[serde(default)]
+  pub text_tracks: Vec<TextTrackData>,
+  /// How the text tracks are drawn. Only supported by the Default receiver.
+  pub text_track_style: Option<TextTrackStyle>,
+  /// Where to start playback, in seconds. Taken from the `t` parameter of YouTube URLs when
+  /// omitted.
+  pub start_time: Option<f64>,
+  /// A YouTube playlist to play the video as part of. Taken from the `list` parameter of YouTube
+  /// URLs when omitted.
+  pub playlist_id: Option<String>,
+  /// The format of HLS segments. Inferred from the media playlist when omitted.
+  pub hls_segment_format: Option<HlsSegmentFormat>,
+  /// The format of HLS video segments. Inferred from the media playlist when omitted.
+  pub hls_video_segment_format: Option<HlsVideoSegmentFormat>,
+  /// Reload the media if the device reports that playback failed or stopped by itself. Intended
+  /// for live streams, and only supported by the Default receiver.
+  #[serde(default)]
+  pub auto_restart: bool,
+  /// Serve the media to the device through Castiel instead of having the device fetch it. Only
+  /// supported by the Default receiver.
+  pub relay: Option<RelayMode>,
 }
 
 /// Starts media using the contents of `StartMediaData`.
-pub fn start_from_data(data: StartMediaData) -> Result<(), CastielError> {
+///
+/// Returns the request in the validated, normalised form the media was started with.
+pub async fn start_from_data(data: StartMediaData) -> Result<StartMediaData, CastielError> {
+  let data = data.validate()?;
   tracing::info!("Starting media from data: {data:?}");
 
-  let ip = data.ip_address.clone();
-  let cast_device = super::get_cast_device(&ip, data.port)?;
-
   match data.receiver {
     ReceiverOptions::Default => {
-      start_app_and_media(&cast_device, &CastDeviceApp::DefaultMediaReceiver, data)?;
+      let data = data.clone();
+      super::blocking(move || load_default_media(&data)).await?;
+    }
+    ReceiverOptions::YouTube => {
+      let video = YouTubeVideo {
+        video_id: data.media_url.clone(),
+        playlist_id: data.playlist_id.clone(),
+        start_time: data.start_time,
+      };
+      youtube::play_video(&data.ip_address, data.port, &video).await?;
+    }
+    ReceiverOptions::Web => {
+      let (ip, port, url) = (data.ip_address.clone(), data.port, data.media_url.clone());
+      super::blocking(move || {
+        let connection = RawCastConnection::connect(&ip, port)?;
+        start_web_media(&connection, url)
+      })
+      .await?;
     }
-    ReceiverOptions::YouTube => start_app_and_media(&cast_device, &CastDeviceApp::YouTube, data)?,
-    ReceiverOptions::Web => start_web_media(&cast_device, data.media_url)?,
   }
 
-  Ok(())
+  Ok(data)
 }
 
-/// Uses the `cast_device` to start the specified `app_to_start` and begin playing `data`.
+/// Launches the Default Media Receiver and loads `data` along with its text tracks and HLS
+/// segment formats.
 ///
-/// This function can be used to start generic media with [`CastDeviceApp::DefaultMediaReciever`]
-/// or YouTube videos with [`CastDevice::YouTube`].
-fn start_app_and_media(
-  cast_device: &CastDevice,
-  app_to_start: &CastDeviceApp,
-  data: StartMediaData,
-) -> Result<(), CastielError> {
-  let app = cast_device
-    .receiver
-    .launch_app(app_to_start)
-    .map_err(CastielError::AppError)?;
+/// [`rust_cast`] can't describe any of these, so the media is loaded over a
+/// [`RawCastConnection`].
+pub fn load_default_media(data: &StartMediaData) -> Result<(), CastielError> {
+  let connection = RawCastConnection::connect(&data.ip_address, data.port)?;
+  let app = connection.launch_app(DEFAULT_MEDIA_ID)?;
 
-  cast_device
-    .connection
-    .connect(app.transport_id.as_str())
-    .map_err(CastielError::ConnError)?;
+  let (tracks, active_track_ids) = tracks::cast_text_tracks(&data.text_tracks);
+  let mut media = json!({
+    "contentId": data.media_url,
+    "contentType": data.content_type.clone().unwrap_or_default(),
+    "streamType": data.stream_type.unwrap_or(StreamTypeOptions::Buffered),
+    "tracks": tracks,
+  });
+  if let Some(style) = &data.text_track_style {
+    media["textTrackStyle"] = json!(style);
+  }
+  if let Some(format) = data.hls_segment_format {
+    media["hlsSegmentFormat"] = json!(format);
+  }
+  if let Some(format) = data.hls_video_segment_format {
+    media["hlsVideoSegmentFormat"] = json!(format);
+  }
 
-  let media = Media {
-    content_id: data.media_url,
-    content_type: data.content_type,
-    stream_type: data.stream_type.into(),
-    duration: None,
-    metadata: None,
-  };
+  let mut load = json!({
+    "type": "LOAD",
+    "sessionId": app.session_id,
+    "media": media,
+    "activeTrackIds": active_track_ids,
+    "autoplay": true,
+  });
+  // Without a start time live streams start at the live edge
+  if let Some(start_time) = data.start_time {
+    load["currentTime"] = json!(start_time);
+  }
 
-  cast_device
-    .media
-    .load(app.transport_id, app.session_id, &media)
+  connection
+    .request(MEDIA_NAMESPACE, &app.transport_id, load)
     .map_err(CastielError::MediaError)?;
 
   Ok(())
@@ -108,55 +173,197 @@ struct WebAppMessage {
   proxy: bool,
 }
 
-fn start_web_media(cast_device: &CastDevice, media_url: String) -> Result<(), CastielError> {
-  // Launch web viewer app
-  let app_to_launch = CastDeviceApp::Custom(WEBVIEW_ID.to_string());
-  let app = cast_device
-    .receiver
-    .launch_app(&app_to_launch)
-    .map_err(CastielError::AppError)?;
-
-  // Start connection to web viewer
-  cast_device
-    .connection
-    .connect(app.transport_id.as_str())
-    .map_err(CastielError::ConnError)?;
-
-  // Broadcast a message to the running web viewer app
-  cast_device
-    .receiver
-    .broadcast_message(
+fn start_web_media(connection: &RawCastConnection, media_url: String) -> Result<(), CastielError> {
+  launch_app_with_message(
+    connection,
+    WEBVIEW_ID,
+    Some((
       WEBVIEW_NAMESPACE,
       &WebAppMessage {
         url: media_url,
         proxy: false,
       },
-    )
-    .map_err(CastielError::MediaError)?;
+    )),
+  )
+}
+
+/// Launches the app with `app_id` and, if given, broadcasts `message` on `namespace` once the app
+/// is running.
+fn launch_app_with_message<M: Serialize>(
+  connection: &RawCastConnection,
+  app_id: &str,
+  message: Option<(&str, &M)>,
+) -> Result<(), CastielError> {
+  // Launch the receiver app and connect to it
+  connection.launch_app(app_id)?;
+
+  // Broadcast a message to the running app
+  if let Some((namespace, message)) = message {
+    let payload =
+      serde_json::to_value(message).map_err(|err| CastielError::MediaError(err.into()))?;
+    connection
+      .send(namespace, "*", payload)
+      .map_err(CastielError::MediaError)?;
+  }
 
   Ok(())
 }
 
-pub fn stop_media_at_device(device_addr: &DeviceAddress) -> Result<(), CastielError> {
-  let cast_device = super::get_cast_device(&device_addr.ip, device_addr.port)?;
+/// A request to launch a registered receiver app and optionally send it a message.
+#[derive(Debug, Deserialize)]
+pub struct CustomAppData {
+  pub ip_address: String,
+  pub port: u16,
+  /// The ID or name of an app from the `receiver_apps` setting.
+  pub app: String,
+  /// A JSON message to send on the app's namespace after launching it.
+  pub message: Option<serde_json::Value>,
+}
+
+/// Launches the registered receiver app named in `data` and sends it `data.message`.
+pub fn start_custom_app(
+  data: CustomAppData,
+  receiver_apps: &[ReceiverAppConfig],
+) -> Result<(), CastielError> {
+  tracing::info!("Starting custom app from data: {data:?}");
 
-  // Get status
-  let device_status = cast_device
-    .receiver
-    .get_status()
-    .map_err(CastielError::ConnError)?;
+  let app_config = receiver_apps
+    .iter()
+    .find(|app| app.id == data.app || app.name.eq_ignore_ascii_case(&data.app))
+    .ok_or_else(|| CastielError::AppNotRegistered(data.app.clone()))?;
+  let message = data
+    .message
+    .map(|message| prepare_custom_message(app_config, message))
+    .transpose()?;
+
+  let connection = RawCastConnection::connect(&data.ip_address, data.port)?;
+  match (&app_config.namespace, &message) {
+    (Some(namespace), Some(message)) => launch_app_with_message(
+      &connection,
+      &app_config.id,
+      Some((namespace.as_str(), message)),
+    ),
+    _ => launch_app_with_message::<serde_json::Value>(&connection, &app_config.id, None),
+  }
+}
+
+/// Checks `message` against the app's registered schema and sets its message type.
+fn prepare_custom_message(
+  app_config: &ReceiverAppConfig,
+  mut message: serde_json::Value,
+) -> Result<serde_json::Value, CastielError> {
+  let mut errors = Vec::new();
+  if app_config.namespace.is_none() {
+    errors.push(FieldError::new(
+      "message",
+      format!(
+        "app `{}` has no namespace configured to send messages on",
+        app_config.name
+      ),
+    ));
+  }
 
-  if let Some(app) = device_status.applications.first() {
-    cast_device
-      .receiver
-      .stop_app(app.session_id.clone())
-      .map_err(CastielError::AppError)?;
-    Ok(())
+  match message.as_object_mut() {
+    Some(fields) => {
+      for required in &app_config.required_fields {
+        if !fields.contains_key(required) {
+          errors.push(FieldError::new(
+            "message",
+            format!("missing required field `{required}`"),
+          ));
+        }
+      }
+      if let Some(message_type) = &app_config.message_type {
+        fields.insert("type".to_string(), message_type.clone().into());
+      }
+    }
+    None => errors.push(FieldError::new("message", "must be a JSON object")),
+  }
+
+  if errors.is_empty() {
+    Ok(message)
   } else {
-    Err(CastielError::AppLookupFailed)
+    Err(CastielError::ValidationFailed(errors))
   }
 }
 
+/// The IDs of an app running on a device, as listed in its receiver status.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunningSession {
+  app_id: String,
+  session_id: String,
+}
+
+/// Stops the app selected by `app_selector` on the device at `device_addr`.
+pub fn stop_media_at_device(
+  device_addr: &DeviceAddress,
+  app_selector: &AppSelector,
+) -> Result<(), CastielError> {
+  let connection = RawCastConnection::connect(&device_addr.ip, device_addr.port)?;
+
+  // Get status
+  let reply = connection
+    .request(
+      RECEIVER_NAMESPACE,
+      PLATFORM_RECEIVER_ID,
+      json!({ "type": "GET_STATUS" }),
+    )
+    .map_err(CastielError::ConnError)?;
+  let applications: Vec<RunningSession> =
+    serde_json::from_value(reply["status"]["applications"].clone())
+      .map_err(|err| CastielError::ConnError(err.into()))?;
+
+  let app = app_selector.select(&applications, |app| {
+    (app.app_id.as_str(), app.session_id.as_str())
+  })?;
+  connection
+    .request(
+      RECEIVER_NAMESPACE,
+      PLATFORM_RECEIVER_ID,
+      json!({ "type": "STOP", "sessionId": app.session_id }),
+    )
+    .map_err(CastielError::AppError)?;
+
+  Ok(())
+}
+
+/// A command controlling playback of the media loaded on a device.
+#[derive(Debug, Clone, Copy)]
+pub enum PlaybackCommand {
+  Play,
+  Pause,
+}
+
+/// Sends `command` to the media session of the app selected by `app_selector` on the device at
+/// `device_addr`, returning the updated media status.
+pub fn control_playback(
+  device_addr: &DeviceAddress,
+  app_selector: &AppSelector,
+  command: PlaybackCommand,
+) -> Result<MediaStatus, CastielError> {
+  let session = status::open_media_session(device_addr, app_selector)?;
+  let kind = match command {
+    PlaybackCommand::Play => "PLAY",
+    PlaybackCommand::Pause => "PAUSE",
+  };
+  session.command(json!({ "type": kind }))
+}
+
+/// Sets the volume of the device at `device_addr` to `level`, from 0 to 1.
+pub fn set_volume_at_device(device_addr: &DeviceAddress, level: f32) -> Result<(), CastielError> {
+  let connection = RawCastConnection::connect(&device_addr.ip, device_addr.port)?;
+  connection
+    .request(
+      RECEIVER_NAMESPACE,
+      PLATFORM_RECEIVER_ID,
+      json!({ "type": "SET_VOLUME", "volume": { "level": level } }),
+    )
+    .map_err(CastielError::MediaCommandFailed)?;
+
+  Ok(())
+}
+
 // TODO - Decide if there's anything to do with this
 //
 // loop {
@@ -180,3 +387,38 @@ pub fn stop_media_at_device(device_addr: &DeviceAddress) -> Result<(), CastielEr
 //     Err(error) => println!("Error occurred while receiving message {}", error),
 //   }
 // }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hls_segment_formats_use_cast_names() {
+    let formats = [
+      (HlsSegmentFormat::Aac, "aac"),
+      (HlsSegmentFormat::Ac3, "ac3"),
+      (HlsSegmentFormat::Mp3, "mp3"),
+      (HlsSegmentFormat::Ts, "ts"),
+      (HlsSegmentFormat::TsAac, "ts_aac"),
+      (HlsSegmentFormat::EAc3, "e-ac3"),
+      (HlsSegmentFormat::Fmp4, "fmp4"),
+    ];
+    for (format, name) in formats {
+      assert_eq!(serde_json::to_value(format).unwrap(), name);
+    }
+
+    let video_formats = [
+      (HlsVideoSegmentFormat::Mpeg2Ts, "mpeg2_ts"),
+      (HlsVideoSegmentFormat::Fmp4, "fmp4"),
+    ];
+    for (format, name) in video_formats {
+      assert_eq!(serde_json::to_value(format).unwrap(), name);
+    }
+  }
+
+  #[test]
+  fn hls_segment_formats_are_read_by_variant_name() {
+    let format: HlsSegmentFormat = serde_json::from_str("\"EAc3\"").unwrap();
+    assert!(matches!(format, HlsSegmentFormat::EAc3));
+  }
+}
@@ -1,14 +1,18 @@
 pub mod app_ids;
 pub mod discovery;
 pub mod media;
+pub mod pinning;
+pub mod raw;
+pub mod registry;
+pub mod sniffing;
 pub mod status;
+pub mod tracks;
+pub mod validation;
+pub mod youtube;
 
-use rust_cast::CastDevice;
 use serde::Deserialize;
 
-use crate::errors::CastielError;
-
-const DEFAULT_DESTINATION_ID: &str = "receiver-0";
+use crate::{devices::app_ids::BACKDROP_ID, errors::CastielError};
 
 /// A serialization structure for a device address sent in an API request.
 #[derive(Debug, Deserialize)]
@@ -17,22 +21,128 @@ pub struct DeviceAddress {
   pub port: u16,
 }
 
-/// Retrieve a cast device by `ip` and `port`.
-fn get_cast_device(ip: &str, port: u16) -> Result<CastDevice, CastielError> {
-  // TODO - Figure out how to use host verification properly.
-  let cast_device = CastDevice::connect_without_host_verification(ip, port)
-    .map_err(CastielError::DeviceLookupFailed)?;
-
-  // Test connection
-  // TODO - Honestly, can I just remove this? Seems like no - it makes status requests fail.
-  cast_device
-    .connection
-    .connect(DEFAULT_DESTINATION_ID.to_string())
-    .map_err(CastielError::ConnError)?;
-  cast_device
-    .heartbeat
-    .ping()
-    .map_err(CastielError::ConnError)?;
-
-  Ok(cast_device)
+/// Identifies one of the apps running on a device, by session ID or app ID.
+///
+/// When neither is given the device's foreground app is meant: the first running app other than
+/// the Backdrop, or the Backdrop if nothing else is running.
+#[derive(Debug, Default, Deserialize)]
+pub struct AppSelector {
+  pub session_id: Option<String>,
+  pub app_id: Option<String>,
+}
+
+impl AppSelector {
+  /// Picks the app this selector refers to from `apps`, using `ids` to get each app's
+  /// `(app_id, session_id)`.
+  pub fn select<'a, T>(
+    &self,
+    apps: &'a [T],
+    ids: impl Fn(&T) -> (&str, &str),
+  ) -> Result<&'a T, CastielError> {
+    match (&self.session_id, &self.app_id) {
+      (Some(session_id), _) => apps
+        .iter()
+        .find(|app| ids(app).1 == session_id)
+        .ok_or_else(|| CastielError::AppNotFound(format!("session {session_id}"))),
+      (None, Some(app_id)) => apps
+        .iter()
+        .find(|app| ids(app).0 == app_id)
+        .ok_or_else(|| CastielError::AppNotFound(format!("app {app_id}"))),
+      (None, None) => foreground_app(apps, ids).ok_or(CastielError::AppLookupFailed),
+    }
+  }
+}
+
+/// Returns the first app in `apps` which isn't the Backdrop, falling back to the first app.
+pub fn foreground_app<T>(apps: &[T], ids: impl Fn(&T) -> (&str, &str)) -> Option<&T> {
+  apps
+    .iter()
+    .find(|app| ids(app).0 != BACKDROP_ID)
+    .or_else(|| apps.first())
+}
+
+/// Runs a blocking device request on the blocking thread pool, so that it doesn't hold up the
+/// async runtime while waiting on the device.
+pub async fn blocking<T: Send + 'static>(
+  request: impl FnOnce() -> Result<T, CastielError> + Send + 'static,
+) -> Result<T, CastielError> {
+  tokio::task::spawn_blocking(request)
+    .await
+    .map_err(|_| CastielError::InternalError)?
+}
+
+/// A request addressed to one of the apps running on a device.
+#[derive(Debug, Deserialize)]
+pub struct AppRequest {
+  #[serde(flatten)]
+  pub device: DeviceAddress,
+  #[serde(flatten)]
+  pub app: AppSelector,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::devices::app_ids::DEFAULT_MEDIA_ID;
+
+  /// `(app_id, session_id)` pairs.
+  const APPS: [(&str, &str); 3] = [
+    (BACKDROP_ID, "backdrop"),
+    (DEFAULT_MEDIA_ID, "media"),
+    ("ABCD1234", "custom"),
+  ];
+
+  fn ids<'a>(app: &'a (&str, &str)) -> (&'a str, &'a str) {
+    *app
+  }
+
+  fn selector(session_id: Option<&str>, app_id: Option<&str>) -> AppSelector {
+    AppSelector {
+      session_id: session_id.map(ToString::to_string),
+      app_id: app_id.map(ToString::to_string),
+    }
+  }
+
+  #[test]
+  fn apps_are_selected_by_session_first() {
+    let app = selector(Some("custom"), Some(DEFAULT_MEDIA_ID))
+      .select(&APPS, ids)
+      .unwrap();
+    assert_eq!(app.1, "custom");
+  }
+
+  #[test]
+  fn apps_are_selected_by_app_id() {
+    let app = selector(None, Some("ABCD1234")).select(&APPS, ids).unwrap();
+    assert_eq!(app.1, "custom");
+  }
+
+  #[test]
+  fn missing_apps_are_not_found() {
+    assert!(matches!(
+      selector(Some("gone"), None).select(&APPS, ids),
+      Err(CastielError::AppNotFound(app)) if app == "session gone"
+    ));
+    assert!(matches!(
+      selector(None, Some("FFFF0000")).select(&APPS, ids),
+      Err(CastielError::AppNotFound(app)) if app == "app FFFF0000"
+    ));
+    assert!(matches!(
+      AppSelector::default().select(&[] as &[(&str, &str)], ids),
+      Err(CastielError::AppLookupFailed)
+    ));
+  }
+
+  #[test]
+  fn the_foreground_app_is_selected_by_default() {
+    let app = AppSelector::default().select(&APPS, ids).unwrap();
+    assert_eq!(app.1, "media");
+  }
+
+  #[test]
+  fn the_backdrop_is_only_in_the_foreground_alone() {
+    assert_eq!(foreground_app(&APPS, ids).unwrap().1, "media");
+    assert_eq!(foreground_app(&APPS[..1], ids).unwrap().1, "backdrop");
+    assert!(foreground_app(&[] as &[(&str, &str)], ids).is_none());
+  }
 }
@@ -0,0 +1,381 @@
+//! Pins the certificate each device presents the first time Castiel connects to it.
+//!
+//! Cast devices use self-signed certificates, so they can't be verified against a CA. Instead the
+//! certificate seen on the first connection is trusted (trust on first use) and later connections
+//! are checked against it. What happens when a device presents a different certificate depends on
+//! the [`PinningMode`] setting. Pins are kept in the state directory so they survive restarts.
+//!
+//! Pinning is weaker than it sounds for Cast devices. They regenerate their TLS certificate from
+//! time to time (typically every day or on reboot) and prove who they are with the signed
+//! challenge on the `deviceauth` namespace instead, which Castiel doesn't perform. So a changed
+//! certificate is usually not an attack: with [`PinningMode::Enforce`] devices get locked out
+//! until their pin is reset, and [`PinningMode::Warn`] reports each regenerated certificate.
+//!
+//! Devices are pinned under their `ip:port` address until discovery reports their device ID. The
+//! pin is then moved to the ID, so that it follows the device to a new address.
+
+use std::{
+  collections::BTreeMap,
+  sync::{Arc, Mutex, OnceLock, PoisonError},
+};
+
+use aws_lc_rs::digest::{SHA256, digest};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  clock::unix_now,
+  config::{PinningMode, SharedSettings},
+  devices::registry::DeviceRegistry,
+  errors::CastielError,
+  storage::{Collection, StateStore},
+};
+
+/// The store checked by every device connection, once [`install`]ed.
+static PIN_STORE: OnceLock<Arc<PinStore>> = OnceLock::new();
+
+/// The certificate pinned for a device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevicePin {
+  /// The SHA-256 fingerprint of the pinned certificate.
+  pub fingerprint: String,
+  /// When the certificate was pinned, as a Unix timestamp.
+  pub first_seen: u64,
+  /// The `ip:port` address the device was at when its certificate was pinned.
+  pub address: String,
+  /// The most recent certificate the device presented which didn't match the pin, if any.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub mismatch: Option<CertificateMismatch>,
+}
+
+/// A certificate a device presented which didn't match its pin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateMismatch {
+  /// The SHA-256 fingerprint of the presented certificate.
+  pub fingerprint: String,
+  /// When the certificate was last presented, as a Unix timestamp.
+  pub seen_at: u64,
+}
+
+/// A pin along with the device it belongs to, as listed by the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct PinnedDevice {
+  /// The device ID reported by discovery, or the `ip:port` address of devices which haven't been
+  /// discovered.
+  pub device: String,
+  #[serde(flatten)]
+  pub pin: DevicePin,
+}
+
+/// The certificates pinned for each device, keyed by device ID.
+pub struct PinStore {
+  store: Arc<StateStore>,
+  settings: SharedSettings,
+  registry: Arc<DeviceRegistry>,
+  pins: Mutex<BTreeMap<String, DevicePin>>,
+}
+
+/// The pins as they are saved, keyed by device ID or address.
+#[derive(Default, Serialize, Deserialize)]
+struct DevicePins(BTreeMap<String, DevicePin>);
+
+impl Collection for DevicePins {
+  const NAME: &'static str = "device-pins";
+  const VERSION: u32 = 1;
+}
+
+impl PinStore {
+  /// Loads the pins saved in `store`, starting with none if none have been saved yet.
+  pub fn load(
+    store: Arc<StateStore>,
+    settings: SharedSettings,
+    registry: Arc<DeviceRegistry>,
+  ) -> Result<Self, CastielError> {
+    let DevicePins(pins) = store.load()?;
+
+    Ok(Self {
+      store,
+      settings,
+      registry,
+      pins: Mutex::new(pins),
+    })
+  }
+
+  /// Returns every pin, sorted by device.
+  pub fn pins(&self) -> Vec<PinnedDevice> {
+    self
+      .pins
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .iter()
+      .map(|(device, pin)| PinnedDevice {
+        device: device.clone(),
+        pin: pin.clone(),
+      })
+      .collect()
+  }
+
+  /// Forgets the pin for `device`, so that the next certificate it presents is pinned instead.
+  pub fn reset(&self, device: &str) -> Result<(), CastielError> {
+    let mut pins = self.pins.lock().unwrap_or_else(PoisonError::into_inner);
+    if pins.remove(device).is_none() {
+      return Err(CastielError::DevicePinNotFound(device.to_string()));
+    }
+    tracing::info!("Reset the certificate pin for device {device}");
+    self.save(&pins);
+
+    Ok(())
+  }
+
+  /// Checks the DER-encoded `certificate` presented by the device at `ip`:`port` against its pin,
+  /// pinning it if the device has none.
+  ///
+  /// Fails with [`CastielError::DeviceCertificateChanged`] if the certificate doesn't match and
+  /// pinning is enforced.
+  pub fn verify(&self, ip: &str, port: u16, certificate: &[u8]) -> Result<(), CastielError> {
+    let mode = self
+      .settings
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .device_pinning
+      .mode;
+    if mode == PinningMode::Off {
+      return Ok(());
+    }
+
+    let address = format!("{ip}:{port}");
+    let device = self
+      .registry
+      .find(ip, port)
+      .and_then(|device| device.id)
+      .unwrap_or_else(|| address.clone());
+    let fingerprint = fingerprint(certificate);
+    let described = if device == address {
+      address.clone()
+    } else {
+      format!("{device} at {address}")
+    };
+
+    let mut pins = self.pins.lock().unwrap_or_else(PoisonError::into_inner);
+    if device != address
+      && !pins.contains_key(&device)
+      && let Some(pin) = pins.remove(&address)
+    {
+      tracing::info!("Moved the certificate pin for {address} to device {device}");
+      pins.insert(device.clone(), pin);
+      self.save(&pins);
+    }
+    let Some(pin) = pins.get_mut(&device) else {
+      tracing::info!("Pinned the certificate of device {described}: {fingerprint}");
+      pins.insert(
+        device,
+        DevicePin {
+          fingerprint,
+          first_seen: unix_now(),
+          address,
+          mismatch: None,
+        },
+      );
+      self.save(&pins);
+      return Ok(());
+    };
+    if pin.fingerprint == fingerprint {
+      return Ok(());
+    }
+
+    // Only report and write the file the first time a new certificate is seen
+    let seen_before = pin
+      .mismatch
+      .as_ref()
+      .is_some_and(|mismatch| mismatch.fingerprint == fingerprint);
+    if !seen_before {
+      tracing::warn!(
+        "Device {described} presented certificate {fingerprint}, but {} is pinned",
+        pin.fingerprint
+      );
+    }
+    pin.mismatch = Some(CertificateMismatch {
+      fingerprint,
+      seen_at: unix_now(),
+    });
+    if !seen_before {
+      self.save(&pins);
+    }
+
+    match mode {
+      PinningMode::Enforce => Err(CastielError::DeviceCertificateChanged(device)),
+      _ => Ok(()),
+    }
+  }
+
+  /// Saves `pins` in the state directory. Failures are logged, and the pins kept in memory
+  /// regardless.
+  fn save(&self, pins: &BTreeMap<String, DevicePin>) {
+    if let Err(err) = self.store.save(&DevicePins(pins.clone())) {
+      tracing::warn!("Failed to save device pins: {err}");
+    }
+  }
+}
+
+/// Makes `store` the one checked by every device connection.
+pub fn install(store: Arc<PinStore>) {
+  if PIN_STORE.set(store).is_err() {
+    tracing::warn!("A device pin store is already installed");
+  }
+}
+
+/// Checks `certificate` against the installed store. Always passes if none is installed.
+pub fn verify(ip: &str, port: u16, certificate: &[u8]) -> Result<(), CastielError> {
+  match PIN_STORE.get() {
+    Some(store) => store.verify(ip, port, certificate),
+    None => Ok(()),
+  }
+}
+
+/// Formats the SHA-256 digest of `certificate` as colon-separated hex, as `openssl x509
+/// -fingerprint -sha256` does.
+fn fingerprint(certificate: &[u8]) -> String {
+  digest(&SHA256, certificate)
+    .as_ref()
+    .iter()
+    .map(|byte| format!("{byte:02X}"))
+    .collect::<Vec<_>>()
+    .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, path::Path, sync::RwLock};
+
+  use super::*;
+  use crate::{
+    config::{CastielSettings, DevicePinningSettings},
+    devices::discovery::DiscoveredDevice,
+  };
+
+  fn pin_store(dir: &Path, mode: PinningMode) -> (PinStore, Arc<DeviceRegistry>) {
+    let store = Arc::new(StateStore::open(dir).unwrap());
+    let settings = Arc::new(RwLock::new(CastielSettings {
+      device_pinning: DevicePinningSettings { mode },
+      ..Default::default()
+    }));
+    let registry = Arc::new(DeviceRegistry::load(store.clone()).unwrap());
+    let pins = PinStore::load(store, settings, registry.clone()).unwrap();
+    (pins, registry)
+  }
+
+  fn device(ip: &str, id: &str) -> DiscoveredDevice {
+    DiscoveredDevice {
+      ip_address: ip.to_string(),
+      port: 8009,
+      fullname: format!("Chromecast-{id}._googlecast._tcp.local."),
+      id: Some(id.to_string()),
+      model_name: None,
+      friendly_name: None,
+      txt_properties: HashMap::new(),
+    }
+  }
+
+  #[test]
+  fn the_first_certificate_is_pinned() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, _) = pin_store(dir.path(), PinningMode::Enforce);
+
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    let pinned = pins.pins();
+    assert_eq!(pinned.len(), 1);
+    assert_eq!(pinned[0].device, "192.168.1.20:8009");
+    assert_eq!(pinned[0].pin.fingerprint, fingerprint(b"first"));
+    assert!(pinned[0].pin.mismatch.is_none());
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+  }
+
+  #[test]
+  fn a_changed_certificate_is_refused_when_enforced() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, _) = pin_store(dir.path(), PinningMode::Enforce);
+
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    assert!(matches!(
+      pins.verify("192.168.1.20", 8009, b"second"),
+      Err(CastielError::DeviceCertificateChanged(device)) if device == "192.168.1.20:8009"
+    ));
+    let pin = &pins.pins()[0].pin;
+    assert_eq!(pin.fingerprint, fingerprint(b"first"));
+    assert_eq!(
+      pin.mismatch.as_ref().unwrap().fingerprint,
+      fingerprint(b"second")
+    );
+  }
+
+  #[test]
+  fn a_changed_certificate_is_recorded_when_warning() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, _) = pin_store(dir.path(), PinningMode::Warn);
+
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    pins.verify("192.168.1.20", 8009, b"second").unwrap();
+    assert!(pins.pins()[0].pin.mismatch.is_some());
+  }
+
+  #[test]
+  fn nothing_is_pinned_when_off() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, _) = pin_store(dir.path(), PinningMode::Off);
+
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    assert!(pins.pins().is_empty());
+  }
+
+  #[test]
+  fn address_pins_move_to_the_device_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, registry) = pin_store(dir.path(), PinningMode::Enforce);
+
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    registry.record(&[device("192.168.1.20", "abc")]);
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    let pinned = pins.pins();
+    assert_eq!(pinned.len(), 1);
+    assert_eq!(pinned[0].device, "abc");
+    assert_eq!(pinned[0].pin.address, "192.168.1.20:8009");
+
+    // The pin follows the device to its new address
+    registry.record(&[device("192.168.1.21", "abc")]);
+    assert!(pins.verify("192.168.1.21", 8009, b"second").is_err());
+  }
+
+  #[test]
+  fn resetting_forgets_the_pin() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, _) = pin_store(dir.path(), PinningMode::Enforce);
+
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    pins.reset("192.168.1.20:8009").unwrap();
+    assert!(pins.pins().is_empty());
+    assert!(matches!(
+      pins.reset("192.168.1.20:8009"),
+      Err(CastielError::DevicePinNotFound(_))
+    ));
+    pins.verify("192.168.1.20", 8009, b"second").unwrap();
+    assert_eq!(pins.pins()[0].pin.fingerprint, fingerprint(b"second"));
+  }
+
+  #[test]
+  fn pins_are_kept_in_the_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let (pins, _) = pin_store(dir.path(), PinningMode::Enforce);
+    pins.verify("192.168.1.20", 8009, b"first").unwrap();
+    drop(pins);
+
+    let (pins, _) = pin_store(dir.path(), PinningMode::Enforce);
+    assert_eq!(pins.pins()[0].pin.fingerprint, fingerprint(b"first"));
+    assert!(pins.verify("192.168.1.20", 8009, b"second").is_err());
+  }
+
+  #[test]
+  fn fingerprints_are_colon_separated_hex() {
+    let formatted = fingerprint(b"");
+    assert!(formatted.starts_with("E3:B0:C4:42:98:FC:1C:14"));
+    assert_eq!(formatted.len(), 32 * 3 - 1);
+  }
+}
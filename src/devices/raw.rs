@@ -0,0 +1,270 @@
+//! A minimal Cast connection for exchanging arbitrary JSON messages with a device.
+//!
+//! [`rust_cast`] only models part of each Cast protocol message, and its channels can't send
+//! messages it doesn't know about. This connection is used for every device, giving Castiel the
+//! full replies (e.g. stream volume or active tracks in media status), custom request types and a
+//! single place to check device certificates.
+
+use std::{
+  net::{SocketAddr, TcpStream, ToSocketAddrs},
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use rust_cast::{
+  NoCertificateVerification,
+  message_manager::{CastMessage, CastMessagePayload, MessageManager},
+};
+use rustls::{ClientConfig, ClientConnection, StreamOwned, pki_types::ServerName};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+  devices::pinning,
+  errors::{self, CastielError},
+  metrics,
+};
+
+/// Namespace of the virtual connection channel.
+pub const CONNECTION_NAMESPACE: &str = "urn:x-cast:com.google.cast.tp.connection";
+/// Namespace of the receiver (platform) channel.
+pub const RECEIVER_NAMESPACE: &str = "urn:x-cast:com.google.cast.receiver";
+/// Namespace of the media channel.
+pub const MEDIA_NAMESPACE: &str = "urn:x-cast:com.google.cast.media";
+
+/// The destination ID of the device's platform receiver.
+pub const PLATFORM_RECEIVER_ID: &str = "receiver-0";
+
+/// The source ID used for messages sent from Castiel.
+const SENDER_ID: &str = "sender-castiel";
+
+/// How long to wait for the TCP connection to a device.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a device to reply before giving up.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// A TLS connection to a Cast device which sends and receives untyped JSON messages.
+pub struct RawCastConnection {
+  message_manager: MessageManager<TlsStream>,
+}
+
+/// The session of an app launched with [`RawCastConnection::launch_app`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LaunchedApp {
+  pub app_id: String,
+  pub session_id: String,
+  pub transport_id: String,
+}
+
+impl RawCastConnection {
+  /// Opens a connection to the device at `ip`:`port` and connects to its platform receiver.
+  ///
+  /// The device's certificate is checked against the one pinned for it before anything is sent.
+  pub fn connect(ip: &str, port: u16) -> Result<Self, CastielError> {
+    let (server_name, address) = resolve(ip, port)?;
+    let stream = open_tls_stream(server_name, address);
+    metrics::observe_device_connection(metrics::result_label(&stream, errors::is_timeout));
+    let stream = stream.map_err(CastielError::DeviceLookupFailed)?;
+    let certificate = stream
+      .conn
+      .peer_certificates()
+      .and_then(|certificates| certificates.first())
+      .ok_or_else(|| {
+        CastielError::DeviceLookupFailed(rust_cast::errors::Error::Internal(format!(
+          "{ip}:{port} presented no certificate"
+        )))
+      })?;
+    pinning::verify(ip, port, certificate)?;
+    let connection = Self {
+      message_manager: MessageManager::new(stream),
+    };
+    connection.connect_to(PLATFORM_RECEIVER_ID)?;
+
+    Ok(connection)
+  }
+
+  /// Opens a virtual connection to `destination`, e.g. a running app's transport ID.
+  pub fn connect_to(&self, destination: &str) -> Result<(), CastielError> {
+    self
+      .send(
+        CONNECTION_NAMESPACE,
+        destination,
+        serde_json::json!({ "type": "CONNECT", "userAgent": "Castiel" }),
+      )
+      .map_err(CastielError::ConnError)
+  }
+
+  /// Sends `payload` without waiting for a reply.
+  pub fn send(
+    &self,
+    namespace: &str,
+    destination: &str,
+    payload: Value,
+  ) -> Result<(), rust_cast::errors::Error> {
+    self.message_manager.send(CastMessage {
+      namespace: namespace.to_string(),
+      source: SENDER_ID.to_string(),
+      destination: destination.to_string(),
+      payload: CastMessagePayload::String(payload.to_string()),
+    })
+  }
+
+  /// Sends `payload` with a fresh `requestId` and waits for the reply carrying the same ID.
+  ///
+  /// Replies with a `type` ending in `ERROR` or `FAILED`, or equal to `INVALID_REQUEST` or
+  /// `LOAD_CANCELLED`, are turned into errors.
+  pub fn request(
+    &self,
+    namespace: &str,
+    destination: &str,
+    mut payload: Value,
+  ) -> Result<Value, rust_cast::errors::Error> {
+    let operation = message_type(&payload);
+    let request_id = self.message_manager.generate_request_id().get();
+    if let Some(fields) = payload.as_object_mut() {
+      fields.insert("requestId".to_string(), request_id.into());
+    }
+
+    observed(&operation, || {
+      self.send(namespace, destination, payload)?;
+      self.message_manager.receive_find_map(|message| {
+        if message.namespace != namespace {
+          return Ok(None);
+        }
+        let CastMessagePayload::String(ref body) = message.payload else {
+          return Ok(None);
+        };
+        let reply: Value = serde_json::from_str(body)?;
+        if reply["requestId"].as_u64() != Some(u64::from(request_id)) {
+          return Ok(None);
+        }
+
+        let reply_type = reply["type"].as_str().unwrap_or_default();
+        if reply_type.ends_with("ERROR")
+          || reply_type.ends_with("FAILED")
+          || matches!(reply_type, "INVALID_REQUEST" | "LOAD_CANCELLED")
+        {
+          let reason = reply["reason"]
+            .as_str()
+            .or(reply["detailedErrorCode"].as_str())
+            .unwrap_or("Unknown");
+          return Err(rust_cast::errors::Error::Internal(format!(
+            "Device rejected {reply_type} request ({reason})."
+          )));
+        }
+
+        Ok(Some(reply))
+      })
+    })
+  }
+
+  /// Sends `payload` and waits for a message on the same namespace whose `type` is `reply_type`.
+  ///
+  /// Some app namespaces don't echo `requestId`, so their replies can only be told apart by type.
+  pub fn request_by_type(
+    &self,
+    namespace: &str,
+    destination: &str,
+    payload: Value,
+    reply_type: &str,
+  ) -> Result<Value, rust_cast::errors::Error> {
+    observed(&message_type(&payload), || {
+      self.send(namespace, destination, payload)?;
+      self.message_manager.receive_find_map(|message| {
+        if message.namespace != namespace {
+          return Ok(None);
+        }
+        let CastMessagePayload::String(ref body) = message.payload else {
+          return Ok(None);
+        };
+        let reply: Value = serde_json::from_str(body)?;
+
+        Ok((reply["type"].as_str() == Some(reply_type)).then_some(reply))
+      })
+    })
+  }
+
+  /// Launches the app with `app_id`, or finds it if it is already running, and connects to it.
+  pub fn launch_app(&self, app_id: &str) -> Result<LaunchedApp, CastielError> {
+    let reply = self
+      .request(
+        RECEIVER_NAMESPACE,
+        PLATFORM_RECEIVER_ID,
+        serde_json::json!({ "type": "LAUNCH", "appId": app_id }),
+      )
+      .map_err(CastielError::AppError)?;
+    let applications: Vec<LaunchedApp> =
+      serde_json::from_value(reply["status"]["applications"].clone())
+        .map_err(|err| CastielError::AppError(err.into()))?;
+    let app = applications
+      .into_iter()
+      .find(|app| app.app_id == app_id)
+      .ok_or_else(|| {
+        CastielError::AppError(rust_cast::errors::Error::Internal(format!(
+          "App {app_id} was not running after launching it."
+        )))
+      })?;
+
+    self.connect_to(&app.transport_id)?;
+    Ok(app)
+  }
+}
+
+/// Resolves the device address given in a request, which is a 404 if it names no host.
+fn resolve(ip: &str, port: u16) -> Result<(ServerName<'static>, SocketAddr), CastielError> {
+  let not_found = || CastielError::DeviceNotFound(format!("{ip}:{port}"));
+  let server_name = ServerName::try_from(ip.to_string()).map_err(|_| not_found())?;
+  let address = (ip, port)
+    .to_socket_addrs()
+    .map_err(|_| not_found())?
+    .next()
+    .ok_or_else(not_found)?;
+
+  Ok((server_name, address))
+}
+
+/// Opens a TLS stream to a Cast device and completes the handshake. Cast devices use self-signed
+/// certificates, so the certificate is not verified here but checked against its pin afterwards.
+fn open_tls_stream(
+  server_name: ServerName<'static>,
+  address: SocketAddr,
+) -> Result<TlsStream, rust_cast::errors::Error> {
+  let config = ClientConfig::builder()
+    .dangerous()
+    .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+    .with_no_client_auth();
+  let mut connection = ClientConnection::new(Arc::new(config), server_name)?;
+
+  let mut tcp_stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+  tcp_stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+  while connection.is_handshaking() {
+    connection.complete_io(&mut tcp_stream)?;
+  }
+
+  Ok(StreamOwned::new(connection, tcp_stream))
+}
+
+/// The `type` of a Cast message, used to tell operations apart in metrics.
+fn message_type(payload: &Value) -> String {
+  payload["type"].as_str().unwrap_or("UNKNOWN").to_string()
+}
+
+/// Runs `request` and records its result and duration as the Cast operation `operation`.
+fn observed<T>(
+  operation: &str,
+  request: impl FnOnce() -> Result<T, rust_cast::errors::Error>,
+) -> Result<T, rust_cast::errors::Error> {
+  let started = Instant::now();
+  let result = request();
+  metrics::observe_cast_operation(
+    operation,
+    metrics::result_label(&result, errors::is_timeout),
+    started.elapsed(),
+  );
+  result
+}
@@ -0,0 +1,104 @@
+//! Keeps track of the Chromecast devices Castiel has discovered.
+
+use std::{
+  collections::HashMap,
+  sync::{Arc, PoisonError, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  devices::discovery::DiscoveredDevice,
+  errors::CastielError,
+  storage::{Collection, StateStore},
+};
+
+/// The devices found by discovery scans, keyed by their mDNS service name.
+///
+/// Devices stay known after they drop off the network so their absence shows up as an error in
+/// aggregate status reports. They are saved in the state directory, so they are still known after
+/// a restart.
+pub struct DeviceRegistry {
+  devices: RwLock<HashMap<String, DiscoveredDevice>>,
+  store: Arc<StateStore>,
+}
+
+/// The known devices as they are saved, sorted by name.
+#[derive(Default, Serialize, Deserialize)]
+struct KnownDevices(Vec<DiscoveredDevice>);
+
+impl Collection for KnownDevices {
+  const NAME: &'static str = "devices";
+  const VERSION: u32 = 1;
+}
+
+impl DeviceRegistry {
+  /// Loads the devices known when Castiel last ran from `store`.
+  pub fn load(store: Arc<StateStore>) -> Result<Self, CastielError> {
+    let KnownDevices(known) = store.load()?;
+    let devices = known
+      .into_iter()
+      .map(|device| (device.fullname.clone(), device))
+      .collect();
+
+    Ok(Self {
+      devices: RwLock::new(devices),
+      store,
+    })
+  }
+
+  /// Adds newly discovered devices and updates the details of already known ones, returning the
+  /// devices which weren't known before.
+  pub fn record(&self, discovered: &[DiscoveredDevice]) -> Vec<DiscoveredDevice> {
+    let mut devices = self.devices.write().unwrap_or_else(PoisonError::into_inner);
+    let mut changed = false;
+    let mut added = Vec::new();
+    for device in discovered {
+      match devices.insert(device.fullname.clone(), device.clone()) {
+        None => added.push(device.clone()),
+        Some(previous) if previous != *device => changed = true,
+        Some(_) => {}
+      }
+    }
+    drop(devices);
+    let changed = changed || !added.is_empty();
+
+    if changed && let Err(err) = self.store.save(&KnownDevices(self.devices())) {
+      // The devices are still known until Castiel restarts
+      tracing::warn!("Failed to save known devices: {err}");
+    }
+    added
+  }
+
+  /// Returns every known device, sorted by name.
+  pub fn devices(&self) -> Vec<DiscoveredDevice> {
+    let mut devices: Vec<DiscoveredDevice> = self
+      .devices
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .values()
+      .cloned()
+      .collect();
+    devices.sort_by(|a, b| a.fullname.cmp(&b.fullname));
+    devices
+  }
+
+  /// Returns the known device at `ip`:`port`, if any.
+  pub fn find(&self, ip: &str, port: u16) -> Option<DiscoveredDevice> {
+    self
+      .devices
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .values()
+      .find(|device| device.ip_address == ip && device.port == port)
+      .cloned()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self
+      .devices
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .is_empty()
+  }
+}
@@ -0,0 +1,517 @@
+//! Infers the content type and stream type of media when a request leaves them out.
+//!
+//! The URL's file extension is tried first. If that is inconclusive the media URL is probed over
+//! HTTP, first with a `HEAD` request and then by reading the first bytes of the response body.
+//! Streaming manifests are downloaded to tell live streams apart and, for HLS, to find the
+//! segment formats the receiver needs to know about.
+
+use std::time::Duration;
+
+use reqwest::{Client, Response, header};
+use url::Url;
+
+use crate::devices::media::{
+  HlsSegmentFormat, HlsVideoSegmentFormat, ReceiverOptions, StartMediaData, StreamTypeOptions,
+};
+
+/// How long to wait for the media server when probing a URL.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many bytes of the media to read when sniffing its contents.
+const SNIFF_LENGTH: usize = 512;
+
+/// The most of a streaming manifest that will be read to decide whether it is live.
+const MAX_MANIFEST_LENGTH: usize = 256 * 1024;
+
+const HLS_CONTENT_TYPE: &str = "application/x-mpegurl";
+const DASH_CONTENT_TYPE: &str = "application/dash+xml";
+
+/// Content types reported by servers that don't know what they are serving.
+const GENERIC_CONTENT_TYPES: &[&str] = &[
+  "application/octet-stream",
+  "binary/octet-stream",
+  "text/plain",
+];
+
+/// Maps file extensions to the content types the Default Media Receiver expects for them.
+const EXTENSION_CONTENT_TYPES: &[(&str, &str)] = &[
+  ("mp4", "video/mp4"),
+  ("m4v", "video/mp4"),
+  ("webm", "video/webm"),
+  ("mkv", "video/x-matroska"),
+  ("ts", "video/mp2t"),
+  ("m3u8", HLS_CONTENT_TYPE),
+  ("m3u", HLS_CONTENT_TYPE),
+  ("mpd", DASH_CONTENT_TYPE),
+  ("ism", "application/vnd.ms-sstr+xml"),
+  ("mp3", "audio/mpeg"),
+  ("m4a", "audio/mp4"),
+  ("aac", "audio/aac"),
+  ("flac", "audio/flac"),
+  ("ogg", "audio/ogg"),
+  ("oga", "audio/ogg"),
+  ("opus", "audio/ogg"),
+  ("wav", "audio/wav"),
+  ("weba", "audio/webm"),
+  ("apng", "image/apng"),
+  ("bmp", "image/bmp"),
+  ("gif", "image/gif"),
+  ("jpg", "image/jpeg"),
+  ("jpeg", "image/jpeg"),
+  ("png", "image/png"),
+  ("webp", "image/webp"),
+];
+
+/// Fills in `content_type` and `stream_type` on `data` when they were not provided, along with
+/// segment format hints for HLS streams.
+///
+/// Only requests for the Default Media Receiver are affected. Anything that cannot be inferred is
+/// left empty for validation to report.
+pub async fn infer_media_details(mut data: StartMediaData) -> StartMediaData {
+  let needs_content_type = data
+    .content_type
+    .as_deref()
+    .is_none_or(|content_type| content_type.trim().is_empty());
+  let needs_hls_hints = data.hls_segment_format.is_none()
+    && data.hls_video_segment_format.is_none()
+    && data.content_type.as_deref().is_none_or(is_hls);
+  if !matches!(data.receiver, ReceiverOptions::Default)
+    || (!needs_content_type && data.stream_type.is_some() && !needs_hls_hints)
+  {
+    return data;
+  }
+
+  let Ok(url) = Url::parse(data.media_url.trim()) else {
+    return data;
+  };
+  let client = match Client::builder().timeout(PROBE_TIMEOUT).build() {
+    Ok(client) => client,
+    Err(err) => {
+      tracing::warn!("Failed to create HTTP client for media probing: {err}");
+      return data;
+    }
+  };
+
+  if needs_content_type {
+    data.content_type = match content_type_from_extension(&url) {
+      Some(content_type) => Some(content_type.to_string()),
+      None => probe_content_type(&client, &url).await,
+    };
+    tracing::info!(
+      "Inferred content type {:?} for {}",
+      data.content_type,
+      data.media_url
+    );
+  }
+
+  let Some(content_type) = data.content_type.as_deref() else {
+    return data;
+  };
+  if is_hls(content_type) {
+    if data.stream_type.is_none() || needs_hls_hints {
+      infer_hls_details(&client, &url, &mut data).await;
+    }
+  } else if data.stream_type.is_none() {
+    let stream_type = infer_stream_type(&client, &url, content_type).await;
+    tracing::info!(
+      "Inferred stream type {stream_type:?} for {}",
+      data.media_url
+    );
+    data.stream_type = Some(stream_type);
+  }
+
+  data
+}
+
+/// Looks up the content type for the extension of the last segment of `url`'s path.
+fn content_type_from_extension(url: &Url) -> Option<&'static str> {
+  let file_name = url.path_segments()?.next_back()?;
+  let (_, extension) = file_name.rsplit_once('.')?;
+
+  EXTENSION_CONTENT_TYPES
+    .iter()
+    .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+    .map(|(_, content_type)| *content_type)
+}
+
+/// Asks the media server for the content type of `url`, falling back to sniffing the first bytes
+/// of the media when the server's answer is missing or too generic.
+async fn probe_content_type(client: &Client, url: &Url) -> Option<String> {
+  if let Some(content_type) = head_content_type(client, url).await
+    && !GENERIC_CONTENT_TYPES.contains(&content_type.as_str())
+  {
+    return Some(content_type);
+  }
+
+  let prefix = fetch_prefix(client, url, SNIFF_LENGTH).await?;
+  sniff_content_type(&prefix).map(ToString::to_string)
+}
+
+/// Sends a `HEAD` request and returns the lowercased content type without parameters.
+async fn head_content_type(client: &Client, url: &Url) -> Option<String> {
+  let response = client
+    .head(url.clone())
+    .send()
+    .await
+    .inspect_err(|err| tracing::debug!("HEAD request to {url} failed: {err}"))
+    .ok()?;
+  if !response.status().is_success() {
+    return None;
+  }
+
+  response_content_type(&response)
+}
+
+fn response_content_type(response: &Response) -> Option<String> {
+  let content_type = response
+    .headers()
+    .get(header::CONTENT_TYPE)?
+    .to_str()
+    .ok()?;
+  let essence = content_type.split(';').next()?.trim().to_ascii_lowercase();
+  (!essence.is_empty()).then_some(essence)
+}
+
+/// Reads up to `length` bytes from the start of the resource at `url` using a range request.
+///
+/// Servers that ignore the range are handled by stopping once enough bytes have arrived.
+async fn fetch_prefix(client: &Client, url: &Url, length: usize) -> Option<Vec<u8>> {
+  let mut response = client
+    .get(url.clone())
+    .header(header::RANGE, format!("bytes=0-{}", length - 1))
+    .send()
+    .await
+    .inspect_err(|err| tracing::debug!("Range request to {url} failed: {err}"))
+    .ok()?;
+  if !response.status().is_success() {
+    return None;
+  }
+
+  let mut prefix = Vec::with_capacity(length);
+  while prefix.len() < length {
+    match response.chunk().await {
+      Ok(Some(chunk)) => prefix.extend_from_slice(&chunk),
+      Ok(None) => break,
+      Err(err) => {
+        tracing::debug!("Failed to read media from {url}: {err}");
+        break;
+      }
+    }
+  }
+  prefix.truncate(length);
+
+  Some(prefix)
+}
+
+/// Identifies common media formats by their leading bytes.
+fn sniff_content_type(prefix: &[u8]) -> Option<&'static str> {
+  let text = String::from_utf8_lossy(prefix);
+  let text = text.trim_start_matches('\u{feff}').trim_start();
+
+  if text.starts_with("#EXTM3U") {
+    Some(HLS_CONTENT_TYPE)
+  } else if text.contains("<MPD") {
+    Some(DASH_CONTENT_TYPE)
+  } else if prefix.get(4..8) == Some(b"ftyp") {
+    Some("video/mp4")
+  } else if prefix.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+    Some("video/webm")
+  } else if prefix.first() == Some(&0x47) && prefix.get(188) == Some(&0x47) {
+    Some("video/mp2t")
+  } else if prefix.starts_with(b"ID3") || prefix.starts_with(&[0xFF, 0xFB]) {
+    Some("audio/mpeg")
+  } else if prefix.starts_with(b"fLaC") {
+    Some("audio/flac")
+  } else if prefix.starts_with(b"OggS") {
+    Some("audio/ogg")
+  } else if prefix.starts_with(b"RIFF") && prefix.get(8..12) == Some(b"WAVE") {
+    Some("audio/wav")
+  } else if prefix.starts_with(b"RIFF") && prefix.get(8..12) == Some(b"WEBP") {
+    Some("image/webp")
+  } else if prefix.starts_with(b"\x89PNG\r\n\x1a\n") {
+    Some("image/png")
+  } else if prefix.starts_with(&[0xFF, 0xD8, 0xFF]) {
+    Some("image/jpeg")
+  } else if prefix.starts_with(b"GIF8") {
+    Some("image/gif")
+  } else if prefix.starts_with(b"BM") {
+    Some("image/bmp")
+  } else {
+    None
+  }
+}
+
+/// Chooses a stream type for non-HLS media of `content_type` at `url`.
+///
+/// Images are not streamed, DASH manifests are inspected to tell live streams from video on
+/// demand, and everything else is assumed to be a buffered file.
+async fn infer_stream_type(client: &Client, url: &Url, content_type: &str) -> StreamTypeOptions {
+  let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+  if essence.starts_with("image/") {
+    StreamTypeOptions::None
+  } else if essence == DASH_CONTENT_TYPE {
+    match fetch_text(client, url).await {
+      Some(manifest) if manifest.contains(r#"type="dynamic""#) => StreamTypeOptions::Live,
+      _ => StreamTypeOptions::Buffered,
+    }
+  } else {
+    StreamTypeOptions::Buffered
+  }
+}
+
+/// Whether `content_type` is an HLS playlist.
+pub fn is_hls(content_type: &str) -> bool {
+  let essence = content_type.split(';').next().unwrap_or_default().trim();
+  matches!(
+    essence,
+    HLS_CONTENT_TYPE | "application/vnd.apple.mpegurl" | "audio/mpegurl"
+  )
+}
+
+/// Fills in the stream type and segment formats of the HLS stream at `url` from its media
+/// playlist, leaving any that were provided untouched.
+async fn infer_hls_details(client: &Client, url: &Url, data: &mut StartMediaData) {
+  let playlist = hls_media_playlist(client, url).await;
+
+  if data.stream_type.is_none() {
+    let stream_type = match &playlist {
+      Some(playlist) if hls_playlist_is_live(playlist) => StreamTypeOptions::Live,
+      _ => StreamTypeOptions::Buffered,
+    };
+    tracing::info!(
+      "Inferred stream type {stream_type:?} for {}",
+      data.media_url
+    );
+    data.stream_type = Some(stream_type);
+  }
+
+  if let Some(playlist) = playlist
+    && data.hls_segment_format.is_none()
+    && data.hls_video_segment_format.is_none()
+  {
+    (data.hls_segment_format, data.hls_video_segment_format) = hls_segment_formats(&playlist);
+    tracing::info!(
+      "Inferred HLS segment formats {:?}/{:?} for {}",
+      data.hls_segment_format,
+      data.hls_video_segment_format,
+      data.media_url
+    );
+  }
+}
+
+/// Downloads the HLS playlist at `url`. A master playlist only lists variants, so the first
+/// variant's media playlist is returned in its place.
+///
+/// Returns [`None`] if the playlist couldn't be read.
+async fn hls_media_playlist(client: &Client, url: &Url) -> Option<String> {
+  let manifest = fetch_text(client, url).await?;
+  if !manifest.contains("#EXT-X-STREAM-INF") {
+    return Some(manifest);
+  }
+
+  let variant = manifest
+    .lines()
+    .skip_while(|line| !line.starts_with("#EXT-X-STREAM-INF"))
+    .map(str::trim)
+    .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+  let variant_url = url.join(variant).ok()?;
+  fetch_text(client, &variant_url).await
+}
+
+/// HLS media playlists without an end tag are still being appended to. EVENT playlists are live
+/// until the end tag is added once the event has finished.
+fn hls_playlist_is_live(playlist: &str) -> bool {
+  !playlist.lines().any(|line| line.trim() == "#EXT-X-ENDLIST")
+}
+
+/// Works out the segment formats of an HLS media playlist from its first segment.
+///
+/// Playlists with an initialization section (`#EXT-X-MAP`) use fragmented MP4 segments.
+fn hls_segment_formats(
+  playlist: &str,
+) -> (Option<HlsSegmentFormat>, Option<HlsVideoSegmentFormat>) {
+  if playlist.contains("#EXT-X-MAP") {
+    return (
+      Some(HlsSegmentFormat::Fmp4),
+      Some(HlsVideoSegmentFormat::Fmp4),
+    );
+  }
+
+  let Some(segment) = playlist
+    .lines()
+    .map(str::trim)
+    .find(|line| !line.is_empty() && !line.starts_with('#'))
+  else {
+    return (None, None);
+  };
+  let path = segment.split(['?', '#']).next().unwrap_or_default();
+  let extension = path
+    .rsplit_once('.')
+    .map(|(_, extension)| extension.to_ascii_lowercase());
+
+  match extension.as_deref() {
+    Some("ts") => (
+      Some(HlsSegmentFormat::Ts),
+      Some(HlsVideoSegmentFormat::Mpeg2Ts),
+    ),
+    Some("m4s" | "mp4") => (
+      Some(HlsSegmentFormat::Fmp4),
+      Some(HlsVideoSegmentFormat::Fmp4),
+    ),
+    Some("aac") => (Some(HlsSegmentFormat::Aac), None),
+    Some("mp3") => (Some(HlsSegmentFormat::Mp3), None),
+    Some("ac3") => (Some(HlsSegmentFormat::Ac3), None),
+    Some("ec3") => (Some(HlsSegmentFormat::EAc3), None),
+    _ => (None, None),
+  }
+}
+
+async fn fetch_text(client: &Client, url: &Url) -> Option<String> {
+  let prefix = fetch_prefix(client, url, MAX_MANIFEST_LENGTH).await?;
+  Some(String::from_utf8_lossy(&prefix).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn extension_content_type(url: &str) -> Option<&'static str> {
+    content_type_from_extension(&Url::parse(url).unwrap())
+  }
+
+  #[test]
+  fn content_type_from_extension_uses_the_last_path_segment() {
+    assert_eq!(
+      extension_content_type("https://example.com/movie.mp4"),
+      Some("video/mp4")
+    );
+    assert_eq!(
+      extension_content_type("https://example.com/live/index.M3U8?token=abc"),
+      Some(HLS_CONTENT_TYPE)
+    );
+    assert_eq!(
+      extension_content_type("https://example.com/stream.mpd#t=10"),
+      Some(DASH_CONTENT_TYPE)
+    );
+    assert_eq!(
+      extension_content_type("https://example.com/music/song.tar.flac"),
+      Some("audio/flac")
+    );
+  }
+
+  #[test]
+  fn content_type_from_extension_ignores_unknown_or_missing_extensions() {
+    for url in [
+      "https://example.com/",
+      "https://example.com/video",
+      "https://example.com/video.mp4/",
+      "https://example.com/archive.zip",
+      "https://example.com/movie.mp4.part",
+      "https://example.com/stream?format=.mp4",
+    ] {
+      assert_eq!(extension_content_type(url), None, "{url}");
+    }
+  }
+
+  #[test]
+  fn sniff_content_type_recognises_manifests() {
+    assert_eq!(
+      sniff_content_type(b"#EXTM3U\n#EXT-X-VERSION:3\n"),
+      Some(HLS_CONTENT_TYPE)
+    );
+    assert_eq!(
+      sniff_content_type("\u{feff}\n  #EXTM3U\n".as_bytes()),
+      Some(HLS_CONTENT_TYPE)
+    );
+    assert_eq!(
+      sniff_content_type(b"<?xml version=\"1.0\"?>\n<MPD type=\"static\">"),
+      Some(DASH_CONTENT_TYPE)
+    );
+  }
+
+  #[test]
+  fn sniff_content_type_recognises_media_signatures() {
+    let mut transport_stream = vec![0; 376];
+    transport_stream[0] = 0x47;
+    transport_stream[188] = 0x47;
+
+    let cases: &[(&[u8], &str)] = &[
+      (b"\0\0\0\x20ftypisom", "video/mp4"),
+      (&[0x1A, 0x45, 0xDF, 0xA3, 0x01], "video/webm"),
+      (&transport_stream, "video/mp2t"),
+      (b"ID3\x04\0", "audio/mpeg"),
+      (&[0xFF, 0xFB, 0x90], "audio/mpeg"),
+      (b"fLaC\0\0", "audio/flac"),
+      (b"OggS\0\x02", "audio/ogg"),
+      (b"RIFF\0\0\0\0WAVEfmt ", "audio/wav"),
+      (b"RIFF\0\0\0\0WEBPVP8 ", "image/webp"),
+      (b"\x89PNG\r\n\x1a\n\0", "image/png"),
+      (&[0xFF, 0xD8, 0xFF, 0xE0], "image/jpeg"),
+      (b"GIF89a", "image/gif"),
+      (b"BM\0\0", "image/bmp"),
+    ];
+    for (prefix, content_type) in cases {
+      assert_eq!(
+        sniff_content_type(prefix),
+        Some(*content_type),
+        "{prefix:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn sniff_content_type_gives_up_on_unknown_data() {
+    assert_eq!(sniff_content_type(b""), None);
+    assert_eq!(sniff_content_type(b"<html><body>"), None);
+    // A lone sync byte isn't enough to call it a transport stream
+    assert_eq!(sniff_content_type(&[0x47; 100]), None);
+    assert_eq!(sniff_content_type(b"RIFF\0\0\0\0AVI "), None);
+  }
+
+  #[test]
+  fn hls_playlists_are_live_until_they_end() {
+    let live = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6,\nsegment1.ts\n";
+    let event = "#EXTM3U\n#EXT-X-PLAYLIST-TYPE:EVENT\n#EXTINF:6,\nsegment1.ts\n";
+    let finished_event = "#EXTM3U\n#EXT-X-PLAYLIST-TYPE:EVENT\n#EXTINF:6,\nsegment1.ts\n\
+      #EXT-X-ENDLIST\n";
+    let vod = "#EXTM3U\r\n#EXT-X-PLAYLIST-TYPE:VOD\r\n#EXTINF:6,\r\nsegment1.ts\r\n\
+      #EXT-X-ENDLIST\r\n";
+
+    assert!(hls_playlist_is_live(live));
+    assert!(hls_playlist_is_live(event));
+    assert!(!hls_playlist_is_live(finished_event));
+    assert!(!hls_playlist_is_live(vod));
+  }
+
+  #[test]
+  fn hls_segment_formats_come_from_the_first_segment() {
+    let formats = |playlist: &str| {
+      let (audio, video) = hls_segment_formats(playlist);
+      (
+        audio.map(|format| serde_json::to_value(format).unwrap()),
+        video.map(|format| serde_json::to_value(format).unwrap()),
+      )
+    };
+    let json = |format: &str| Some(serde_json::Value::from(format));
+
+    assert_eq!(
+      formats("#EXTM3U\n#EXTINF:6,\nsegment1.ts?token=abc\nsegment2.aac\n"),
+      (json("ts"), json("mpeg2_ts"))
+    );
+    assert_eq!(
+      formats("#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:6,\nsegment1.m4s\n"),
+      (json("fmp4"), json("fmp4"))
+    );
+    assert_eq!(
+      formats("#EXTM3U\n#EXTINF:6,\nsegment1.m4s\n"),
+      (json("fmp4"), json("fmp4"))
+    );
+    assert_eq!(
+      formats("#EXTM3U\n#EXTINF:10,\naudio/chunk1.AAC\n"),
+      (json("aac"), None)
+    );
+    assert_eq!(formats("#EXTM3U\n#EXTINF:10,\nchunk1\n"), (None, None));
+    assert_eq!(formats("#EXTM3U\n"), (None, None));
+  }
+}
@@ -1,15 +1,21 @@
 //! Defines functionality for checking the status of Chromecast devices.
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 
 use crate::{
-  devices::app_ids::{BACKDROP_ID, DEFAULT_MEDIA_ID, WEBVIEW_ID, YOUTUBE_ID},
+  config::ReceiverAppConfig,
+  devices::{
+    AppSelector, DeviceAddress,
+    app_ids::{BACKDROP_ID, DEFAULT_MEDIA_ID, WEBVIEW_ID, YOUTUBE_ID},
+    raw::{MEDIA_NAMESPACE, PLATFORM_RECEIVER_ID, RECEIVER_NAMESPACE, RawCastConnection},
+  },
   errors::CastielError,
 };
 
 /// The status of a chromecast device. Contains information about the current
 /// volume, running app, and state of the device.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceStatus {
   // TODO - Figure out wtf this means
   is_active_input: bool,
@@ -17,31 +23,37 @@ pub struct DeviceStatus {
   in_standby: bool,
   /// The current volume settings of the device.
   volume: VolumeStatus,
-  // TODO - Verify that it's okay to use Option instead of Vec.
-  /// The status of the currently running application on the Chromecast.
+  /// The status of the foreground application on the Chromecast: the first running app which
+  /// isn't the Backdrop, or the Backdrop if nothing else is running.
   /// Can be [`None`] if no application is running, but this is not expected.
   app_status: Option<AppStatus>,
+  /// Every application running on the Chromecast.
+  applications: Vec<AppStatus>,
 }
 
-/// Contains the volume state of a device.
-#[derive(Debug, Serialize)]
+/// Contains the volume state of a device or media stream.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct VolumeStatus {
   /// The current volume of the device from `0.0` to `1.0`.
-  /// [`rust_cast`] can return a [`None`] volume, which defaults to `0.0`.
+  /// Devices can leave the level out, which defaults to `0.0`.
+  #[serde(rename(deserialize = "level"), default)]
   volume: f32,
   /// Whether the device is muted.
-  /// [`rust_cast`] can return [`None`], which defaults to `false`.
+  /// Devices can leave this out, which defaults to `false`.
+  #[serde(default)]
   muted: bool,
 }
 
 /// Contains the status of a running application on the device.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct AppStatus {
   /// The raw `app_id` field reported by the device.
   id: String,
   /// The parsed `app_id`.
   /// This value will be [`ParsedApp:Unknown`] if not recognized.
   app_identity: ParsedApp,
+  /// The name the app is registered under in the settings file, if it is a custom app.
+  registered_name: Option<String>,
   /// The display name of the application.
   display_name: String,
   /// The namespaces used by the application.
@@ -54,41 +66,101 @@ pub struct AppStatus {
   transport_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum ParsedApp {
   Backdrop,
   DefaultMedia,
   YouTube,
   WebView,
+  /// An app registered in the `receiver_apps` setting.
+  Custom,
   Unknown,
 }
 
-impl From<rust_cast::channels::receiver::Status> for DeviceStatus {
-  fn from(status: rust_cast::channels::receiver::Status) -> Self {
-    let app_status = status.applications.into_iter().next().map(AppStatus::from);
+impl DeviceStatus {
+  /// Builds the status from a device's receiver status, recognising apps in `receiver_apps`.
+  fn new(status: ReceiverStatus, receiver_apps: &[ReceiverAppConfig]) -> Self {
+    let applications: Vec<AppStatus> = status
+      .applications
+      .into_iter()
+      .map(|app| AppStatus::new(app, receiver_apps))
+      .collect();
+    let app_status = super::foreground_app(&applications, |app| {
+      (app.id.as_str(), app.session_id.as_str())
+    })
+    .cloned();
 
     Self {
       is_active_input: status.is_active_input,
       in_standby: status.is_stand_by,
-      // TODO - Make sure I can get away with this default unwrap strategy
-      volume: VolumeStatus {
-        volume: status.volume.level.unwrap_or(0.0),
-        muted: status.volume.muted.unwrap_or(false),
-      },
+      volume: status.volume,
       app_status,
+      applications,
     }
   }
+
+  /// The device volume.
+  pub fn volume(&self) -> &VolumeStatus {
+    &self.volume
+  }
+
+  /// The foreground app, if any app is running.
+  pub fn app_status(&self) -> Option<&AppStatus> {
+    self.app_status.as_ref()
+  }
+
+  /// Every application running on the device.
+  pub fn applications(&self) -> &[AppStatus] {
+    &self.applications
+  }
+
+  /// Builds the status from the `status` object of a receiver status reply.
+  #[cfg(test)]
+  pub fn from_reply(status: serde_json::Value) -> Self {
+    Self::new(
+      serde_json::from_value(status).expect("a valid receiver status"),
+      &[],
+    )
+  }
 }
 
-impl From<rust_cast::channels::receiver::Application> for AppStatus {
-  fn from(app: rust_cast::channels::receiver::Application) -> Self {
-    let app_identity = ParsedApp::from(app.app_id.as_str());
+impl VolumeStatus {
+  /// The volume from `0.0` to `1.0`.
+  pub fn level(&self) -> f32 {
+    self.volume
+  }
+
+  pub fn muted(&self) -> bool {
+    self.muted
+  }
+}
+
+impl AppStatus {
+  /// The raw `app_id` reported by the device.
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn display_name(&self) -> &str {
+    &self.display_name
+  }
+
+  fn new(app: RunningApp, receiver_apps: &[ReceiverAppConfig]) -> Self {
+    let registered_name = receiver_apps
+      .iter()
+      .find(|registered| registered.id == app.app_id)
+      .map(|registered| registered.name.clone());
+    let app_identity = match ParsedApp::from(app.app_id.as_str()) {
+      ParsedApp::Unknown if registered_name.is_some() => ParsedApp::Custom,
+      parsed => parsed,
+    };
 
     Self {
       id: app.app_id,
       app_identity,
+      registered_name,
       display_name: app.display_name,
-      namespaces: app.namespaces,
+      namespaces: app.namespaces.into_iter().map(|ns| ns.name).collect(),
       session_id: app.session_id,
       status: app.status_text,
       transport_id: app.transport_id,
@@ -108,83 +180,492 @@ impl From<&str> for ParsedApp {
   }
 }
 
-pub fn get_device_status(ip: &str, port: u16) -> Result<DeviceStatus, CastielError> {
+pub fn get_device_status(
+  ip: &str,
+  port: u16,
+  receiver_apps: &[ReceiverAppConfig],
+) -> Result<DeviceStatus, CastielError> {
   tracing::info!("Getting device status for {ip}:{port}");
-  let cast_device = super::get_cast_device(ip, port)?;
+  let connection = RawCastConnection::connect(ip, port)?;
 
-  let device_status = cast_device
-    .receiver
-    .get_status()
-    .map_err(CastielError::ConnError)?;
-  Ok(DeviceStatus::from(device_status))
+  let receiver_status = request_receiver_status(&connection)?;
+  Ok(DeviceStatus::new(receiver_status, receiver_apps))
 }
 
-#[derive(Debug, Serialize)]
+/// The status of the media sessions in the app running on a device.
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct MediaStatus {
+  /// The first media session, flattened into the top level for convenience.
+  #[serde(flatten)]
+  current: MediaSessionStatus,
+  /// Every media session reported by the app.
+  entries: Vec<MediaSessionStatus>,
+}
+
+/// The status of a single media session, as reported by the media channel.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct MediaSessionStatus {
+  /// Identifies this playback, for use in media commands.
+  media_session_id: Option<i32>,
+  /// The media being played, if the app reported it.
+  media: Option<MediaInfo>,
+  /// The playback position in seconds.
   current_time: Option<f32>,
+  /// The rate at which playback is progressing, where `1.0` is regular speed.
+  #[serde(default)]
   playback_rate: f32,
+  #[serde(default)]
   player_state: PlayerState,
+  /// Why the player became idle, if it is idle and the reason is known.
+  idle_reason: Option<IdleReason>,
+  /// The raw bit flags of media commands the player supports.
+  #[serde(default)]
+  supported_media_commands: u32,
+  /// The names of the media commands the player supports, decoded from the bit flags.
+  #[serde(skip_deserializing)]
+  supported_commands: Vec<&'static str>,
+  /// The volume of the media stream, as opposed to the device volume.
+  volume: Option<VolumeStatus>,
+  /// The IDs of the tracks in [`MediaInfo::tracks`] which are enabled.
+  #[serde(default)]
+  active_track_ids: Vec<u32>,
+  /// The ID of the queue item being played.
+  current_item_id: Option<u32>,
+}
+
+/// Describes the media loaded into a media session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct MediaInfo {
+  /// The identifier of the content, usually its URL.
+  #[serde(default)]
+  content_id: String,
+  /// The URL of the content, if it differs from `content_id`.
+  content_url: Option<String>,
+  content_type: Option<String>,
+  /// One of `BUFFERED`, `LIVE` or `NONE`.
+  stream_type: Option<String>,
+  /// The duration of the media in seconds, if known.
+  duration: Option<f64>,
+  /// App-specific metadata such as a title or images.
+  metadata: Option<serde_json::Value>,
+  /// The text, audio and video tracks available in the media.
+  #[serde(default)]
+  tracks: Vec<TrackInfo>,
 }
 
-#[derive(Debug, Serialize)]
+/// Describes a single text, audio or video track.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct TrackInfo {
+  track_id: u32,
+  /// One of `TEXT`, `AUDIO` or `VIDEO`.
+  #[serde(rename(deserialize = "type"))]
+  kind: String,
+  track_content_id: Option<String>,
+  track_content_type: Option<String>,
+  name: Option<String>,
+  language: Option<String>,
+  /// For text tracks, one of `SUBTITLES`, `CAPTIONS`, `DESCRIPTIONS`, `CHAPTERS` or `METADATA`.
+  subtype: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "SCREAMING_SNAKE_CASE"))]
 pub enum PlayerState {
+  #[default]
   Idle,
   Playing,
   Buffering,
   Paused,
 }
 
-impl Default for MediaStatus {
-  fn default() -> Self {
+/// The reason a player became idle.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "SCREAMING_SNAKE_CASE"))]
+pub enum IdleReason {
+  /// A sender stopped playback.
+  Cancelled,
+  /// A sender loaded different media.
+  Interrupted,
+  /// Playback reached the end of the media.
+  Finished,
+  /// Playback failed, e.g. because the media couldn't be downloaded.
+  Error,
+}
+
+/// Names of the bit flags in `supportedMediaCommands`.
+///
+/// See <https://developers.google.com/cast/docs/reference/web_receiver/cast.framework.messages#.Command>.
+const MEDIA_COMMANDS: &[(u32, &str)] = &[
+  (1, "pause"),
+  (1 << 1, "seek"),
+  (1 << 2, "stream_volume"),
+  (1 << 3, "stream_mute"),
+  (1 << 4, "skip_forward"),
+  (1 << 5, "skip_backward"),
+  (1 << 6, "queue_next"),
+  (1 << 7, "queue_prev"),
+  (1 << 8, "queue_shuffle"),
+  (1 << 9, "skip_ad"),
+  (1 << 10, "queue_repeat_all"),
+  (1 << 11, "queue_repeat_one"),
+  (1 << 12, "edit_tracks"),
+  (1 << 13, "playback_rate"),
+  (1 << 14, "like"),
+  (1 << 15, "dislike"),
+  (1 << 16, "follow"),
+  (1 << 17, "unfollow"),
+  (1 << 18, "stream_transfer"),
+];
+
+impl MediaStatus {
+  /// The state of the player in the first media session.
+  pub fn player_state(&self) -> PlayerState {
+    self.current.player_state
+  }
+
+  /// Why the player in the first media session is idle, if it is and the reason is known.
+  pub fn idle_reason(&self) -> Option<IdleReason> {
+    self.current.idle_reason
+  }
+
+  /// The content ID of the media in the first media session, if any is loaded.
+  pub fn content_id(&self) -> Option<&str> {
+    self
+      .current
+      .media
+      .as_ref()
+      .map(|media| media.content_id.as_str())
+  }
+
+  /// The title of the media in the first media session, if the app reported one.
+  pub fn title(&self) -> Option<&str> {
+    self
+      .current
+      .media
+      .as_ref()?
+      .metadata
+      .as_ref()?
+      .get("title")?
+      .as_str()
+  }
+
+  /// The IDs of every track in the current media.
+  pub fn track_ids(&self) -> Vec<u32> {
+    self
+      .current
+      .media
+      .iter()
+      .flat_map(|media| &media.tracks)
+      .map(|track| track.track_id)
+      .collect()
+  }
+
+  /// Builds the status from the `status` array of a media status reply.
+  #[cfg(test)]
+  pub fn from_reply(status: serde_json::Value) -> Self {
+    Self::new(serde_json::from_value(status).expect("a valid media status"))
+  }
+
+  fn new(mut entries: Vec<MediaSessionStatus>) -> Self {
+    for entry in &mut entries {
+      entry.supported_commands = MEDIA_COMMANDS
+        .iter()
+        .filter(|(flag, _)| entry.supported_media_commands & flag != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    }
+
     Self {
-      current_time: Option::default(),
-      playback_rate: 0.0,
-      player_state: PlayerState::Idle,
+      current: entries.first().cloned().unwrap_or_default(),
+      entries,
     }
   }
 }
 
-impl From<rust_cast::channels::media::Status> for MediaStatus {
-  fn from(status: rust_cast::channels::media::Status) -> Self {
-    status
-      .entries
-      .first()
-      .map_or_else(Self::default, |entry| Self {
-        current_time: entry.current_time,
-        playback_rate: entry.playback_rate,
-        player_state: entry.player_state.into(),
-      })
+/// The receiver status reported by a device, as sent over the receiver channel.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReceiverStatus {
+  #[serde(default)]
+  applications: Vec<RunningApp>,
+  #[serde(default)]
+  is_active_input: bool,
+  #[serde(default)]
+  is_stand_by: bool,
+  #[serde(default)]
+  volume: VolumeStatus,
+}
+
+/// A running application as reported in the receiver status.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunningApp {
+  app_id: String,
+  #[serde(default)]
+  display_name: String,
+  #[serde(default)]
+  namespaces: Vec<AppNamespace>,
+  session_id: String,
+  #[serde(default)]
+  status_text: String,
+  transport_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AppNamespace {
+  name: String,
+}
+
+impl RunningApp {
+  /// Whether the app listens on the media channel, and so can answer media status requests.
+  fn supports_media(&self) -> bool {
+    self.namespaces.iter().any(|ns| ns.name == MEDIA_NAMESPACE)
   }
 }
 
-impl From<rust_cast::channels::media::PlayerState> for PlayerState {
-  fn from(state: rust_cast::channels::media::PlayerState) -> Self {
-    match state {
-      rust_cast::channels::media::PlayerState::Idle => Self::Idle,
-      rust_cast::channels::media::PlayerState::Playing => Self::Playing,
-      rust_cast::channels::media::PlayerState::Buffering => Self::Buffering,
-      rust_cast::channels::media::PlayerState::Paused => Self::Paused,
+/// Gets the media status of the app selected by `app_selector`.
+///
+/// Apps which don't use the media channel report an empty status.
+pub fn get_media_status(
+  ip: &str,
+  port: u16,
+  app_selector: &AppSelector,
+) -> Result<MediaStatus, CastielError> {
+  tracing::info!("Getting media status for {ip}:{port}");
+  let connection = RawCastConnection::connect(ip, port)?;
+
+  let receiver_status = request_receiver_status(&connection)?;
+  let app = app_selector.select(&receiver_status.applications, |app| {
+    (app.app_id.as_str(), app.session_id.as_str())
+  })?;
+
+  Ok(request_media_status(&connection, app)?.unwrap_or_default())
+}
+
+/// A connection to the media channel of a running app with media loaded.
+pub struct MediaSession {
+  connection: RawCastConnection,
+  transport_id: String,
+  media_session_id: i32,
+  status: MediaStatus,
+}
+
+impl MediaSession {
+  /// The media status at the time the session was opened.
+  pub fn status(&self) -> &MediaStatus {
+    &self.status
+  }
+
+  /// Sends a media command to the session and returns the updated media status.
+  ///
+  /// The `mediaSessionId` of the session is added to `payload`.
+  pub fn command(&self, mut payload: Value) -> Result<MediaStatus, CastielError> {
+    if let Some(fields) = payload.as_object_mut() {
+      fields.insert("mediaSessionId".to_string(), self.media_session_id.into());
     }
+    let reply = self
+      .connection
+      .request(MEDIA_NAMESPACE, &self.transport_id, payload)
+      .map_err(CastielError::MediaCommandFailed)?;
+    let entries = serde_json::from_value(reply["status"].clone())
+      .map_err(|err| CastielError::MediaCommandFailed(err.into()))?;
+
+    Ok(MediaStatus::new(entries))
   }
 }
 
-pub fn get_media_status(ip: &str, port: u16) -> Result<MediaStatus, CastielError> {
-  tracing::info!("Getting media status for {ip}:{port}");
-  let cast_device = super::get_cast_device(ip, port)?;
+/// Connects to the media session of the app selected by `app_selector`.
+///
+/// Fails with [`CastielError::NoMediaSession`] if the app has no media loaded.
+pub fn open_media_session(
+  device_addr: &DeviceAddress,
+  app_selector: &AppSelector,
+) -> Result<MediaSession, CastielError> {
+  let connection = RawCastConnection::connect(&device_addr.ip, device_addr.port)?;
+
+  let receiver_status = request_receiver_status(&connection)?;
+  let app = app_selector.select(&receiver_status.applications, |app| {
+    (app.app_id.as_str(), app.session_id.as_str())
+  })?;
+  let status = request_media_status(&connection, app)?.ok_or(CastielError::NoMediaSession)?;
+  let media_session_id = status
+    .current
+    .media_session_id
+    .ok_or(CastielError::NoMediaSession)?;
+
+  Ok(MediaSession {
+    transport_id: app.transport_id.clone(),
+    connection,
+    media_session_id,
+    status,
+  })
+}
+
+/// Gets the device status and the foreground app's media status over a single connection.
+///
+/// The media status is [`None`] if no app is running or the foreground app doesn't use the media
+/// channel.
+pub fn get_full_status(
+  ip: &str,
+  port: u16,
+  receiver_apps: &[ReceiverAppConfig],
+) -> Result<(DeviceStatus, Option<MediaStatus>), CastielError> {
+  tracing::debug!("Getting full status for {ip}:{port}");
+  let connection = RawCastConnection::connect(ip, port)?;
 
-  let device_status = cast_device
-    .receiver
-    .get_status()
+  let receiver_status = request_receiver_status(&connection)?;
+  let media_status = match super::foreground_app(&receiver_status.applications, |app| {
+    (app.app_id.as_str(), app.session_id.as_str())
+  }) {
+    Some(app) => request_media_status(&connection, app)?,
+    None => None,
+  };
+
+  Ok((
+    DeviceStatus::new(receiver_status, receiver_apps),
+    media_status,
+  ))
+}
+
+fn request_receiver_status(connection: &RawCastConnection) -> Result<ReceiverStatus, CastielError> {
+  let reply = connection
+    .request(
+      RECEIVER_NAMESPACE,
+      PLATFORM_RECEIVER_ID,
+      json!({ "type": "GET_STATUS" }),
+    )
     .map_err(CastielError::ConnError)?;
-  let app = device_status
-    .applications
-    .first()
-    .ok_or(CastielError::AppLookupFailed)?;
-
-  let media_status = cast_device
-    .media
-    .get_status(&app.transport_id, None)
+  serde_json::from_value(reply["status"].clone()).map_err(|err| CastielError::ConnError(err.into()))
+}
+
+/// Requests the media status of `app`, or returns [`None`] if it doesn't use the media channel.
+fn request_media_status(
+  connection: &RawCastConnection,
+  app: &RunningApp,
+) -> Result<Option<MediaStatus>, CastielError> {
+  if !app.supports_media() {
+    return Ok(None);
+  }
+
+  connection.connect_to(&app.transport_id)?;
+  let reply = connection
+    .request(
+      MEDIA_NAMESPACE,
+      &app.transport_id,
+      json!({ "type": "GET_STATUS" }),
+    )
     .map_err(CastielError::ConnError)?;
+  let entries = serde_json::from_value(reply["status"].clone())
+    .map_err(|err| CastielError::ConnError(err.into()))?;
+
+  Ok(Some(MediaStatus::new(entries)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn app(app_id: &str, session_id: &str, namespaces: &[&str]) -> Value {
+    json!({
+      "appId": app_id,
+      "displayName": app_id,
+      "namespaces": namespaces.iter().map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+      "sessionId": session_id,
+      "transportId": format!("transport-{session_id}"),
+    })
+  }
 
-  Ok(MediaStatus::from(media_status))
+  #[test]
+  fn device_status_fields_are_optional() {
+    let status = DeviceStatus::from_reply(json!({}));
+    assert_eq!(status.volume().level(), 0.0);
+    assert!(!status.volume().muted());
+    assert!(status.app_status().is_none());
+    assert!(status.applications().is_empty());
+    assert!(!status.in_standby);
+  }
+
+  #[test]
+  fn device_status_names_the_foreground_app() {
+    let status = DeviceStatus::from_reply(json!({
+      "applications": [
+        app(BACKDROP_ID, "backdrop", &[]),
+        app(DEFAULT_MEDIA_ID, "media", &[MEDIA_NAMESPACE]),
+      ],
+      "isStandBy": true,
+      "volume": { "level": 0.5, "muted": true },
+    }));
+    assert_eq!(status.volume().level(), 0.5);
+    assert!(status.volume().muted());
+    assert!(status.in_standby);
+    assert_eq!(status.applications().len(), 2);
+    let foreground = status.app_status().unwrap();
+    assert_eq!(foreground.id(), DEFAULT_MEDIA_ID);
+    assert!(matches!(foreground.app_identity, ParsedApp::DefaultMedia));
+    assert_eq!(foreground.namespaces, [MEDIA_NAMESPACE]);
+  }
+
+  #[test]
+  fn registered_apps_are_recognised() {
+    let receiver_apps = [ReceiverAppConfig {
+      id: "ABCD1234".to_string(),
+      name: "Dashboard".to_string(),
+      namespace: None,
+      message_type: None,
+      required_fields: Vec::new(),
+    }];
+    let receiver_status =
+      serde_json::from_value(json!({ "applications": [app("ABCD1234", "1", &[])] })).unwrap();
+    let status = DeviceStatus::new(receiver_status, &receiver_apps);
+    let app = status.app_status().unwrap();
+    assert!(matches!(app.app_identity, ParsedApp::Custom));
+    assert_eq!(app.registered_name.as_deref(), Some("Dashboard"));
+  }
+
+  #[test]
+  fn media_status_fields_are_optional() {
+    let status = MediaStatus::from_reply(json!([{}]));
+    assert_eq!(status.player_state(), PlayerState::Idle);
+    assert!(status.idle_reason().is_none());
+    assert!(status.content_id().is_none());
+    assert!(status.track_ids().is_empty());
+    assert!(status.current.active_track_ids.is_empty());
+
+    let empty = MediaStatus::from_reply(json!([]));
+    assert!(empty.entries.is_empty());
+    assert_eq!(empty.player_state(), PlayerState::Idle);
+  }
+
+  #[test]
+  fn media_status_is_parsed() {
+    let status = MediaStatus::from_reply(json!([
+      {
+        "mediaSessionId": 3,
+        "playerState": "IDLE",
+        "idleReason": "FINISHED",
+        "activeTrackIds": [1, 3],
+        "supportedMediaCommands": 3,
+        "media": {
+          "contentId": "http://example.com/a.mp4",
+          "metadata": { "title": "A" },
+          "tracks": [
+            { "trackId": 1, "type": "TEXT" },
+            { "trackId": 3, "type": "AUDIO" },
+          ],
+        },
+      },
+      { "mediaSessionId": 4, "playerState": "PLAYING" },
+    ]));
+    assert_eq!(status.player_state(), PlayerState::Idle);
+    assert!(matches!(status.idle_reason(), Some(IdleReason::Finished)));
+    assert_eq!(status.content_id(), Some("http://example.com/a.mp4"));
+    assert_eq!(status.title(), Some("A"));
+    assert_eq!(status.track_ids(), [1, 3]);
+    assert_eq!(status.current.active_track_ids, [1, 3]);
+    assert_eq!(status.current.supported_commands, ["pause", "seek"]);
+    assert_eq!(status.entries.len(), 2);
+  }
 }
@@ -0,0 +1,289 @@
+//! Defines text tracks (subtitles and captions), their styling, and switching between the text
+//! and audio tracks of a running media session.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::{
+  devices::{
+    AppRequest,
+    status::{self, MediaStatus},
+    validation::FieldError,
+  },
+  errors::CastielError,
+};
+
+/// The only text track format Castiel sends to devices.
+pub const WEBVTT_CONTENT_TYPE: &str = "text/vtt";
+
+/// A text track to load alongside media on the Default Media Receiver.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TextTrackData {
+  /// The URL of a WebVTT file.
+  pub url: String,
+  /// The BCP 47 language tag of the track, e.g. `en-US`.
+  pub language: Option<String>,
+  /// The name shown for the track in the receiver's track picker.
+  pub label: Option<String>,
+  #[serde(default)]
+  pub kind: TextTrackKind,
+  /// Whether the track is shown as soon as playback starts.
+  #[serde(default)]
+  pub enabled: bool,
+}
+
+/// What a text track contains.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "SCREAMING_SNAKE_CASE"))]
+pub enum TextTrackKind {
+  #[default]
+  Subtitles,
+  Captions,
+  Descriptions,
+  Chapters,
+  Metadata,
+}
+
+/// How text tracks are drawn by the receiver. Fields left out keep the receiver's default.
+///
+/// Colors are given as `#RRGGBBAA` hex strings.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct TextTrackStyle {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub foreground_color: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub background_color: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub edge_type: Option<TextEdgeType>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub edge_color: Option<String>,
+  /// The window is the box drawn behind the whole caption area.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub window_type: Option<TextWindowType>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub window_color: Option<String>,
+  /// The text size relative to the receiver's default, where `1.0` is the default size.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub font_scale: Option<f32>,
+  /// A specific font name, e.g. `Droid Sans`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub font_family: Option<String>,
+  /// The kind of font to fall back to if `font_family` is unavailable.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub font_generic_family: Option<TextFontFamily>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub font_style: Option<TextFontStyle>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "SCREAMING_SNAKE_CASE"))]
+pub enum TextEdgeType {
+  None,
+  Outline,
+  DropShadow,
+  Raised,
+  Depressed,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "SCREAMING_SNAKE_CASE"))]
+pub enum TextWindowType {
+  None,
+  Normal,
+  RoundedCorners,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "SCREAMING_SNAKE_CASE"))]
+pub enum TextFontFamily {
+  SansSerif,
+  MonospacedSansSerif,
+  Serif,
+  MonospacedSerif,
+  Casual,
+  Cursive,
+  SmallCapitals,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "SCREAMING_SNAKE_CASE"))]
+pub enum TextFontStyle {
+  Normal,
+  Bold,
+  BoldItalic,
+  Italic,
+}
+
+/// Builds the Cast `tracks` array for `text_tracks` and the IDs of the tracks to enable.
+///
+/// Track IDs are assigned from `1` in the order the tracks were given.
+pub fn cast_text_tracks(text_tracks: &[TextTrackData]) -> (Vec<Value>, Vec<u32>) {
+  let mut active_track_ids = Vec::new();
+  let tracks = (1..)
+    .zip(text_tracks)
+    .map(|(track_id, track)| {
+      if track.enabled {
+        active_track_ids.push(track_id);
+      }
+      json!({
+        "trackId": track_id,
+        "type": "TEXT",
+        "subtype": track.kind,
+        "trackContentId": track.url,
+        "trackContentType": WEBVTT_CONTENT_TYPE,
+        "name": track.label,
+        "language": track.language,
+      })
+    })
+    .collect();
+
+  (tracks, active_track_ids)
+}
+
+/// A request to choose which text and audio tracks of the current media are enabled.
+#[derive(Debug, Deserialize)]
+pub struct ActiveTracksRequest {
+  #[serde(flatten)]
+  pub target: AppRequest,
+  /// The IDs of the tracks to enable, as listed in the media status. Tracks not listed are
+  /// disabled, so an empty list turns subtitles off.
+  pub active_track_ids: Vec<u32>,
+}
+
+/// A request to change how text tracks are drawn for the current media.
+#[derive(Debug, Deserialize)]
+pub struct TextTrackStyleRequest {
+  #[serde(flatten)]
+  pub target: AppRequest,
+  pub style: TextTrackStyle,
+}
+
+/// Enables exactly the tracks in `request.active_track_ids` on the targeted app's media session.
+pub fn set_active_tracks(request: &ActiveTracksRequest) -> Result<MediaStatus, CastielError> {
+  let device_addr = &request.target.device;
+  tracing::info!(
+    "Setting active tracks {:?} on {}:{}",
+    request.active_track_ids,
+    device_addr.ip,
+    device_addr.port
+  );
+  let session = status::open_media_session(device_addr, &request.target.app)?;
+
+  let track_ids = session.status().track_ids();
+  let unknown: Vec<String> = request
+    .active_track_ids
+    .iter()
+    .filter(|track_id| !track_ids.contains(track_id))
+    .map(ToString::to_string)
+    .collect();
+  if !unknown.is_empty() {
+    return Err(CastielError::ValidationFailed(vec![FieldError::new(
+      "active_track_ids",
+      format!(
+        "the current media has no tracks with IDs {}",
+        unknown.join(", ")
+      ),
+    )]));
+  }
+
+  session.command(json!({
+    "type": "EDIT_TRACKS_INFO",
+    "activeTrackIds": request.active_track_ids,
+  }))
+}
+
+/// Applies `request.style` to the text tracks of the targeted app's media session.
+pub fn set_text_track_style(request: &TextTrackStyleRequest) -> Result<MediaStatus, CastielError> {
+  request.style.validate()?;
+
+  let device_addr = &request.target.device;
+  tracing::info!(
+    "Setting text track style on {}:{}",
+    device_addr.ip,
+    device_addr.port
+  );
+  let session = status::open_media_session(device_addr, &request.target.app)?;
+
+  session.command(json!({
+    "type": "EDIT_TRACKS_INFO",
+    "textTrackStyle": request.style,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn track(url: &str, kind: TextTrackKind, enabled: bool) -> TextTrackData {
+    TextTrackData {
+      url: url.to_string(),
+      language: Some("en-US".to_string()),
+      label: Some("English".to_string()),
+      kind,
+      enabled,
+    }
+  }
+
+  #[test]
+  fn text_tracks_are_numbered_from_one() {
+    let (tracks, active_track_ids) = cast_text_tracks(&[
+      track("http://example.com/en.vtt", TextTrackKind::Subtitles, false),
+      track("http://example.com/cc.vtt", TextTrackKind::Captions, true),
+      track("http://example.com/ch.vtt", TextTrackKind::Chapters, true),
+    ]);
+
+    assert_eq!(active_track_ids, [2, 3]);
+    assert_eq!(
+      tracks[0],
+      json!({
+        "trackId": 1,
+        "type": "TEXT",
+        "subtype": "SUBTITLES",
+        "trackContentId": "http://example.com/en.vtt",
+        "trackContentType": "text/vtt",
+        "name": "English",
+        "language": "en-US",
+      })
+    );
+    assert_eq!(tracks[1]["trackId"], 2);
+    assert_eq!(tracks[1]["subtype"], "CAPTIONS");
+    assert_eq!(tracks[2]["subtype"], "CHAPTERS");
+  }
+
+  #[test]
+  fn no_text_tracks_are_enabled_by_default() {
+    let (tracks, active_track_ids) = cast_text_tracks(&[]);
+    assert!(tracks.is_empty());
+    assert!(active_track_ids.is_empty());
+  }
+
+  #[test]
+  fn text_track_styles_use_cast_names() {
+    let style = TextTrackStyle {
+      foreground_color: Some("#FFFFFFFF".to_string()),
+      edge_type: Some(TextEdgeType::DropShadow),
+      window_type: Some(TextWindowType::RoundedCorners),
+      font_scale: Some(1.5),
+      font_generic_family: Some(TextFontFamily::MonospacedSansSerif),
+      font_style: Some(TextFontStyle::BoldItalic),
+      ..TextTrackStyle::default()
+    };
+
+    assert_eq!(
+      serde_json::to_value(&style).unwrap(),
+      json!({
+        "foregroundColor": "#FFFFFFFF",
+        "edgeType": "DROP_SHADOW",
+        "windowType": "ROUNDED_CORNERS",
+        "fontScale": 1.5,
+        "fontGenericFamily": "MONOSPACED_SANS_SERIF",
+        "fontStyle": "BOLD_ITALIC",
+      })
+    );
+    assert_eq!(
+      serde_json::to_value(TextTrackStyle::default()).unwrap(),
+      json!({})
+    );
+  }
+}
@@ -0,0 +1,592 @@
+//! Validation and normalisation of media requests before they are sent to a device.
+
+use serde::Serialize;
+use url::Url;
+
+use crate::{
+  devices::{
+    media::{ReceiverOptions, StartMediaData, StreamTypeOptions},
+    sniffing,
+    tracks::{TextTrackData, TextTrackStyle},
+    youtube::YouTubeQueueData,
+  },
+  errors::CastielError,
+};
+
+/// MIME types the Default Media Receiver is known to play.
+///
+/// See <https://developers.google.com/cast/docs/media> for the formats supported by Cast devices.
+const SUPPORTED_CONTENT_TYPES: &[&str] = &[
+  // Video
+  "video/mp4",
+  "video/webm",
+  "video/mp2t",
+  "video/x-matroska",
+  // Streaming manifests
+  "application/x-mpegurl",
+  "application/vnd.apple.mpegurl",
+  "application/dash+xml",
+  "application/vnd.ms-sstr+xml",
+  // Audio
+  "audio/mpeg",
+  "audio/mp3",
+  "audio/mp4",
+  "audio/aac",
+  "audio/flac",
+  "audio/ogg",
+  "audio/wav",
+  "audio/webm",
+  // Images
+  "image/apng",
+  "image/bmp",
+  "image/gif",
+  "image/jpeg",
+  "image/png",
+  "image/webp",
+];
+
+/// Hosts from which YouTube video IDs can be extracted.
+const YOUTUBE_HOSTS: &[&str] = &[
+  "youtube.com",
+  "www.youtube.com",
+  "m.youtube.com",
+  "music.youtube.com",
+  "youtube-nocookie.com",
+  "www.youtube-nocookie.com",
+];
+
+/// The largest text size, relative to the default size, that can be requested for text tracks.
+const MAX_FONT_SCALE: f32 = 4.0;
+
+/// A problem with a single field of a request.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+  /// The name of the offending field.
+  pub field: &'static str,
+  /// A description of what is wrong with it.
+  pub message: String,
+}
+
+impl FieldError {
+  pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+    Self {
+      field,
+      message: message.into(),
+    }
+  }
+}
+
+impl StartMediaData {
+  /// Checks that the request makes sense for its receiver and returns it in normalised form.
+  ///
+  /// YouTube URLs are reduced to their video ID and content types are lowercased. Every problem
+  /// found is reported at once through [`CastielError::ValidationFailed`].
+  pub fn validate(mut self) -> Result<Self, CastielError> {
+    let mut errors = Vec::new();
+
+    self.media_url = self.media_url.trim().to_string();
+    self.content_type = self
+      .content_type
+      .map(|content_type| content_type.trim().to_ascii_lowercase())
+      .filter(|content_type| !content_type.is_empty());
+
+    match self.receiver {
+      ReceiverOptions::Default => {
+        if let Err(err) = check_http_url("media_url", &self.media_url) {
+          errors.push(err);
+        }
+        validate_text_tracks(&mut self.text_tracks, &mut errors);
+        validate_content_type(self.content_type.as_deref(), self.stream_type, &mut errors);
+        if (self.hls_segment_format.is_some() || self.hls_video_segment_format.is_some())
+          && self
+            .content_type
+            .as_deref()
+            .is_some_and(|content_type| !sniffing::is_hls(content_type))
+        {
+          errors.push(FieldError::new(
+            "hls_segment_format",
+            "segment formats only apply to HLS streams",
+          ));
+        }
+      }
+      ReceiverOptions::YouTube => {
+        // Fill in the playlist and start time from the URL before it is reduced to the video ID
+        let (playlist_id, start_time) = youtube_url_params(&self.media_url);
+        self.playlist_id = self.playlist_id.take().or(playlist_id);
+        self.start_time = self.start_time.or(start_time);
+
+        match youtube_video_id(&self.media_url) {
+          Some(video_id) => self.media_url = video_id,
+          None => errors.push(FieldError::new(
+            "media_url",
+            "expected a YouTube video ID or a YouTube video URL",
+          )),
+        }
+        if let Some(playlist_id) = &self.playlist_id
+          && !is_youtube_playlist_id(playlist_id)
+        {
+          errors.push(FieldError::new(
+            "playlist_id",
+            "expected a YouTube playlist ID",
+          ));
+        }
+      }
+      ReceiverOptions::Web => {
+        if let Err(err) = check_http_url("media_url", &self.media_url) {
+          errors.push(err);
+        }
+        if self.start_time.is_some() {
+          errors.push(FieldError::new(
+            "start_time",
+            "start times are only supported by the Default and YouTube receivers",
+          ));
+        }
+      }
+    }
+
+    if !matches!(self.receiver, ReceiverOptions::Default)
+      && (!self.text_tracks.is_empty() || self.text_track_style.is_some())
+    {
+      errors.push(FieldError::new(
+        "text_tracks",
+        "text tracks are only supported by the Default receiver",
+      ));
+    }
+    if !matches!(self.receiver, ReceiverOptions::Default) && self.auto_restart {
+      errors.push(FieldError::new(
+        "auto_restart",
+        "automatic restarts are only supported by the Default receiver",
+      ));
+    }
+    if self.playlist_id.is_some() && !matches!(self.receiver, ReceiverOptions::YouTube) {
+      errors.push(FieldError::new(
+        "playlist_id",
+        "playlists are only supported by the YouTube receiver",
+      ));
+    }
+    if let Some(start_time) = self.start_time
+      && !(start_time.is_finite() && start_time >= 0.0)
+    {
+      errors.push(FieldError::new(
+        "start_time",
+        "must be a number of seconds of at least 0",
+      ));
+    }
+    if let Some(style) = &self.text_track_style {
+      check_text_track_style("text_track_style", style, &mut errors);
+    }
+
+    if errors.is_empty() {
+      Ok(self)
+    } else {
+      Err(CastielError::ValidationFailed(errors))
+    }
+  }
+}
+
+impl YouTubeQueueData {
+  /// Reduces `media_url` to a YouTube video ID.
+  pub fn validate(mut self) -> Result<Self, CastielError> {
+    match youtube_video_id(self.media_url.trim()) {
+      Some(video_id) => {
+        self.media_url = video_id;
+        Ok(self)
+      }
+      None => Err(CastielError::ValidationFailed(vec![FieldError::new(
+        "media_url",
+        "expected a YouTube video ID or a YouTube video URL",
+      )])),
+    }
+  }
+}
+
+impl TextTrackStyle {
+  /// Checks that colors are `#RRGGBBAA` hex strings and the font scale is in range.
+  pub fn validate(&self) -> Result<(), CastielError> {
+    let mut errors = Vec::new();
+    check_text_track_style("style", self, &mut errors);
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(CastielError::ValidationFailed(errors))
+    }
+  }
+}
+
+/// Checks that `url` is an absolute `http` or `https` URL.
+fn check_http_url(field: &'static str, url: &str) -> Result<(), FieldError> {
+  if url.is_empty() {
+    return Err(FieldError::new(field, "must not be empty"));
+  }
+
+  let parsed =
+    Url::parse(url).map_err(|err| FieldError::new(field, format!("is not a valid URL: {err}")))?;
+  match parsed.scheme() {
+    "http" | "https" => Ok(()),
+    scheme => Err(FieldError::new(
+      field,
+      format!("unsupported URL scheme `{scheme}`, expected http or https"),
+    )),
+  }
+}
+
+/// Checks that `content_type` is a known MIME type which can be played with `stream_type`.
+fn validate_content_type(
+  content_type: Option<&str>,
+  stream_type: Option<StreamTypeOptions>,
+  errors: &mut Vec<FieldError>,
+) {
+  let Some(content_type) = content_type else {
+    errors.push(FieldError::new(
+      "content_type",
+      "could not be determined from the media URL and must be provided",
+    ));
+    return;
+  };
+
+  // Ignore parameters such as `; codecs="avc1.42E01E"`
+  let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+  if !SUPPORTED_CONTENT_TYPES.contains(&essence) {
+    errors.push(FieldError::new(
+      "content_type",
+      format!("`{essence}` is not a content type supported by the Default Media Receiver"),
+    ));
+  } else if essence.starts_with("image/") && stream_type == Some(StreamTypeOptions::Live) {
+    errors.push(FieldError::new(
+      "stream_type",
+      "images cannot be played as a live stream",
+    ));
+  }
+}
+
+/// Checks each text track's URL and language, and that at most one track starts enabled.
+fn validate_text_tracks(text_tracks: &mut [TextTrackData], errors: &mut Vec<FieldError>) {
+  for (index, track) in text_tracks.iter_mut().enumerate() {
+    track.url = track.url.trim().to_string();
+    if let Err(err) = check_http_url("text_tracks", &track.url) {
+      errors.push(FieldError::new(
+        "text_tracks",
+        format!("track {index} url {}", err.message),
+      ));
+    }
+
+    track.language = track
+      .language
+      .take()
+      .map(|language| language.trim().to_string());
+    if track.language.as_deref().is_some_and(|language| {
+      language.is_empty()
+        || !language
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }) {
+      errors.push(FieldError::new(
+        "text_tracks",
+        format!("track {index} language must be a BCP 47 language tag such as `en-US`"),
+      ));
+    }
+  }
+
+  if text_tracks.iter().filter(|track| track.enabled).count() > 1 {
+    errors.push(FieldError::new(
+      "text_tracks",
+      "at most one text track can be enabled",
+    ));
+  }
+}
+
+fn check_text_track_style(
+  field: &'static str,
+  style: &TextTrackStyle,
+  errors: &mut Vec<FieldError>,
+) {
+  let colors = [
+    &style.foreground_color,
+    &style.background_color,
+    &style.edge_color,
+    &style.window_color,
+  ];
+  for color in colors.into_iter().flatten() {
+    if !is_rgba_color(color) {
+      errors.push(FieldError::new(
+        field,
+        format!("`{color}` is not a color of the form #RRGGBBAA"),
+      ));
+    }
+  }
+
+  if let Some(font_scale) = style.font_scale
+    && !(font_scale > 0.0 && font_scale <= MAX_FONT_SCALE)
+  {
+    errors.push(FieldError::new(
+      field,
+      format!("font_scale must be greater than 0 and at most {MAX_FONT_SCALE}"),
+    ));
+  }
+}
+
+fn is_rgba_color(color: &str) -> bool {
+  color
+    .strip_prefix('#')
+    .is_some_and(|hex| hex.len() == 8 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Extracts the video ID from a YouTube URL, or returns `input` if it is already a bare ID.
+fn youtube_video_id(input: &str) -> Option<String> {
+  if is_youtube_id(input) {
+    return Some(input.to_string());
+  }
+
+  let url = Url::parse(input).ok()?;
+  let host = url.host_str()?;
+  let mut segments = url.path_segments()?.filter(|segment| !segment.is_empty());
+
+  let candidate = if host == "youtu.be" {
+    segments.next().map(ToString::to_string)
+  } else if YOUTUBE_HOSTS.contains(&host) {
+    match segments.next() {
+      Some("watch") => url
+        .query_pairs()
+        .find(|(key, _)| key == "v")
+        .map(|(_, value)| value.into_owned()),
+      Some("embed" | "shorts" | "live" | "v") => segments.next().map(ToString::to_string),
+      _ => None,
+    }
+  } else {
+    None
+  };
+
+  candidate.filter(|id| is_youtube_id(id))
+}
+
+/// Reads the playlist ID (`list`) and start time (`t` or `start`) from a YouTube URL.
+fn youtube_url_params(input: &str) -> (Option<String>, Option<f64>) {
+  let Ok(url) = Url::parse(input) else {
+    return (None, None);
+  };
+
+  let mut playlist_id = None;
+  let mut start_time = None;
+  for (key, value) in url.query_pairs() {
+    match key.as_ref() {
+      "list" => playlist_id = Some(value.into_owned()),
+      "t" | "start" => start_time = parse_youtube_time(&value),
+      _ => {}
+    }
+  }
+
+  (playlist_id, start_time)
+}
+
+/// Parses YouTube start times such as `90`, `90s` or `1h2m30s` into seconds.
+fn parse_youtube_time(time: &str) -> Option<f64> {
+  if let Ok(seconds) = time.parse::<u32>() {
+    return Some(f64::from(seconds));
+  }
+
+  let mut seconds: u32 = 0;
+  let mut number = String::new();
+  for c in time.chars() {
+    if c.is_ascii_digit() {
+      number.push(c);
+      continue;
+    }
+    let unit = match c {
+      'h' => 3600,
+      'm' => 60,
+      's' => 1,
+      _ => return None,
+    };
+    seconds = seconds.checked_add(number.parse::<u32>().ok()?.checked_mul(unit)?)?;
+    number.clear();
+  }
+
+  number.is_empty().then_some(f64::from(seconds))
+}
+
+/// YouTube playlist IDs are made of the same characters as video IDs, but vary in length.
+fn is_youtube_playlist_id(id: &str) -> bool {
+  !id.is_empty()
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// YouTube video IDs are 11 characters from the URL-safe base64 alphabet.
+fn is_youtube_id(id: &str) -> bool {
+  id.len() == 11
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn youtube_video_id_accepts_bare_ids() {
+    assert_eq!(
+      youtube_video_id("dQw4w9WgXcQ").as_deref(),
+      Some("dQw4w9WgXcQ")
+    );
+    assert_eq!(
+      youtube_video_id("a-b_c-d_e-f").as_deref(),
+      Some("a-b_c-d_e-f")
+    );
+  }
+
+  #[test]
+  fn youtube_video_id_reads_urls() {
+    for url in [
+      "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+      "https://youtube.com/watch?list=PL123&v=dQw4w9WgXcQ&t=30",
+      "https://m.youtube.com/watch?v=dQw4w9WgXcQ",
+      "https://music.youtube.com/watch?v=dQw4w9WgXcQ",
+      "https://youtu.be/dQw4w9WgXcQ",
+      "https://youtu.be/dQw4w9WgXcQ?t=1h2m3s",
+      "https://www.youtube.com/shorts/dQw4w9WgXcQ",
+      "https://www.youtube.com/embed/dQw4w9WgXcQ",
+      "https://www.youtube.com/live/dQw4w9WgXcQ",
+      "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ",
+    ] {
+      assert_eq!(
+        youtube_video_id(url).as_deref(),
+        Some("dQw4w9WgXcQ"),
+        "{url}"
+      );
+    }
+  }
+
+  #[test]
+  fn youtube_video_id_rejects_bad_ids() {
+    for input in [
+      "",
+      "dQw4w9WgXc",
+      "dQw4w9WgXcQQ",
+      "dQw4w9WgXc!",
+      "https://www.youtube.com/watch?v=short",
+      "https://www.youtube.com/watch?v=dQw4w9WgXc%21",
+      "https://www.youtube.com/watch",
+      "https://www.youtube.com/shorts/",
+      "https://www.youtube.com/channel/dQw4w9WgXcQ",
+      "https://youtu.be/",
+      "https://example.com/watch?v=dQw4w9WgXcQ",
+      "https://example.com/dQw4w9WgXcQ",
+    ] {
+      assert_eq!(youtube_video_id(input), None, "{input}");
+    }
+  }
+
+  #[test]
+  fn youtube_url_params_reads_playlist_and_start_time() {
+    assert_eq!(
+      youtube_url_params("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&t=1h2m3s"),
+      (Some("PL123".to_string()), Some(3723.0))
+    );
+    assert_eq!(
+      youtube_url_params("https://youtu.be/dQw4w9WgXcQ?start=90"),
+      (None, Some(90.0))
+    );
+    assert_eq!(youtube_url_params("dQw4w9WgXcQ"), (None, None));
+  }
+
+  #[test]
+  fn parse_youtube_time_accepts_seconds_and_units() {
+    assert_eq!(parse_youtube_time("0"), Some(0.0));
+    assert_eq!(parse_youtube_time("90"), Some(90.0));
+    assert_eq!(parse_youtube_time("90s"), Some(90.0));
+    assert_eq!(parse_youtube_time("2m"), Some(120.0));
+    assert_eq!(parse_youtube_time("1h2m3s"), Some(3723.0));
+    assert_eq!(parse_youtube_time("1h30s"), Some(3630.0));
+  }
+
+  #[test]
+  fn parse_youtube_time_rejects_malformed_times() {
+    for time in ["1h2", "1x", "-5", "1.5", "h", "99999999999", "4294967295h"] {
+      assert_eq!(parse_youtube_time(time), None, "{time}");
+    }
+  }
+
+  #[test]
+  fn start_times_are_rejected_for_the_web_receiver() {
+    let request = |receiver| -> StartMediaData {
+      serde_json::from_value(serde_json::json!({
+        "ip_address": "192.168.1.20",
+        "port": 8009,
+        "receiver": receiver,
+        "media_url": "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+        "content_type": "video/mp4",
+        "start_time": 30.0,
+      }))
+      .unwrap()
+    };
+
+    assert!(request("YouTube").validate().is_ok());
+    assert!(request("Default").validate().is_ok());
+    match request("Web").validate() {
+      Err(CastielError::ValidationFailed(errors)) => {
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "start_time");
+      }
+      result => panic!("expected a validation failure, got {result:?}"),
+    }
+  }
+
+  #[test]
+  fn content_types_are_checked_against_the_default_receiver() {
+    let check = |content_type, stream_type| {
+      let mut errors = Vec::new();
+      validate_content_type(content_type, stream_type, &mut errors);
+      errors.into_iter().map(|err| err.field).collect::<Vec<_>>()
+    };
+
+    assert!(check(Some("video/mp4"), None).is_empty());
+    assert!(check(Some("video/mp4; codecs=\"avc1.42E01E\""), None).is_empty());
+    assert!(check(Some("application/x-mpegurl"), Some(StreamTypeOptions::Live)).is_empty());
+    assert_eq!(check(Some("video/x-flv"), None), ["content_type"]);
+    assert_eq!(check(None, None), ["content_type"]);
+    assert_eq!(
+      check(Some("image/png"), Some(StreamTypeOptions::Live)),
+      ["stream_type"]
+    );
+  }
+
+  #[test]
+  fn text_track_styles_need_rgba_colors_and_a_font_scale_in_range() {
+    let check = |style: &TextTrackStyle| {
+      let mut errors = Vec::new();
+      check_text_track_style("style", style, &mut errors);
+      errors.len()
+    };
+
+    assert_eq!(check(&TextTrackStyle::default()), 0);
+    assert_eq!(
+      check(&TextTrackStyle {
+        foreground_color: Some("#FFFFFFFF".to_string()),
+        background_color: Some("#00000080".to_string()),
+        font_scale: Some(MAX_FONT_SCALE),
+        ..Default::default()
+      }),
+      0
+    );
+    assert_eq!(
+      check(&TextTrackStyle {
+        foreground_color: Some("#FFFFFF".to_string()),
+        edge_color: Some("FFFFFFFF".to_string()),
+        window_color: Some("#GGGGGGGG".to_string()),
+        ..Default::default()
+      }),
+      3
+    );
+    for font_scale in [0.0, -1.0, MAX_FONT_SCALE + 0.1, f32::NAN] {
+      let style = TextTrackStyle {
+        font_scale: Some(font_scale),
+        ..Default::default()
+      };
+      assert_eq!(check(&style), 1, "{font_scale}");
+    }
+  }
+}
@@ -0,0 +1,282 @@
+//! Plays and queues videos on the YouTube receiver.
+//!
+//! The YouTube receiver ignores most of the media channel. Instead, it registers with YouTube's
+//! "lounge" service as a screen, and senders control it by joining the screen's lounge session
+//! over HTTPS. The screen ID needed to join is read from the receiver over the MDX namespace.
+
+use std::{
+  collections::HashMap,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{Client, header};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+  devices::{app_ids::YOUTUBE_ID, raw::RawCastConnection},
+  errors::CastielError,
+};
+
+/// The namespace the YouTube receiver uses to talk to senders.
+const MDX_NAMESPACE: &str = "urn:x-cast:com.google.youtube.mdx";
+
+const LOUNGE_TOKEN_URL: &str = "https://www.youtube.com/api/lounge/pairing/get_lounge_token_batch";
+const BIND_URL: &str = "https://www.youtube.com/api/lounge/bc/bind";
+const LOUNGE_ID_HEADER: &str = "X-YouTube-LoungeId-Token";
+
+/// How long to wait for the lounge service to respond.
+const LOUNGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A video to play on the YouTube receiver.
+#[derive(Debug)]
+pub struct YouTubeVideo {
+  pub video_id: String,
+  /// A playlist to play the video as part of.
+  pub playlist_id: Option<String>,
+  /// Where to start playback, in seconds.
+  pub start_time: Option<f64>,
+}
+
+/// Where a video is added to the YouTube receiver's queue.
+#[derive(Debug, Default, Deserialize)]
+pub enum QueuePosition {
+  /// After every other queued video.
+  #[default]
+  End,
+  /// Straight after the video currently playing.
+  Next,
+}
+
+/// A request to add a video to the queue of the YouTube receiver.
+#[derive(Debug, Deserialize)]
+pub struct YouTubeQueueData {
+  pub ip_address: String,
+  pub port: u16,
+  /// A YouTube video ID or video URL.
+  pub media_url: String,
+  #[serde(default)]
+  pub position: QueuePosition,
+}
+
+/// Launches the YouTube receiver and plays `video`, replacing anything already playing.
+pub async fn play_video(ip: &str, port: u16, video: &YouTubeVideo) -> Result<(), CastielError> {
+  tracing::info!("Playing YouTube video {video:?} on {ip}:{port}");
+  let screen_id = get_screen_id(ip, port).await?;
+  let mut session = LoungeSession::join(&screen_id).await?;
+
+  let start_time = video.start_time.unwrap_or_default().to_string();
+  session
+    .send(
+      "setPlaylist",
+      &[
+        ("videoId", video.video_id.as_str()),
+        ("listId", video.playlist_id.as_deref().unwrap_or_default()),
+        ("currentTime", start_time.as_str()),
+        ("currentIndex", "-1"),
+        ("audioOnly", "false"),
+      ],
+    )
+    .await
+}
+
+/// Adds `video_id` to the YouTube receiver's queue at `position`.
+pub async fn queue_video(
+  ip: &str,
+  port: u16,
+  video_id: &str,
+  position: &QueuePosition,
+) -> Result<(), CastielError> {
+  tracing::info!("Queueing YouTube video {video_id} at {position:?} on {ip}:{port}");
+  let screen_id = get_screen_id(ip, port).await?;
+  let mut session = LoungeSession::join(&screen_id).await?;
+
+  let action = match position {
+    QueuePosition::End => "addVideo",
+    QueuePosition::Next => "insertVideo",
+  };
+  session.send(action, &[("videoId", video_id)]).await
+}
+
+/// Launches the YouTube receiver, or finds it if it is already running, and asks it for the
+/// screen ID it registered with the lounge service.
+async fn get_screen_id(ip: &str, port: u16) -> Result<String, CastielError> {
+  let ip = ip.to_string();
+  super::blocking(move || read_screen_id(&ip, port)).await
+}
+
+fn read_screen_id(ip: &str, port: u16) -> Result<String, CastielError> {
+  let connection = RawCastConnection::connect(ip, port)?;
+  let app = match connection.launch_app(YOUTUBE_ID) {
+    Ok(app) => app,
+    // The device answered, but refused to run the YouTube receiver
+    Err(CastielError::AppError(rust_cast::errors::Error::Internal(reason))) => {
+      return Err(CastielError::YouTubeUnsupported(reason));
+    }
+    Err(err) => return Err(err),
+  };
+
+  let reply = connection
+    .request_by_type(
+      MDX_NAMESPACE,
+      &app.transport_id,
+      json!({ "type": "getMdxSessionStatus" }),
+      "mdxSessionStatus",
+    )
+    .map_err(|err| match err {
+      rust_cast::errors::Error::Io(io_err)
+        if matches!(
+          io_err.kind(),
+          std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+        ) =>
+      {
+        CastielError::YouTubeUnsupported(
+          "the YouTube receiver did not answer on the MDX namespace".to_string(),
+        )
+      }
+      err => CastielError::ConnError(err),
+    })?;
+
+  reply["data"]["screenId"]
+    .as_str()
+    .map(ToString::to_string)
+    .ok_or_else(|| {
+      CastielError::YouTubeUnsupported("the YouTube receiver reported no screen ID".to_string())
+    })
+}
+
+/// A sender's connection to the lounge session of a YouTube screen.
+struct LoungeSession {
+  client: Client,
+  lounge_token: String,
+  /// The session ID assigned when binding.
+  sid: String,
+  gsessionid: String,
+  /// Counts the requests sent in the session, starting from the bind request.
+  rid: u32,
+}
+
+impl LoungeSession {
+  /// Exchanges `screen_id` for a lounge token and binds a new session to the screen's lounge.
+  async fn join(screen_id: &str) -> Result<Self, CastielError> {
+    let client = Client::builder()
+      .timeout(LOUNGE_TIMEOUT)
+      .build()
+      .map_err(|err| CastielError::YouTubeLoungeFailed(err.to_string()))?;
+
+    let body = post(
+      client
+        .post(LOUNGE_TOKEN_URL)
+        .form(&[("screen_ids", screen_id)]),
+    )
+    .await?;
+    let lounge_token = serde_json::from_str::<serde_json::Value>(&body)
+      .ok()
+      .and_then(|tokens| {
+        tokens["screens"][0]["loungeToken"]
+          .as_str()
+          .map(String::from)
+      })
+      .ok_or_else(|| {
+        CastielError::YouTubeLoungeFailed("no lounge token was issued for the screen".to_string())
+      })?;
+
+    let body = post(
+      client
+        .post(BIND_URL)
+        .header(LOUNGE_ID_HEADER, &lounge_token)
+        .query(&[("RID", "0"), ("VER", "8"), ("CVER", "1")])
+        .form(&[
+          ("device", "REMOTE_CONTROL"),
+          ("id", &sender_id()),
+          ("name", "Castiel"),
+          ("mdx-version", "3"),
+          ("pairing_type", "cast"),
+          ("app", "android-phone-13.14.55"),
+        ]),
+    )
+    .await?;
+    let (Some(sid), Some(gsessionid)) = (
+      quoted_after(&body, r#"["c",""#),
+      quoted_after(&body, r#"["S",""#),
+    ) else {
+      return Err(CastielError::YouTubeLoungeFailed(
+        "binding to the lounge returned no session".to_string(),
+      ));
+    };
+
+    Ok(Self {
+      client,
+      lounge_token,
+      sid,
+      gsessionid,
+      rid: 1,
+    })
+  }
+
+  /// Sends a single `action` with `params` to the screen.
+  async fn send(&mut self, action: &str, params: &[(&str, &str)]) -> Result<(), CastielError> {
+    let mut form: HashMap<String, &str> = params
+      .iter()
+      .map(|(key, value)| (format!("req0_{key}"), *value))
+      .collect();
+    form.insert("req0__sc".to_string(), action);
+    form.insert("count".to_string(), "1");
+
+    let rid = self.rid.to_string();
+    self.rid += 1;
+    post(
+      self
+        .client
+        .post(BIND_URL)
+        .header(LOUNGE_ID_HEADER, &self.lounge_token)
+        .query(&[
+          ("SID", self.sid.as_str()),
+          ("gsessionid", self.gsessionid.as_str()),
+          ("RID", rid.as_str()),
+          ("VER", "8"),
+          ("CVER", "1"),
+        ])
+        .form(&form),
+    )
+    .await?;
+
+    Ok(())
+  }
+}
+
+/// Sends a lounge request and returns the response body, failing on non-success statuses.
+async fn post(request: reqwest::RequestBuilder) -> Result<String, CastielError> {
+  let response = request
+    .header(header::ORIGIN, "https://www.youtube.com")
+    .send()
+    .await
+    .map_err(|err| CastielError::YouTubeLoungeFailed(err.to_string()))?;
+  let status = response.status();
+  if !status.is_success() {
+    return Err(CastielError::YouTubeLoungeFailed(format!(
+      "the lounge service responded with {status}"
+    )));
+  }
+
+  response
+    .text()
+    .await
+    .map_err(|err| CastielError::YouTubeLoungeFailed(err.to_string()))
+}
+
+/// Returns the quoted string which directly follows `marker` in `body`.
+fn quoted_after(body: &str, marker: &str) -> Option<String> {
+  let start = body.find(marker)? + marker.len();
+  let length = body[start..].find('"')?;
+  Some(body[start..start + length].to_string())
+}
+
+/// A fresh ID to identify Castiel as a remote in a new lounge session.
+fn sender_id() -> String {
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_nanos())
+    .unwrap_or_default();
+  format!("castiel{nanos:x}")
+}
@@ -1,11 +1,21 @@
 //! Defines an error enum for use throughout Castiel.
 //!
 //! The error can be transformed into an HTTP response and is used in all
-//! API outputs.
+//! API outputs. Responses carry a JSON [`ErrorBody`] whose `code` field is
+//! stable and intended for automated clients to branch on.
 
-use axum::{http::StatusCode, response::IntoResponse, response::Response};
+use axum::{
+  Json,
+  extract::rejection::JsonRejection,
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::json;
 use thiserror::Error;
 
+use crate::devices::validation::FieldError;
+
 #[derive(Debug, Error)]
 pub enum CastielError {
   #[error("Configuration error: {0}")]
@@ -16,36 +26,424 @@ pub enum CastielError {
   JsonError(#[from] serde_json::Error),
   #[error("Chromecast media error: {0}")]
   MediaError(rust_cast::errors::Error),
+  #[error("Chromecast media command failed: {0}")]
+  MediaCommandFailed(rust_cast::errors::Error),
   #[error("Chromecast app error: {0}")]
   AppError(rust_cast::errors::Error),
   #[error("Chromecast connection error: {0}")]
   ConnError(rust_cast::errors::Error),
+  #[error("No device was found at {0}")]
+  DeviceNotFound(String),
   #[error("Chromecast device lookup failed: {0}")]
   DeviceLookupFailed(rust_cast::errors::Error),
   #[error("Chromecast app lookup failed")]
   AppLookupFailed,
+  #[error("No running app matches {0}")]
+  AppNotFound(String),
+  #[error("No receiver app named `{0}` is registered")]
+  AppNotRegistered(String),
+  #[error("No media is loaded in the running app")]
+  NoMediaSession,
+  #[error("The device does not support YouTube casting: {0}")]
+  YouTubeUnsupported(String),
+  #[error("YouTube lounge request failed: {0}")]
+  YouTubeLoungeFailed(String),
+  #[error("Device {0} presented a different certificate than the one pinned for it")]
+  DeviceCertificateChanged(String),
+  #[error("No certificate is pinned for device {0}")]
+  DevicePinNotFound(String),
+  #[error("No preset named `{0}` exists")]
+  PresetNotFound(String),
+  #[error("Stored state error: {0}")]
+  StateError(String),
+  #[error("Relaying media failed: {0}")]
+  RelayFailed(String),
+  #[error("Authentication is required")]
+  Unauthorized,
+  #[error("Forbidden: {0}")]
+  Forbidden(String),
+  #[error("The request body is too large")]
+  PayloadTooLarge,
+  #[error("Invalid request body: {0}")]
+  JsonRejection(#[from] JsonRejection),
+  #[error("Request validation failed: {}", describe_field_errors(.0))]
+  ValidationFailed(Vec<FieldError>),
   #[error("Internal server error")]
   InternalError,
+  /// Wraps another error with the address of the device it occurred at.
+  #[error("{source}")]
+  AtDevice {
+    device: String,
+    source: Box<CastielError>,
+  },
+}
+
+/// The JSON body returned for every failed API request.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+  /// A stable, machine-readable error code such as `device_unreachable`.
+  pub code: &'static str,
+  /// A human-readable description of the error.
+  pub message: String,
+  /// The `ip:port` address of the device involved, if any.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub device: Option<String>,
+  /// Additional error-specific information.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub details: Option<serde_json::Value>,
+}
+
+impl CastielError {
+  /// Attaches the address of the device at which this error occurred.
+  pub fn at_device(self, ip: &str, port: u16) -> Self {
+    Self::AtDevice {
+      device: format!("{ip}:{port}"),
+      source: Box::new(self),
+    }
+  }
+
+  /// The HTTP status code this error is reported with.
+  pub fn status_code(&self) -> StatusCode {
+    match self {
+      Self::ConfigError(_)
+      | Self::IoError(_)
+      | Self::JsonError(_)
+      | Self::StateError(_)
+      | Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+      Self::MediaError(err)
+      | Self::MediaCommandFailed(err)
+      | Self::AppError(err)
+      | Self::ConnError(err)
+      | Self::DeviceLookupFailed(err) => {
+        if is_timeout(err) {
+          StatusCode::GATEWAY_TIMEOUT
+        } else {
+          StatusCode::BAD_GATEWAY
+        }
+      }
+      Self::AppLookupFailed | Self::NoMediaSession => StatusCode::CONFLICT,
+      Self::DeviceNotFound(_)
+      | Self::AppNotFound(_)
+      | Self::AppNotRegistered(_)
+      | Self::DevicePinNotFound(_)
+      | Self::PresetNotFound(_) => StatusCode::NOT_FOUND,
+      Self::YouTubeUnsupported(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      Self::YouTubeLoungeFailed(_) | Self::RelayFailed(_) | Self::DeviceCertificateChanged(_) => {
+        StatusCode::BAD_GATEWAY
+      }
+      Self::Unauthorized => StatusCode::UNAUTHORIZED,
+      Self::Forbidden(_) => StatusCode::FORBIDDEN,
+      Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+      Self::JsonRejection(rejection) => rejection.status(),
+      Self::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      Self::AtDevice { source, .. } => source.status_code(),
+    }
+  }
+
+  /// The stable, machine-readable code identifying this kind of error.
+  pub fn code(&self) -> &'static str {
+    match self {
+      Self::ConfigError(_) => "config_error",
+      Self::IoError(_) | Self::JsonError(_) | Self::InternalError => "internal_error",
+      Self::MediaError(err)
+      | Self::MediaCommandFailed(err)
+      | Self::AppError(err)
+      | Self::ConnError(err)
+        if is_timeout(err) =>
+      {
+        "device_timeout"
+      }
+      Self::MediaError(_) => "media_load_failed",
+      Self::MediaCommandFailed(_) => "media_command_failed",
+      Self::AppError(_) => "app_launch_failed",
+      Self::ConnError(_) => "device_connection_failed",
+      Self::DeviceLookupFailed(err) => match err {
+        _ if is_timeout(err) => "device_timeout",
+        rust_cast::errors::Error::Tls(_) => "device_tls_error",
+        _ => "device_unreachable",
+      },
+      Self::DeviceNotFound(_) => "device_not_found",
+      Self::AppLookupFailed => "app_not_running",
+      Self::AppNotFound(_) => "app_not_found",
+      Self::AppNotRegistered(_) => "app_not_registered",
+      Self::NoMediaSession => "no_media_session",
+      Self::YouTubeUnsupported(_) => "youtube_unsupported",
+      Self::YouTubeLoungeFailed(_) => "youtube_lounge_failed",
+      Self::DeviceCertificateChanged(_) => "device_certificate_changed",
+      Self::DevicePinNotFound(_) => "device_pin_not_found",
+      Self::PresetNotFound(_) => "preset_not_found",
+      Self::StateError(_) => "state_error",
+      Self::RelayFailed(_) => "relay_failed",
+      Self::Unauthorized => "unauthorized",
+      Self::Forbidden(_) => "forbidden",
+      Self::PayloadTooLarge => "payload_too_large",
+      Self::JsonRejection(_) => "invalid_request",
+      Self::ValidationFailed(_) => "validation_failed",
+      Self::AtDevice { source, .. } => source.code(),
+    }
+  }
+
+  /// Builds the JSON body describing this error.
+  pub fn to_body(&self) -> ErrorBody {
+    match self {
+      Self::AtDevice { device, source } => ErrorBody {
+        device: Some(device.clone()),
+        ..source.to_body()
+      },
+      _ => ErrorBody {
+        code: self.code(),
+        message: self.to_string(),
+        device: None,
+        details: self.details(),
+      },
+    }
+  }
+
+  fn details(&self) -> Option<serde_json::Value> {
+    match self {
+      Self::MediaError(err)
+      | Self::MediaCommandFailed(err)
+      | Self::AppError(err)
+      | Self::ConnError(err)
+      | Self::DeviceLookupFailed(err) => Some(json!({ "cause": cast_error_cause(err) })),
+      Self::ValidationFailed(errors) => Some(json!({ "fields": errors })),
+      _ => None,
+    }
+  }
 }
 
 impl IntoResponse for CastielError {
   fn into_response(self) -> Response {
-    let (status, error_message) = match self {
-      Self::ConfigError(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-      Self::IoError(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-      Self::JsonError(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-      Self::MediaError(err) | Self::AppError(err) => {
-        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
-      }
-      Self::ConnError(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-      Self::DeviceLookupFailed(err) => (StatusCode::NOT_FOUND, err.to_string()),
-      Self::AppLookupFailed => (StatusCode::NOT_FOUND, Self::AppLookupFailed.to_string()),
-      Self::InternalError => (
+    let status = self.status_code();
+    if status.is_server_error() {
+      tracing::error!("{self}");
+    }
+
+    (status, Json(self.to_body())).into_response()
+  }
+}
+
+/// Whether a [`rust_cast`] error was caused by the device not responding in time.
+pub fn is_timeout(err: &rust_cast::errors::Error) -> bool {
+  matches!(
+    err,
+    rust_cast::errors::Error::Io(io_err)
+      if matches!(io_err.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock)
+  )
+}
+
+/// A short name for the layer of the Cast protocol a [`rust_cast`] error came from.
+fn cast_error_cause(err: &rust_cast::errors::Error) -> &'static str {
+  match err {
+    rust_cast::errors::Error::Internal(_) => "internal",
+    rust_cast::errors::Error::Io(_) => "io",
+    rust_cast::errors::Error::Protobuf(_) => "protobuf",
+    rust_cast::errors::Error::Serialization(_) => "serialization",
+    rust_cast::errors::Error::Parsing(_) => "parsing",
+    rust_cast::errors::Error::Dns(_) => "dns",
+    rust_cast::errors::Error::Tls(_) => "tls",
+    rust_cast::errors::Error::Namespace(_) => "namespace",
+  }
+}
+
+/// Joins field errors into a single sentence for the error's message.
+fn describe_field_errors(errors: &[FieldError]) -> String {
+  errors
+    .iter()
+    .map(|err| format!("{} {}", err.field, err.message))
+    .collect::<Vec<_>>()
+    .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+
+  use super::*;
+
+  fn cast_error(kind: io::ErrorKind) -> rust_cast::errors::Error {
+    rust_cast::errors::Error::Io(io::Error::from(kind))
+  }
+
+  #[test]
+  fn errors_have_stable_codes_and_statuses() {
+    let refused = io::ErrorKind::ConnectionRefused;
+    let cases = [
+      (
+        CastielError::ConfigError(config::ConfigError::Message("bad".to_string())),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "config_error",
+      ),
+      (
+        CastielError::IoError(io::Error::from(io::ErrorKind::NotFound)),
         StatusCode::INTERNAL_SERVER_ERROR,
-        Self::InternalError.to_string(),
+        "internal_error",
       ),
-    };
+      (
+        CastielError::JsonError(serde_json::from_str::<()>("{").unwrap_err()),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+      ),
+      (
+        CastielError::MediaError(cast_error(refused)),
+        StatusCode::BAD_GATEWAY,
+        "media_load_failed",
+      ),
+      (
+        CastielError::MediaCommandFailed(cast_error(refused)),
+        StatusCode::BAD_GATEWAY,
+        "media_command_failed",
+      ),
+      (
+        CastielError::AppError(cast_error(refused)),
+        StatusCode::BAD_GATEWAY,
+        "app_launch_failed",
+      ),
+      (
+        CastielError::ConnError(cast_error(refused)),
+        StatusCode::BAD_GATEWAY,
+        "device_connection_failed",
+      ),
+      (
+        CastielError::ConnError(cast_error(io::ErrorKind::TimedOut)),
+        StatusCode::GATEWAY_TIMEOUT,
+        "device_timeout",
+      ),
+      (
+        CastielError::MediaError(cast_error(io::ErrorKind::WouldBlock)),
+        StatusCode::GATEWAY_TIMEOUT,
+        "device_timeout",
+      ),
+      (
+        CastielError::DeviceLookupFailed(cast_error(refused)),
+        StatusCode::BAD_GATEWAY,
+        "device_unreachable",
+      ),
+      (
+        CastielError::DeviceLookupFailed(cast_error(io::ErrorKind::TimedOut)),
+        StatusCode::GATEWAY_TIMEOUT,
+        "device_timeout",
+      ),
+      (
+        CastielError::DeviceLookupFailed(rust_cast::errors::Error::Tls(
+          rustls::Error::DecryptError,
+        )),
+        StatusCode::BAD_GATEWAY,
+        "device_tls_error",
+      ),
+      (
+        CastielError::DeviceNotFound("nowhere:8009".to_string()),
+        StatusCode::NOT_FOUND,
+        "device_not_found",
+      ),
+      (
+        CastielError::AppLookupFailed,
+        StatusCode::CONFLICT,
+        "app_not_running",
+      ),
+      (
+        CastielError::AppNotFound("CC1AD845".to_string()),
+        StatusCode::NOT_FOUND,
+        "app_not_found",
+      ),
+      (
+        CastielError::AppNotRegistered("mine".to_string()),
+        StatusCode::NOT_FOUND,
+        "app_not_registered",
+      ),
+      (
+        CastielError::NoMediaSession,
+        StatusCode::CONFLICT,
+        "no_media_session",
+      ),
+      (
+        CastielError::YouTubeUnsupported("old".to_string()),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "youtube_unsupported",
+      ),
+      (
+        CastielError::YouTubeLoungeFailed("down".to_string()),
+        StatusCode::BAD_GATEWAY,
+        "youtube_lounge_failed",
+      ),
+      (
+        CastielError::DeviceCertificateChanged("device".to_string()),
+        StatusCode::BAD_GATEWAY,
+        "device_certificate_changed",
+      ),
+      (
+        CastielError::DevicePinNotFound("device".to_string()),
+        StatusCode::NOT_FOUND,
+        "device_pin_not_found",
+      ),
+      (
+        CastielError::PresetNotFound("evening".to_string()),
+        StatusCode::NOT_FOUND,
+        "preset_not_found",
+      ),
+      (
+        CastielError::StateError("corrupt".to_string()),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "state_error",
+      ),
+      (
+        CastielError::RelayFailed("ffmpeg".to_string()),
+        StatusCode::BAD_GATEWAY,
+        "relay_failed",
+      ),
+      (
+        CastielError::Unauthorized,
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+      ),
+      (
+        CastielError::Forbidden("no".to_string()),
+        StatusCode::FORBIDDEN,
+        "forbidden",
+      ),
+      (
+        CastielError::PayloadTooLarge,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+      ),
+      (
+        CastielError::ValidationFailed(vec![FieldError::new("url", "is required")]),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "validation_failed",
+      ),
+      (
+        CastielError::InternalError,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+      ),
+      (
+        CastielError::NoMediaSession.at_device("192.168.1.20", 8009),
+        StatusCode::CONFLICT,
+        "no_media_session",
+      ),
+    ];
+
+    for (error, status, code) in cases {
+      assert_eq!(error.status_code(), status, "{error:?}");
+      assert_eq!(error.code(), code, "{error:?}");
+      assert_eq!(error.to_body().code, code, "{error:?}");
+    }
+  }
+
+  #[test]
+  fn bodies_carry_the_device_and_details() {
+    let body = CastielError::ConnError(cast_error(io::ErrorKind::ConnectionRefused))
+      .at_device("192.168.1.20", 8009)
+      .to_body();
+    assert_eq!(body.device.as_deref(), Some("192.168.1.20:8009"));
+    assert_eq!(body.details, Some(json!({ "cause": "io" })));
 
-    (status, error_message).into_response()
+    let body =
+      CastielError::ValidationFailed(vec![FieldError::new("url", "is required")]).to_body();
+    assert_eq!(body.message, "Request validation failed: url is required");
+    assert_eq!(
+      body.details,
+      Some(json!({ "fields": [{ "field": "url", "message": "is required" }] }))
+    );
   }
 }
@@ -0,0 +1,25 @@
+//! Request extractors whose rejections are reported as [`CastielError`]s.
+
+use axum::{
+  Json,
+  extract::{FromRequest, Request, rejection::JsonRejection},
+};
+
+use crate::errors::CastielError;
+
+/// A drop-in replacement for [`axum::Json`] that rejects malformed bodies with Castiel's JSON
+/// error format instead of axum's plain-text one.
+pub struct ApiJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+  Json<T>: FromRequest<S, Rejection = JsonRejection>,
+  S: Send + Sync,
+{
+  type Rejection = CastielError;
+
+  async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+    let Json(value) = Json::<T>::from_request(req, state).await?;
+    Ok(Self(value))
+  }
+}
@@ -0,0 +1,302 @@
+//! Liveness and readiness checks for container orchestrators and uptime monitors.
+//!
+//! `/healthz` answers as long as the process is serving requests. `/readyz` also checks that the
+//! settings file loads, that the last discovery scan succeeded, that the frontend is in place and,
+//! if configured, that enough devices were reachable in the background status snapshot. Probes
+//! only read what Castiel has already recorded, so they never scan the network or connect to
+//! devices themselves. Both are served without authentication.
+
+use std::sync::PoisonError;
+
+use axum::{
+  Json, Router,
+  extract::State,
+  http::StatusCode,
+  response::{IntoResponse, Response},
+  routing::get,
+};
+use serde::Serialize;
+
+use crate::{
+  aggregate::{DiscoveryScan, StatusSnapshot},
+  config::CastielSettings,
+  routes,
+  state::AppState,
+};
+
+/// Creates the routes serving `/healthz` and `/readyz`.
+pub fn create_routes() -> Router<AppState> {
+  Router::new()
+    .route("/healthz", get(get_health))
+    .route("/readyz", get(get_readiness))
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+  status: &'static str,
+  version: &'static str,
+  /// How long Castiel has been running, in seconds.
+  uptime_seconds: u64,
+}
+
+/// Handler for the GET /healthz endpoint.
+async fn get_health(State(state): State<AppState>) -> Json<HealthResponse> {
+  Json(HealthResponse {
+    status: "ok",
+    version: env!("CARGO_PKG_VERSION"),
+    uptime_seconds: state.started_at.elapsed().as_secs(),
+  })
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+  ready: bool,
+  checks: ReadinessChecks,
+}
+
+#[derive(Serialize)]
+struct ReadinessChecks {
+  /// Whether the settings file can be loaded.
+  config: Check,
+  /// Whether the most recent discovery scan succeeded.
+  discovery: DiscoveryCheck,
+  /// Whether the frontend's files are in place.
+  frontend: Check,
+  /// Whether at least `min_reachable_devices` devices answered in the background status snapshot.
+  devices: DeviceCheck,
+}
+
+#[derive(Serialize)]
+struct Check {
+  ok: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DiscoveryCheck {
+  ok: bool,
+  /// When the most recent scan finished, in seconds since the Unix epoch. Unset until the first
+  /// scan, which doesn't fail the check.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  last_scan_at: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeviceCheck {
+  ok: bool,
+  /// The number of known devices which answered.
+  reachable: usize,
+  /// The number of devices which must answer.
+  required: usize,
+  /// When the snapshot the devices were counted from was collected, in seconds since the Unix
+  /// epoch.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  snapshot_at: Option<u64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+impl Check {
+  fn new(error: Option<String>) -> Self {
+    Self {
+      ok: error.is_none(),
+      error,
+    }
+  }
+}
+
+/// Handler for the GET /readyz endpoint.
+///
+/// Responds with `503 Service Unavailable` if any check fails.
+async fn get_readiness(State(state): State<AppState>) -> Response {
+  // The parse error can quote the settings file, which may hold secrets
+  let config = match CastielSettings::load(&state.config_path) {
+    Ok(_) => Check::new(None),
+    Err(err) => {
+      tracing::warn!("Readiness check failed to load settings: {err}");
+      Check::new(Some("the settings file could not be loaded".to_string()))
+    }
+  };
+
+  let last_scan = state
+    .last_discovery
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone();
+  let discovery = discovery_check(last_scan);
+
+  let index = routes::frontend_dir().join("index.html");
+  let frontend = if index.is_file() {
+    Check::new(None)
+  } else {
+    Check::new(Some(format!("{} is missing", index.display())))
+  };
+
+  let required = state.settings().health.min_reachable_devices;
+  let devices = device_check(
+    state
+      .status_snapshot
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .as_ref(),
+    required,
+  );
+
+  let ready = config.ok && discovery.ok && frontend.ok && devices.ok;
+  let status = if ready {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  let response = ReadinessResponse {
+    ready,
+    checks: ReadinessChecks {
+      config,
+      discovery,
+      frontend,
+      devices,
+    },
+  };
+
+  (status, Json(response)).into_response()
+}
+
+/// Passes unless the most recent discovery scan failed. Before the first scan there is nothing to
+/// check, so it passes.
+fn discovery_check(last_scan: Option<DiscoveryScan>) -> DiscoveryCheck {
+  DiscoveryCheck {
+    ok: last_scan.as_ref().is_none_or(|scan| scan.error.is_none()),
+    last_scan_at: last_scan.as_ref().map(|scan| scan.finished_at),
+    error: last_scan
+      .and_then(|scan| scan.error)
+      .map(|err| format!("the last discovery scan failed: {err}")),
+  }
+}
+
+/// Counts the devices which answered in the background status snapshot. Without a snapshot there
+/// is nothing to count, so the check fails rather than connecting to devices from the probe. It
+/// always passes when no devices are `required`.
+fn device_check(snapshot: Option<&StatusSnapshot>, required: usize) -> DeviceCheck {
+  if required == 0 {
+    return DeviceCheck {
+      ok: true,
+      reachable: 0,
+      required,
+      snapshot_at: None,
+      error: None,
+    };
+  }
+  let Some(snapshot) = snapshot else {
+    return DeviceCheck {
+      ok: false,
+      reachable: 0,
+      required,
+      snapshot_at: None,
+      error: Some(
+        "no background status snapshot has been collected; set status_refresh_seconds".to_string(),
+      ),
+    };
+  };
+
+  let reachable = snapshot
+    .devices
+    .iter()
+    .filter(|report| report.device_status.is_some())
+    .count();
+  DeviceCheck {
+    ok: reachable >= required,
+    reachable,
+    required,
+    snapshot_at: Some(snapshot.generated_at),
+    error: None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use serde_json::json;
+
+  use super::*;
+  use crate::{
+    aggregate::DeviceReport,
+    devices::{discovery::DiscoveredDevice, status::DeviceStatus},
+  };
+
+  fn scan(error: Option<&str>) -> DiscoveryScan {
+    DiscoveryScan {
+      finished_at: 1_700_000_000,
+      devices_found: error.is_none().then_some(1),
+      error: error.map(ToString::to_string),
+    }
+  }
+
+  fn snapshot(reachable: usize, unreachable: usize) -> StatusSnapshot {
+    let report = |answered: bool| DeviceReport {
+      device: DiscoveredDevice {
+        ip_address: "192.168.1.20".to_string(),
+        port: 8009,
+        fullname: "Chromecast._googlecast._tcp.local.".to_string(),
+        id: None,
+        model_name: None,
+        friendly_name: None,
+        txt_properties: HashMap::new(),
+      },
+      device_status: answered.then(|| DeviceStatus::from_reply(json!({ "applications": [] }))),
+      media_status: None,
+      error: None,
+    };
+    StatusSnapshot {
+      generated_at: 1_700_000_000,
+      devices: (0..reachable)
+        .map(|_| report(true))
+        .chain((0..unreachable).map(|_| report(false)))
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn discovery_is_ready_until_a_scan_fails() {
+    assert!(discovery_check(None).ok);
+
+    let check = discovery_check(Some(scan(None)));
+    assert!(check.ok);
+    assert_eq!(check.last_scan_at, Some(1_700_000_000));
+
+    let check = discovery_check(Some(scan(Some("no network interface"))));
+    assert!(!check.ok);
+    assert_eq!(
+      check.error.as_deref(),
+      Some("the last discovery scan failed: no network interface")
+    );
+  }
+
+  #[test]
+  fn devices_are_ready_when_enough_answered() {
+    let check = device_check(Some(&snapshot(2, 1)), 2);
+    assert!(check.ok);
+    assert_eq!((check.reachable, check.required), (2, 2));
+    assert_eq!(check.snapshot_at, Some(1_700_000_000));
+
+    let check = device_check(Some(&snapshot(1, 2)), 2);
+    assert!(!check.ok);
+    assert_eq!(check.reachable, 1);
+  }
+
+  #[test]
+  fn devices_are_not_ready_without_a_snapshot() {
+    let check = device_check(None, 1);
+    assert!(!check.ok);
+    assert!(check.error.unwrap().contains("status_refresh_seconds"));
+  }
+
+  #[test]
+  fn devices_are_ignored_unless_required() {
+    assert!(device_check(None, 0).ok);
+    assert!(device_check(Some(&snapshot(0, 3)), 0).ok);
+  }
+}
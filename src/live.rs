@@ -0,0 +1,295 @@
+//! Keeps media started with `auto_restart` playing.
+//!
+//! A watch polls the media status of the device it was started on and reloads the media when the
+//! player reports an error, or goes idle on its own. Watching stops once something else takes
+//! over the device: other media is loaded, playback is stopped by a sender or the receiver app is
+//! closed.
+
+use std::{sync::PoisonError, time::Duration};
+
+use serde_json::json;
+
+use crate::{
+  config::WebhookEvent,
+  devices::{
+    AppSelector,
+    app_ids::DEFAULT_MEDIA_ID,
+    media::{self, StartMediaData},
+    status::{self, IdleReason, MediaStatus, PlayerState},
+  },
+  errors::CastielError,
+  metrics,
+  state::AppState,
+  webhooks::{self, Event, EventDevice},
+};
+
+/// How often the media status of a watched device is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many restarts in a row may fail to get the media playing before giving up.
+const MAX_CONSECUTIVE_RESTARTS: u32 = 5;
+
+/// How many status checks in a row may fail before the device is considered gone.
+const MAX_CONSECUTIVE_FAILURES: u32 = 30;
+
+/// What a watch should do after a status check.
+enum WatchAction {
+  /// The media is playing, or about to.
+  Continue,
+  /// The media stopped by itself and should be loaded again.
+  Restart(&'static str),
+  /// Something else took over the device.
+  Stop(&'static str),
+}
+
+/// Starts watching the media described by `data`, replacing any watch on the same device.
+pub fn watch(state: &AppState, data: StartMediaData) {
+  let device = format!("{}:{}", data.ip_address, data.port);
+  tracing::info!("Watching {} on {device} for restarts", data.media_url);
+
+  let mut watches = state
+    .live_watches
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner);
+  let task = tokio::spawn(watch_media(state.clone(), device.clone(), data));
+  if let Some(previous) = watches.insert(device, task.abort_handle()) {
+    previous.abort();
+  }
+}
+
+/// Stops watching the device at `ip`:`port`, if it is being watched.
+pub fn unwatch(state: &AppState, ip: &str, port: u16) {
+  let device = format!("{ip}:{port}");
+  let previous = state
+    .live_watches
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .remove(&device);
+  if let Some(previous) = previous {
+    tracing::info!("Stopped watching {device}");
+    previous.abort();
+  }
+}
+
+async fn watch_media(state: AppState, device: String, data: StartMediaData) {
+  let mut restarts = 0;
+  let mut failures = 0;
+  let mut idle_polls = 0;
+
+  loop {
+    tokio::time::sleep(POLL_INTERVAL).await;
+
+    let (ip, port) = (data.ip_address.clone(), data.port);
+    let app_selector = AppSelector {
+      session_id: None,
+      app_id: Some(DEFAULT_MEDIA_ID.to_string()),
+    };
+    let result =
+      tokio::task::spawn_blocking(move || status::get_media_status(&ip, port, &app_selector))
+        .await
+        .unwrap_or(Err(CastielError::InternalError));
+
+    let action = match result {
+      Ok(media_status) => {
+        failures = 0;
+        if matches!(media_status.player_state(), PlayerState::Playing) {
+          restarts = 0;
+        }
+        next_action(&media_status, &data.media_url, &mut idle_polls)
+      }
+      Err(CastielError::AppNotFound(_)) => WatchAction::Stop("the receiver app was closed"),
+      Err(err) => {
+        failures += 1;
+        tracing::debug!("Failed to check media status on {device}: {err}");
+        if failures >= MAX_CONSECUTIVE_FAILURES {
+          WatchAction::Stop("the device stopped responding")
+        } else {
+          WatchAction::Continue
+        }
+      }
+    };
+
+    match action {
+      WatchAction::Continue => {}
+      WatchAction::Restart(reason) => {
+        if restarts >= MAX_CONSECUTIVE_RESTARTS {
+          tracing::warn!(
+            "Giving up on {} at {device} after {restarts} restarts",
+            data.media_url
+          );
+          let name = state
+            .devices
+            .find(&data.ip_address, data.port)
+            .and_then(|found| found.friendly_name);
+          webhooks::notify(
+            &state,
+            Event::new(
+              WebhookEvent::WatchdogRecoveryFailed,
+              Some(EventDevice {
+                address: device.clone(),
+                name,
+              }),
+              json!({ "media_url": data.media_url, "restarts": restarts }),
+            ),
+          );
+          break;
+        }
+        restarts += 1;
+        idle_polls = 0;
+        tracing::warn!(
+          "Restarting {} at {device} because {reason} (attempt {restarts})",
+          data.media_url
+        );
+
+        let data = data.clone();
+        let result = tokio::task::spawn_blocking(move || media::load_default_media(&data))
+          .await
+          .unwrap_or(Err(CastielError::InternalError));
+        metrics::observe_media_restart(if result.is_ok() { "ok" } else { "error" });
+        if let Err(err) = result {
+          tracing::warn!("Failed to restart media at {device}: {err}");
+        }
+      }
+      WatchAction::Stop(reason) => {
+        tracing::info!(
+          "Stopped watching {} at {device} because {reason}",
+          data.media_url
+        );
+        break;
+      }
+    }
+  }
+
+  // Remove this watch unless it has already been replaced by a newer one
+  let mut watches = state
+    .live_watches
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner);
+  if watches
+    .get(&device)
+    .is_some_and(|watch| watch.id() == tokio::task::id())
+  {
+    watches.remove(&device);
+  }
+}
+
+/// Decides what to do about the reported `media_status` of the watched `media_url`.
+///
+/// The receiver drops a media session shortly after it ends, so a missing session is treated as
+/// the stream having ended unless a sender is seen stopping it first. An idle player without a
+/// reason is given one extra check to start playing, since players are briefly idle while loading.
+fn next_action(media_status: &MediaStatus, media_url: &str, idle_polls: &mut u32) -> WatchAction {
+  match media_status.content_id() {
+    None => return WatchAction::Restart("the media session ended"),
+    Some(content_id) if content_id != media_url => {
+      return WatchAction::Stop("other media was loaded");
+    }
+    Some(_) => {}
+  }
+
+  match (media_status.player_state(), media_status.idle_reason()) {
+    (PlayerState::Idle, Some(IdleReason::Error)) => WatchAction::Restart("playback failed"),
+    (PlayerState::Idle, Some(IdleReason::Finished)) => WatchAction::Restart("the stream ended"),
+    (PlayerState::Idle, Some(IdleReason::Cancelled)) => WatchAction::Stop("playback was stopped"),
+    (PlayerState::Idle, Some(IdleReason::Interrupted)) => {
+      WatchAction::Stop("other media was loaded")
+    }
+    (PlayerState::Idle, None) => {
+      *idle_polls += 1;
+      if *idle_polls > 1 {
+        WatchAction::Restart("the player is stuck idle")
+      } else {
+        WatchAction::Continue
+      }
+    }
+    (PlayerState::Playing | PlayerState::Buffering | PlayerState::Paused, _) => {
+      *idle_polls = 0;
+      WatchAction::Continue
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::Value;
+
+  use super::*;
+
+  const URL: &str = "https://example.com/live/index.m3u8";
+
+  fn session(player_state: &str, idle_reason: Option<&str>) -> Value {
+    json!([{
+      "mediaSessionId": 1,
+      "media": { "contentId": URL, "streamType": "LIVE" },
+      "playerState": player_state,
+      "idleReason": idle_reason,
+    }])
+  }
+
+  fn action(status: Value, idle_polls: &mut u32) -> String {
+    match next_action(&MediaStatus::from_reply(status), URL, idle_polls) {
+      WatchAction::Continue => "continue".to_string(),
+      WatchAction::Restart(reason) => format!("restart: {reason}"),
+      WatchAction::Stop(reason) => format!("stop: {reason}"),
+    }
+  }
+
+  #[test]
+  fn playing_media_is_left_alone() {
+    let mut idle_polls = 1;
+    for state in ["PLAYING", "BUFFERING", "PAUSED"] {
+      assert_eq!(action(session(state, None), &mut idle_polls), "continue");
+    }
+    assert_eq!(idle_polls, 0);
+  }
+
+  #[test]
+  fn media_which_stops_by_itself_is_restarted() {
+    let mut idle_polls = 0;
+    assert_eq!(
+      action(session("IDLE", Some("ERROR")), &mut idle_polls),
+      "restart: playback failed"
+    );
+    assert_eq!(
+      action(session("IDLE", Some("FINISHED")), &mut idle_polls),
+      "restart: the stream ended"
+    );
+    assert_eq!(
+      action(json!([]), &mut idle_polls),
+      "restart: the media session ended"
+    );
+  }
+
+  #[test]
+  fn idle_players_get_one_more_check_to_start() {
+    let mut idle_polls = 0;
+    assert_eq!(action(session("IDLE", None), &mut idle_polls), "continue");
+    assert_eq!(
+      action(session("IDLE", None), &mut idle_polls),
+      "restart: the player is stuck idle"
+    );
+  }
+
+  #[test]
+  fn watching_stops_when_something_else_takes_over() {
+    let mut idle_polls = 0;
+    assert_eq!(
+      action(session("IDLE", Some("CANCELLED")), &mut idle_polls),
+      "stop: playback was stopped"
+    );
+    assert_eq!(
+      action(session("IDLE", Some("INTERRUPTED")), &mut idle_polls),
+      "stop: other media was loaded"
+    );
+
+    let other_media = json!([{
+      "mediaSessionId": 2,
+      "media": { "contentId": "https://example.com/other.mp4" },
+      "playerState": "PLAYING",
+    }]);
+    assert_eq!(
+      action(other_media, &mut idle_polls),
+      "stop: other media was loaded"
+    );
+  }
+}
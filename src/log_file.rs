@@ -0,0 +1,248 @@
+//! Writes logs to a file which is rotated by size or age.
+//!
+//! When the file is rotated it is renamed to `<path>.1`, shifting older files to `<path>.2` and so
+//! on, and files beyond `max_files` are deleted.
+
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, Write},
+  path::{Path, PathBuf},
+  sync::{Mutex, PoisonError},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::{LogFileSettings, RotationPeriod};
+
+/// A log file shared by every log line. Lines are written with a single write each, so they are
+/// never split between files.
+pub struct RotatingFile {
+  settings: LogFileSettings,
+  current: Mutex<CurrentFile>,
+}
+
+struct CurrentFile {
+  file: File,
+  /// The size of the file in bytes.
+  size: u64,
+  /// The rotation period the file was started in.
+  period: u64,
+}
+
+impl RotatingFile {
+  /// Opens the log file, appending to it if it already exists.
+  pub fn open(settings: LogFileSettings) -> io::Result<Self> {
+    if let Some(parent) = settings.path.parent()
+      && !parent.as_os_str().is_empty()
+    {
+      fs::create_dir_all(parent)?;
+    }
+    let file = open_append(&settings.path)?;
+    let metadata = file.metadata()?;
+    // A file left over from before a restart belongs to the period it was last written in
+    let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+    let current = CurrentFile {
+      size: metadata.len(),
+      period: period_of(settings.rotate_every, modified),
+      file,
+    };
+
+    Ok(Self {
+      settings,
+      current: Mutex::new(current),
+    })
+  }
+
+  /// Whether `current` must be rotated before `incoming` more bytes are written to it.
+  fn needs_rotation(&self, current: &CurrentFile, incoming: usize) -> bool {
+    let too_large = self.settings.max_size_mb.is_some_and(|max_size_mb| {
+      current.size > 0 && current.size + incoming as u64 > max_size_mb * 1024 * 1024
+    });
+    let expired = period_of(self.settings.rotate_every, SystemTime::now()) != current.period;
+    too_large || expired
+  }
+
+  /// Moves the current file aside and starts a new one.
+  fn rotate(&self, current: &mut CurrentFile) -> io::Result<()> {
+    current.file.flush()?;
+
+    let path = &self.settings.path;
+    let max_files = self.settings.max_files;
+    if max_files == 0 {
+      fs::remove_file(path)?;
+    } else {
+      // Deleting a file that doesn't exist yet is fine
+      let _ = fs::remove_file(rotated_path(path, max_files));
+      for index in (1..max_files).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+          fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+      }
+      fs::rename(path, rotated_path(path, 1))?;
+    }
+
+    current.file = open_append(path)?;
+    current.size = 0;
+    current.period = period_of(self.settings.rotate_every, SystemTime::now());
+    Ok(())
+  }
+}
+
+impl Write for &RotatingFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+    if self.needs_rotation(&current, buf.len())
+      && let Err(err) = self.rotate(&mut current)
+    {
+      // Keep logging to the current file rather than losing lines
+      eprintln!(
+        "Failed to rotate log file {}: {err}",
+        self.settings.path.display()
+      );
+      // Wait for the next period or another full file before trying again
+      current.period = period_of(self.settings.rotate_every, SystemTime::now());
+      current.size = 0;
+    }
+
+    let written = current.file.write(buf)?;
+    current.size += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self
+      .current
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .file
+      .flush()
+  }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+  OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The path of the `index`th most recent rotated file.
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+  let mut rotated = path.as_os_str().to_owned();
+  rotated.push(format!(".{index}"));
+  PathBuf::from(rotated)
+}
+
+/// The number of whole rotation periods between the Unix epoch and `time`, or `0` when files
+/// aren't rotated by age.
+fn period_of(rotate_every: Option<RotationPeriod>, time: SystemTime) -> u64 {
+  let seconds = time
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_secs())
+    .unwrap_or_default();
+  match rotate_every {
+    Some(RotationPeriod::Hourly) => seconds / (60 * 60),
+    Some(RotationPeriod::Daily) => seconds / (24 * 60 * 60),
+    None => 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A write just over half the 1 MB limit, so that every second write rotates the file.
+  const HALF: usize = 600 * 1024;
+
+  fn settings(path: &Path, max_files: usize) -> LogFileSettings {
+    LogFileSettings {
+      path: path.to_path_buf(),
+      max_size_mb: Some(1),
+      rotate_every: None,
+      max_files,
+    }
+  }
+
+  fn write(file: &RotatingFile, byte: u8) {
+    (&*file).write_all(&vec![byte; HALF]).unwrap();
+  }
+
+  /// The byte each of `path`'s rotated files was filled with, newest first, ending with the first
+  /// missing file.
+  fn contents(path: &Path) -> Vec<Option<u8>> {
+    (0..4)
+      .map(|index| {
+        let path = match index {
+          0 => path.to_path_buf(),
+          index => rotated_path(path, index),
+        };
+        fs::read(path).ok().map(|contents| contents[0])
+      })
+      .collect()
+  }
+
+  #[test]
+  fn files_are_rotated_once_they_would_grow_too_large() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("logs/castiel.log");
+    let file = RotatingFile::open(settings(&path, 2)).unwrap();
+
+    write(&file, b'a');
+    assert_eq!(contents(&path), [Some(b'a'), None, None, None]);
+    write(&file, b'b');
+    assert_eq!(contents(&path), [Some(b'b'), Some(b'a'), None, None]);
+    write(&file, b'c');
+    write(&file, b'd');
+    // Only `max_files` rotated files are kept
+    assert_eq!(contents(&path), [Some(b'd'), Some(b'c'), Some(b'b'), None]);
+    assert_eq!(fs::metadata(&path).unwrap().len(), HALF as u64);
+  }
+
+  #[test]
+  fn files_are_deleted_on_rotation_when_none_are_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("castiel.log");
+    let file = RotatingFile::open(settings(&path, 0)).unwrap();
+
+    write(&file, b'a');
+    write(&file, b'b');
+    assert_eq!(contents(&path), [Some(b'b'), None, None, None]);
+  }
+
+  #[test]
+  fn reopened_files_keep_their_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("castiel.log");
+    write(&RotatingFile::open(settings(&path, 2)).unwrap(), b'a');
+
+    write(&RotatingFile::open(settings(&path, 2)).unwrap(), b'b');
+    assert_eq!(contents(&path), [Some(b'b'), Some(b'a'), None, None]);
+  }
+
+  #[test]
+  fn files_are_rotated_when_their_period_ends() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("castiel.log");
+    let file = RotatingFile::open(LogFileSettings {
+      max_size_mb: None,
+      rotate_every: Some(RotationPeriod::Hourly),
+      ..settings(&path, 2)
+    })
+    .unwrap();
+
+    (&file).write_all(b"a").unwrap();
+    (&file).write_all(b"a").unwrap();
+    assert_eq!(contents(&path), [Some(b'a'), None, None, None]);
+
+    // Pretend the file was started in the previous hour
+    file.current.lock().unwrap().period -= 1;
+    (&file).write_all(b"b").unwrap();
+    assert_eq!(contents(&path), [Some(b'b'), Some(b'a'), None, None]);
+    assert_eq!(fs::read(rotated_path(&path, 1)).unwrap(), b"aa");
+  }
+
+  #[test]
+  fn periods_count_whole_hours_or_days() {
+    let time = UNIX_EPOCH + std::time::Duration::from_secs(3 * 24 * 60 * 60 + 5 * 60 * 60 + 59);
+    assert_eq!(period_of(Some(RotationPeriod::Hourly), time), 3 * 24 + 5);
+    assert_eq!(period_of(Some(RotationPeriod::Daily), time), 3);
+    assert_eq!(period_of(None, time), 0);
+  }
+}
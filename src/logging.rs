@@ -1,12 +1,186 @@
 //! Contains code for initializing logging in Castiel.
 
-use std::str::FromStr;
-use tracing::level_filters::LevelFilter;
+use std::{
+  fmt,
+  sync::{Arc, PoisonError, RwLock},
+};
 
-pub fn init_logging(log_level: &str) {
-  let level_filter = LevelFilter::from_str(log_level).unwrap_or(LevelFilter::INFO);
+use tracing::Subscriber;
+use tracing_subscriber::{
+  EnvFilter, Layer, Registry,
+  field::RecordFields,
+  fmt::{
+    FormatFields, MakeWriter,
+    format::{DefaultFields, Writer},
+  },
+  layer::SubscriberExt,
+  registry::LookupSpan,
+  reload,
+  util::SubscriberInitExt,
+};
 
-  tracing_subscriber::fmt()
-    .with_max_level(level_filter)
+use crate::{
+  config::{CastielSettings, LogFormat},
+  log_file::RotatingFile,
+};
+
+/// A handle used to inspect and change the active log filter after logging has been initialized.
+#[derive(Clone)]
+pub struct LogReloadHandle {
+  reload: reload::Handle<EnvFilter, Registry>,
+  /// The active filter, as it was given.
+  current: Arc<RwLock<String>>,
+}
+
+impl LogReloadHandle {
+  /// The active log filter.
+  pub fn current(&self) -> String {
+    self
+      .current
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone()
+  }
+
+  /// Replaces the active log filter with `filter`, leaving it unchanged if `filter` is invalid.
+  pub fn set(&self, filter: &str) -> Result<(), String> {
+    let filter = normalize_filter(filter);
+    let env_filter = parse_filter(&filter)?;
+    self
+      .reload
+      .reload(env_filter)
+      .map_err(|err| format!("failed to change the log filter: {err}"))?;
+    *self.current.write().unwrap_or_else(PoisonError::into_inner) = filter;
+    Ok(())
+  }
+}
+
+pub fn init_logging(settings: &CastielSettings) -> LogReloadHandle {
+  // As with the log file, an invalid filter is reported once logging is running
+  let (env_filter, current, filter_error) = match parse_filter(&settings.log_level) {
+    Ok(env_filter) => (env_filter, normalize_filter(&settings.log_level), None),
+    Err(err) => (EnvFilter::new("info"), "info".to_string(), Some(err)),
+  };
+  let (filter, reload_handle) = reload::Layer::new(env_filter);
+
+  // Likewise a failure to open the log file
+  let (log_file, log_file_error) = match settings.log_file.clone() {
+    Some(log_file) => {
+      let path = log_file.path.clone();
+      match RotatingFile::open(log_file) {
+        Ok(file) => (Some(Arc::new(file)), None),
+        Err(err) => (None, Some((path, err))),
+      }
+    }
+    None => (None, None),
+  };
+
+  tracing_subscriber::registry()
+    .with(filter)
+    .with(output_layer(settings.log_format, std::io::stdout, true))
+    .with(log_file.map(|file| output_layer(settings.log_format, file, false)))
     .init();
+
+  if let Some(err) = filter_error {
+    tracing::warn!(
+      "Invalid log_level {:?}, logging at info instead: {err}",
+      settings.log_level
+    );
+  }
+  if let Some((path, err)) = log_file_error {
+    tracing::warn!(
+      "Failed to open log file {}, logging to stdout only: {err}",
+      path.display()
+    );
+  }
+
+  LogReloadHandle {
+    reload: reload_handle,
+    current: Arc::new(RwLock::new(current)),
+  }
+}
+
+/// Creates a layer writing log lines in `format` to `writer`. Text written to stdout is coloured.
+fn output_layer<S, W>(format: LogFormat, writer: W, stdout: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+  W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+  let layer = tracing_subscriber::fmt::layer()
+    .with_writer(writer)
+    .with_ansi(stdout);
+  match format {
+    LogFormat::Text if stdout => layer.boxed(),
+    LogFormat::Text => layer.fmt_fields(FileFields::default()).boxed(),
+    // Each line holds the event's fields, the innermost span's fields in `span` and those of
+    // every enclosing span, outermost first, in `spans`
+    LogFormat::Json => layer
+      .json()
+      .with_current_span(true)
+      .with_span_list(true)
+      .boxed(),
+  }
+}
+
+/// Parses a log filter made of comma-separated directives.
+///
+/// Each directive is either a level applying to every target, such as `info`, a target and the
+/// level for it and the modules under it, such as `castiel::devices=trace`, or a level for
+/// everything logged within matching spans, such as `[request{route=/api/start-media}]=debug`.
+/// A target on its own enables every level for it. See [`EnvFilter`] for the full syntax.
+///
+/// Span fields are matched against the values a span starts with, so a request's `device`, which
+/// is only recorded once its handler has read the request, can't be filtered on.
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+  let filter = normalize_filter(filter);
+  if filter.is_empty() {
+    return Err("the log filter is empty".to_string());
+  }
+  EnvFilter::builder()
+    .parse(&filter)
+    .map_err(|err| err.to_string())
+}
+
+/// Removes whitespace and empty directives from `filter`.
+fn normalize_filter(filter: &str) -> String {
+  filter
+    .split(',')
+    .map(str::trim)
+    .filter(|directive| !directive.is_empty())
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Formats span fields for the log file like [`DefaultFields`] does.
+///
+/// Layers store the formatted fields of each span by the type of their field formatter, so the
+/// file layer needs its own type to avoid reusing the coloured fields stored for stdout.
+#[derive(Default)]
+struct FileFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for FileFields {
+  fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+    self.0.format_fields(writer, fields)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn filters_accept_levels_targets_and_spans() {
+    for filter in [
+      "INFO",
+      "info,castiel::devices=trace,mdns_sd=warn",
+      "warn,[request{route=/api/start-media}]=debug",
+      " info, ,castiel ",
+    ] {
+      assert!(parse_filter(filter).is_ok(), "{filter}");
+    }
+    assert_eq!(normalize_filter(" info, ,castiel "), "info,castiel");
+    assert!(parse_filter(" , ").is_err());
+    assert!(parse_filter("info,[request").is_err());
+    assert!(parse_filter("castiel=loud").is_err());
+  }
 }
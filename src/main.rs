@@ -1,46 +1,130 @@
 //! Main entry point for Castiel.
 
+mod aggregate;
+mod audit;
+mod auth;
+mod cast;
+mod clock;
 mod config;
 mod devices;
 mod errors;
+mod extract;
+mod health;
+mod live;
+mod log_file;
 mod logging;
+mod metrics;
+mod mqtt;
+mod presets;
+mod relay;
+mod reload;
+mod request_tracing;
 mod routes;
+mod state;
+mod storage;
+mod tls;
+mod webhooks;
 
 use std::path::Path;
 
 use tokio::net::TcpListener;
 
+use audit::ClientAddr;
 use config::CastielSettings;
+use state::AppState;
 
 const DEFAULT_CONFIG_PATH: &str = "Settings.toml";
 
 #[tokio::main]
 async fn main() {
   // Load settings from file
-  let settings =
-    CastielSettings::initialize(Path::new(DEFAULT_CONFIG_PATH)).unwrap_or_else(|err| {
-      tracing::warn!("Failed to load settings: {err}");
-      CastielSettings::default()
-    });
+  let config_path = Path::new(DEFAULT_CONFIG_PATH);
+  let settings = CastielSettings::initialize(config_path).unwrap_or_else(|err| {
+    tracing::warn!("Failed to load settings: {err}");
+    CastielSettings::default()
+  });
 
-  logging::init_logging(&settings.log_level);
+  let log_handle = logging::init_logging(&settings);
   tracing::info!("Launching Castiel server");
 
+  // Both rust_cast and reqwest use rustls, which needs a process-wide crypto provider
+  let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+  // Share settings with handlers and apply later edits to the settings file live
+  let (host, port) = (settings.host.clone(), settings.port);
+  let tls_settings = settings.tls.clone();
+  let relay_port = settings.relay.as_ref().map(|relay| relay.port);
+  let state = AppState::new(config_path.to_path_buf(), settings, log_handle);
+  devices::pinning::install(state.device_pins.clone());
+  reload::spawn_config_watcher(state.clone());
+  aggregate::spawn_status_refresher(state.clone());
+  mqtt::spawn(state.clone());
+
+  // Serve relayed media on every interface, so that devices can reach it
+  if let Some(relay_port) = relay_port {
+    let relay_addr = format!("0.0.0.0:{relay_port}");
+    let relay_listener = TcpListener::bind(&relay_addr)
+      .await
+      .unwrap_or_else(|_| panic!("Failed to bind to {relay_addr}"));
+    tracing::info!("Relaying media on {relay_addr}");
+
+    let relay_app = relay::create_router(state.clone());
+    tokio::spawn(async move {
+      if let Err(err) = axum::serve(relay_listener, relay_app).await {
+        tracing::error!("Relay listener failed: {err}");
+      }
+    });
+  }
+
   // Create Axum Router
-  let app = routes::create_router();
+  let app = routes::create_router(state);
 
   // Bind TCP port indicated in settings
-  let listener_addr = format!("127.0.0.1:{}", settings.port);
-  let listener = TcpListener::bind(&listener_addr)
+  let listener = TcpListener::bind((host.as_str(), port))
     .await
-    .unwrap_or_else(|_| panic!("Failed to bind to {listener_addr}"));
+    .unwrap_or_else(|_| panic!("Failed to bind to {host}:{port}"));
+  let local_addr = listener
+    .local_addr()
+    .expect("Failed to retrieve local address");
+
+  let Some(tls_settings) = tls_settings else {
+    // Log and begin serving
+    tracing::info!("Listening on http://{local_addr}");
+    axum::serve(
+      listener,
+      app.into_make_service_with_connect_info::<ClientAddr>(),
+    )
+    .await
+    .unwrap();
+    return;
+  };
+
+  // Redirect plain HTTP to HTTPS on a separate port, if asked to
+  if let Some(redirect_port) = tls_settings.redirect_port {
+    let redirect_listener = TcpListener::bind((host.as_str(), redirect_port))
+      .await
+      .unwrap_or_else(|_| panic!("Failed to bind to {host}:{redirect_port}"));
+    tracing::info!("Redirecting http://{host}:{redirect_port} to HTTPS");
+
+    let redirect_app = tls::create_redirect_router(port);
+    tokio::spawn(async move {
+      if let Err(err) = axum::serve(redirect_listener, redirect_app).await {
+        tracing::error!("HTTPS redirect listener failed: {err}");
+      }
+    });
+  }
+
+  let tls_config = tls::load_server_config(&tls_settings)
+    .unwrap_or_else(|err| panic!("Failed to load TLS certificate: {err}"));
+  let listener =
+    tls::TlsListener::new(listener, tls_config).expect("Failed to retrieve local address");
 
   // Log and begin serving
-  tracing::info!(
-    "Listening on {}",
-    listener
-      .local_addr()
-      .expect("Failed to retrieve local address")
-  );
-  axum::serve(listener, app).await.unwrap();
+  tracing::info!("Listening on https://{local_addr}");
+  axum::serve(
+    listener,
+    app.into_make_service_with_connect_info::<ClientAddr>(),
+  )
+  .await
+  .unwrap();
 }
@@ -0,0 +1,480 @@
+//! Collects metrics about Castiel and the devices it manages, served to Prometheus at `/metrics`.
+//!
+//! Counters and histograms are updated as API requests, Cast operations and discovery scans
+//! happen. Per-device gauges are read from the background status snapshot when one is kept, or
+//! collected when Prometheus scrapes otherwise. Metrics are written in the Prometheus text
+//! exposition format.
+
+use std::{
+  collections::BTreeMap,
+  fmt::Write,
+  sync::{LazyLock, Mutex, PoisonError},
+  time::{Duration, Instant},
+};
+
+use axum::{
+  extract::{MatchedPath, Request},
+  middleware::Next,
+  response::Response,
+};
+
+use crate::{
+  aggregate::{self, StatusSnapshot},
+  auth::Caller,
+  devices::status::PlayerState,
+  state::AppState,
+};
+
+/// The metrics updated as things happen, shared by the whole process.
+static METRICS: LazyLock<Mutex<MetricSet>> = LazyLock::new(Mutex::default);
+
+/// The upper bounds of histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Every metric Castiel reports, with its type and help text, in the order they are written.
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+  (
+    "castiel_http_requests_total",
+    "counter",
+    "API and UI requests handled, by route and response status.",
+  ),
+  (
+    "castiel_http_request_duration_seconds",
+    "histogram",
+    "Time taken to handle API and UI requests.",
+  ),
+  (
+    "castiel_cast_operations_total",
+    "counter",
+    "Requests sent to devices over the Cast protocol, by message type and result.",
+  ),
+  (
+    "castiel_cast_operation_duration_seconds",
+    "histogram",
+    "Time taken for devices to answer Cast requests.",
+  ),
+  (
+    "castiel_device_connections_total",
+    "counter",
+    "Connections opened to devices, by result. Castiel connects afresh for every operation.",
+  ),
+  (
+    "castiel_media_restarts_total",
+    "counter",
+    "Media reloaded by auto_restart after playback failed or stopped, by result.",
+  ),
+  (
+    "castiel_discovery_scans_total",
+    "counter",
+    "mDNS discovery scans run, by result.",
+  ),
+  (
+    "castiel_discovery_scan_duration_seconds",
+    "histogram",
+    "Time taken by mDNS discovery scans.",
+  ),
+  (
+    "castiel_discovered_devices",
+    "gauge",
+    "Devices found by the most recent discovery scan.",
+  ),
+  (
+    "castiel_known_devices",
+    "gauge",
+    "Devices Castiel knows about, including those remembered from earlier runs.",
+  ),
+  (
+    "castiel_device_up",
+    "gauge",
+    "Whether the device answered the most recent status request.",
+  ),
+  (
+    "castiel_device_volume",
+    "gauge",
+    "The device volume, from 0 to 1.",
+  ),
+  (
+    "castiel_device_muted",
+    "gauge",
+    "Whether the device is muted.",
+  ),
+  (
+    "castiel_device_app_running",
+    "gauge",
+    "Set to 1 for each app running on the device.",
+  ),
+  (
+    "castiel_media_player_state",
+    "gauge",
+    "Set to 1 for the state of the foreground app's player, and 0 for the other states.",
+  ),
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+/// A set of metric values, keyed by metric name and labels.
+#[derive(Default)]
+struct MetricSet {
+  counters: BTreeMap<(&'static str, Labels), u64>,
+  gauges: BTreeMap<(&'static str, Labels), f64>,
+  histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+  /// The number of observations in each bucket of [`BUCKETS`], not counting smaller buckets.
+  buckets: [u64; BUCKETS.len()],
+  count: u64,
+  sum: f64,
+}
+
+impl MetricSet {
+  fn increment(&mut self, name: &'static str, labels: Labels) {
+    *self.counters.entry((name, labels)).or_default() += 1;
+  }
+
+  fn set(&mut self, name: &'static str, labels: Labels, value: f64) {
+    self.gauges.insert((name, labels), value);
+  }
+
+  fn observe(&mut self, name: &'static str, labels: Labels, elapsed: Duration) {
+    let histogram = self.histograms.entry((name, labels)).or_default();
+    let seconds = elapsed.as_secs_f64();
+    if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+      histogram.buckets[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+  }
+
+  /// Writes every metric in `self` and `extra` in the text exposition format.
+  fn render(&self, extra: &MetricSet) -> String {
+    let mut output = String::new();
+    for (name, kind, help) in DESCRIPTIONS {
+      let _ = writeln!(output, "# HELP {name} {help}");
+      let _ = writeln!(output, "# TYPE {name} {kind}");
+
+      for set in [self, extra] {
+        for ((_, labels), value) in set
+          .counters
+          .iter()
+          .filter(|((series, _), _)| series == name)
+        {
+          let _ = writeln!(output, "{name}{} {value}", format_labels(labels, None));
+        }
+        for ((_, labels), value) in set.gauges.iter().filter(|((series, _), _)| series == name) {
+          let _ = writeln!(output, "{name}{} {value}", format_labels(labels, None));
+        }
+        for ((_, labels), histogram) in set
+          .histograms
+          .iter()
+          .filter(|((series, _), _)| series == name)
+        {
+          let mut cumulative = 0;
+          for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let le = bound.to_string();
+            let _ = writeln!(
+              output,
+              "{name}_bucket{} {cumulative}",
+              format_labels(labels, Some(&le))
+            );
+          }
+          let _ = writeln!(
+            output,
+            "{name}_bucket{} {}",
+            format_labels(labels, Some("+Inf")),
+            histogram.count
+          );
+          let _ = writeln!(
+            output,
+            "{name}_sum{} {}",
+            format_labels(labels, None),
+            histogram.sum
+          );
+          let _ = writeln!(
+            output,
+            "{name}_count{} {}",
+            format_labels(labels, None),
+            histogram.count
+          );
+        }
+      }
+    }
+    output
+  }
+}
+
+/// Formats `labels` as `{name="value",...}`, adding the `le` label of a histogram bucket if given.
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+  let mut pairs: Vec<String> = labels
+    .iter()
+    .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+    .collect();
+  if let Some(le) = le {
+    pairs.push(format!("le=\"{le}\""));
+  }
+  if pairs.is_empty() {
+    String::new()
+  } else {
+    format!("{{{}}}", pairs.join(","))
+  }
+}
+
+fn escape_label(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+fn with_metrics(update: impl FnOnce(&mut MetricSet)) {
+  update(&mut METRICS.lock().unwrap_or_else(PoisonError::into_inner));
+}
+
+/// A `result` label value: `ok`, `timeout` or `error`.
+pub fn result_label<T, E>(result: &Result<T, E>, is_timeout: impl Fn(&E) -> bool) -> &'static str {
+  match result {
+    Ok(_) => "ok",
+    Err(err) if is_timeout(err) => "timeout",
+    Err(_) => "error",
+  }
+}
+
+/// Records a request sent to a device, where `operation` is the message type, e.g. `LOAD`.
+pub fn observe_cast_operation(operation: &str, result: &'static str, elapsed: Duration) {
+  with_metrics(|metrics| {
+    metrics.increment(
+      "castiel_cast_operations_total",
+      vec![
+        ("operation", operation.to_string()),
+        ("result", result.to_string()),
+      ],
+    );
+    metrics.observe(
+      "castiel_cast_operation_duration_seconds",
+      vec![("operation", operation.to_string())],
+      elapsed,
+    );
+  });
+}
+
+/// Records an attempt to connect to a device.
+pub fn observe_device_connection(result: &'static str) {
+  with_metrics(|metrics| {
+    metrics.increment(
+      "castiel_device_connections_total",
+      vec![("result", result.to_string())],
+    );
+  });
+}
+
+/// Records an attempt by `auto_restart` to reload media.
+pub fn observe_media_restart(result: &'static str) {
+  with_metrics(|metrics| {
+    metrics.increment(
+      "castiel_media_restarts_total",
+      vec![("result", result.to_string())],
+    );
+  });
+}
+
+/// Records a discovery scan, along with the number of devices it found if it succeeded.
+pub fn observe_discovery_scan(devices_found: Option<usize>, elapsed: Duration) {
+  let result = if devices_found.is_some() {
+    "ok"
+  } else {
+    "error"
+  };
+  with_metrics(|metrics| {
+    metrics.increment(
+      "castiel_discovery_scans_total",
+      vec![("result", result.to_string())],
+    );
+    metrics.observe(
+      "castiel_discovery_scan_duration_seconds",
+      Vec::new(),
+      elapsed,
+    );
+    if let Some(devices_found) = devices_found {
+      metrics.set(
+        "castiel_discovered_devices",
+        Vec::new(),
+        devices_found as f64,
+      );
+    }
+  });
+}
+
+/// Middleware recording the route, status and duration of every request.
+///
+/// Requests which don't match an API route, such as those for the UI's files, are grouped under
+/// a single `fallback` route.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+  let method = request.method().to_string();
+  let route = request
+    .extensions()
+    .get::<MatchedPath>()
+    .map_or("fallback", MatchedPath::as_str)
+    .to_string();
+
+  let started = Instant::now();
+  let response = next.run(request).await;
+  let elapsed = started.elapsed();
+
+  with_metrics(|metrics| {
+    metrics.increment(
+      "castiel_http_requests_total",
+      vec![
+        ("method", method.clone()),
+        ("route", route.clone()),
+        ("status", response.status().as_u16().to_string()),
+      ],
+    );
+    metrics.observe(
+      "castiel_http_request_duration_seconds",
+      vec![("method", method), ("route", route)],
+      elapsed,
+    );
+  });
+
+  response
+}
+
+/// Writes every metric, with per-device gauges for the devices `caller` may access.
+pub async fn render(state: &AppState, caller: &Caller) -> String {
+  let snapshot = aggregate::latest_status(state).await;
+
+  let mut device_metrics = MetricSet::default();
+  device_metrics.set(
+    "castiel_known_devices",
+    Vec::new(),
+    state.devices.devices().len() as f64,
+  );
+  add_device_gauges(&mut device_metrics, &snapshot, caller);
+
+  METRICS
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .render(&device_metrics)
+}
+
+/// Adds the gauges describing each device in `snapshot` to `metrics`.
+fn add_device_gauges(metrics: &mut MetricSet, snapshot: &StatusSnapshot, caller: &Caller) {
+  for report in &snapshot.devices {
+    let device = &report.device;
+    if !caller.can_access(&device.ip_address, device.port) {
+      continue;
+    }
+    let labels = vec![
+      ("address", format!("{}:{}", device.ip_address, device.port)),
+      ("name", device.friendly_name.clone().unwrap_or_default()),
+    ];
+
+    metrics.set(
+      "castiel_device_up",
+      labels.clone(),
+      f64::from(u8::from(report.device_status.is_some())),
+    );
+    let Some(device_status) = &report.device_status else {
+      continue;
+    };
+
+    let volume = device_status.volume();
+    metrics.set(
+      "castiel_device_volume",
+      labels.clone(),
+      f64::from(volume.level()),
+    );
+    metrics.set(
+      "castiel_device_muted",
+      labels.clone(),
+      f64::from(u8::from(volume.muted())),
+    );
+    for app in device_status.applications() {
+      let mut app_labels = labels.clone();
+      app_labels.push(("app_id", app.id().to_string()));
+      app_labels.push(("app", app.display_name().to_string()));
+      metrics.set("castiel_device_app_running", app_labels, 1.0);
+    }
+
+    if let Some(media_status) = &report.media_status {
+      let current = media_status.player_state();
+      for state in [
+        PlayerState::Idle,
+        PlayerState::Playing,
+        PlayerState::Buffering,
+        PlayerState::Paused,
+      ] {
+        let mut state_labels = labels.clone();
+        state_labels.push(("state", format!("{state:?}").to_uppercase()));
+        metrics.set(
+          "castiel_media_player_state",
+          state_labels,
+          f64::from(u8::from(state == current)),
+        );
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn every_metric_is_described() {
+    let output = MetricSet::default().render(&MetricSet::default());
+    for (name, kind, help) in DESCRIPTIONS {
+      assert!(output.contains(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n")));
+    }
+  }
+
+  #[test]
+  fn label_values_are_escaped() {
+    let mut metrics = MetricSet::default();
+    metrics.set(
+      "castiel_device_up",
+      vec![("name", "Living \"room\"\\TV\nleft".to_string())],
+      1.0,
+    );
+    let output = metrics.render(&MetricSet::default());
+    assert!(output.contains("castiel_device_up{name=\"Living \\\"room\\\"\\\\TV\\nleft\"} 1\n"));
+  }
+
+  #[test]
+  fn histograms_have_cumulative_buckets() {
+    let mut metrics = MetricSet::default();
+    let labels = vec![("operation", "LOAD".to_string())];
+    let name = "castiel_cast_operation_duration_seconds";
+    metrics.observe(name, labels.clone(), Duration::from_millis(20));
+    metrics.observe(name, labels.clone(), Duration::from_millis(300));
+    metrics.observe(name, labels, Duration::from_secs(60));
+    let output = metrics.render(&MetricSet::default());
+
+    for line in [
+      "castiel_cast_operation_duration_seconds_bucket{operation=\"LOAD\",le=\"0.01\"} 0",
+      "castiel_cast_operation_duration_seconds_bucket{operation=\"LOAD\",le=\"0.025\"} 1",
+      "castiel_cast_operation_duration_seconds_bucket{operation=\"LOAD\",le=\"0.5\"} 2",
+      "castiel_cast_operation_duration_seconds_bucket{operation=\"LOAD\",le=\"30\"} 2",
+      "castiel_cast_operation_duration_seconds_bucket{operation=\"LOAD\",le=\"+Inf\"} 3",
+      "castiel_cast_operation_duration_seconds_sum{operation=\"LOAD\"} 60.32",
+      "castiel_cast_operation_duration_seconds_count{operation=\"LOAD\"} 3",
+    ] {
+      assert!(
+        output.lines().any(|written| written == line),
+        "missing {line}"
+      );
+    }
+  }
+
+  #[test]
+  fn metrics_without_labels_have_no_braces() {
+    let mut extra = MetricSet::default();
+    extra.set("castiel_known_devices", Vec::new(), 2.0);
+    let output = MetricSet::default().render(&extra);
+    assert!(output.lines().any(|line| line == "castiel_known_devices 2"));
+  }
+}
@@ -0,0 +1,499 @@
+//! A minimal MQTT 3.1.1 client, covering what Castiel needs: publishing messages and receiving
+//! the messages published to the topics it subscribes to.
+//!
+//! Castiel publishes at QoS 0 and subscribes at QoS 0, so no messages need to be stored for
+//! redelivery. See <https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/mqtt-v3.1.1.html>.
+
+use std::{io, time::Duration};
+
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+  net::{
+    TcpStream,
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+  },
+};
+
+use crate::config::MqttSettings;
+
+/// How long to wait for the broker to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest packet accepted from the broker. Castiel only expects short commands.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// The largest remaining length the packet header can describe.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+// Packet types, in the upper four bits of the first header byte
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x80;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+/// The return code a broker gives in a SUBACK for a subscription it refused.
+pub const SUBSCRIPTION_FAILED: u8 = 0x80;
+
+/// A message published to a topic.
+#[derive(Debug, Clone)]
+pub struct Message {
+  pub topic: String,
+  pub payload: Vec<u8>,
+  /// Whether the broker keeps the message for clients which subscribe later. Set on received
+  /// messages when they were kept from before the subscription was made.
+  pub retain: bool,
+}
+
+/// A packet received from the broker.
+#[derive(Debug)]
+pub enum Packet {
+  /// A message published to a subscribed topic. Messages sent at QoS 1 carry a packet ID, which
+  /// must be acknowledged.
+  Publish {
+    message: Message,
+    packet_id: Option<u16>,
+  },
+  /// The return code for each topic filter of a subscription.
+  SubAck(Vec<u8>),
+  PingResp,
+  /// Any other packet, which Castiel doesn't need to act on.
+  Other,
+}
+
+/// Reads packets sent by the broker.
+pub struct PacketReader(BufReader<OwnedReadHalf>);
+
+/// Sends packets to the broker.
+pub struct PacketWriter(OwnedWriteHalf);
+
+/// Connects to the broker in `settings`, which publishes `will` if the connection is lost without
+/// a DISCONNECT.
+pub async fn connect(
+  settings: &MqttSettings,
+  will: &Message,
+) -> io::Result<(PacketReader, PacketWriter)> {
+  let connecting = async {
+    let stream = TcpStream::connect((settings.host.as_str(), settings.port)).await?;
+    let (read, write) = stream.into_split();
+    let (mut reader, mut writer) = (PacketReader(BufReader::new(read)), PacketWriter(write));
+
+    writer.send(CONNECT, &connect_body(settings, will)?).await?;
+    let (header, body) = read_packet(&mut reader.0).await?;
+    match (header & 0xF0, body.as_slice()) {
+      (CONNACK, [_, 0]) => Ok((reader, writer)),
+      (CONNACK, [_, code]) => Err(io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        format!(
+          "the broker refused the connection: {}",
+          refusal_reason(*code)
+        ),
+      )),
+      _ => Err(invalid("the broker didn't acknowledge the connection")),
+    }
+  };
+
+  tokio::time::timeout(CONNECT_TIMEOUT, connecting)
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+impl PacketReader {
+  /// Reads the next packet.
+  pub async fn read(&mut self) -> io::Result<Packet> {
+    let (header, body) = read_packet(&mut self.0).await?;
+    match header & 0xF0 {
+      PUBLISH => parse_publish(header, &body),
+      SUBACK => Ok(Packet::SubAck(body.get(2..).unwrap_or_default().to_vec())),
+      PINGRESP => Ok(Packet::PingResp),
+      _ => Ok(Packet::Other),
+    }
+  }
+}
+
+impl PacketWriter {
+  /// Publishes `message` at QoS 0.
+  pub async fn publish(&mut self, message: &Message) -> io::Result<()> {
+    let mut body = Vec::with_capacity(message.topic.len() + message.payload.len() + 2);
+    put_bytes(&mut body, message.topic.as_bytes())?;
+    body.extend_from_slice(&message.payload);
+    self.send(PUBLISH | u8::from(message.retain), &body).await
+  }
+
+  /// Subscribes to every topic matching `filters` at QoS 0.
+  pub async fn subscribe(&mut self, packet_id: u16, filters: &[String]) -> io::Result<()> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    for filter in filters {
+      put_bytes(&mut body, filter.as_bytes())?;
+      body.push(0);
+    }
+    // SUBSCRIBE packets must set the second lowest header bit
+    self.send(SUBSCRIBE | 0x02, &body).await
+  }
+
+  /// Acknowledges a message received at QoS 1.
+  pub async fn acknowledge(&mut self, packet_id: u16) -> io::Result<()> {
+    self.send(PUBACK, &packet_id.to_be_bytes()).await
+  }
+
+  pub async fn ping(&mut self) -> io::Result<()> {
+    self.send(PINGREQ, &[]).await
+  }
+
+  /// Closes the connection, so that the broker doesn't publish the will.
+  pub async fn disconnect(mut self) -> io::Result<()> {
+    self.send(DISCONNECT, &[]).await?;
+    self.0.shutdown().await
+  }
+
+  async fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+    self.0.write_all(&encode_packet(header, body)?).await
+  }
+}
+
+/// Reads the first header byte and the rest of the next packet from `reader`.
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<(u8, Vec<u8>)> {
+  let header = reader.read_u8().await?;
+
+  // The remaining length takes up to four bytes, seven bits at a time
+  let mut length = 0;
+  for shift in [0, 7, 14, 21] {
+    let byte = reader.read_u8().await?;
+    length |= usize::from(byte & 0x7F) << shift;
+    if byte & 0x80 == 0 {
+      break;
+    }
+    if shift == 21 {
+      return Err(invalid("the packet length is malformed"));
+    }
+  }
+  if length > MAX_PACKET_SIZE {
+    return Err(invalid(format!("a packet of {length} bytes is too large")));
+  }
+
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body).await?;
+  Ok((header, body))
+}
+
+/// Builds a packet from its first header byte and its body.
+fn encode_packet(header: u8, body: &[u8]) -> io::Result<Vec<u8>> {
+  if body.len() > MAX_REMAINING_LENGTH {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "the packet is too large",
+    ));
+  }
+
+  let mut packet = Vec::with_capacity(body.len() + 5);
+  packet.push(header);
+  put_remaining_length(&mut packet, body.len());
+  packet.extend_from_slice(body);
+  Ok(packet)
+}
+
+/// Appends the remaining length of a packet, seven bits at a time with the top bit set on every
+/// byte but the last.
+fn put_remaining_length(buffer: &mut Vec<u8>, mut length: usize) {
+  loop {
+    let byte = (length & 0x7F) as u8;
+    length >>= 7;
+    if length == 0 {
+      buffer.push(byte);
+      return;
+    }
+    buffer.push(byte | 0x80);
+  }
+}
+
+/// Builds the body of the CONNECT packet, starting a clean session.
+fn connect_body(settings: &MqttSettings, will: &Message) -> io::Result<Vec<u8>> {
+  // Clean session, and a will published at QoS 1
+  let mut flags = 0x02 | 0x04 | 0x08;
+  if will.retain {
+    flags |= 0x20;
+  }
+  // MQTT 3.1.1 only allows a password along with a username
+  let username = settings.username.as_ref();
+  let password = settings.password.as_ref().filter(|_| username.is_some());
+  if username.is_some() {
+    flags |= 0x80;
+  }
+  if password.is_some() {
+    flags |= 0x40;
+  }
+
+  let mut body = Vec::new();
+  put_bytes(&mut body, b"MQTT")?;
+  body.push(4);
+  body.push(flags);
+  body.extend_from_slice(&settings.keep_alive_seconds.to_be_bytes());
+  put_bytes(&mut body, settings.client_id.as_bytes())?;
+  put_bytes(&mut body, will.topic.as_bytes())?;
+  put_bytes(&mut body, &will.payload)?;
+  for credential in [username, password].into_iter().flatten() {
+    put_bytes(&mut body, credential.as_bytes())?;
+  }
+  Ok(body)
+}
+
+fn parse_publish(header: u8, body: &[u8]) -> io::Result<Packet> {
+  let retain = header & 0x01 != 0;
+  let qos = (header >> 1) & 0x03;
+
+  let mut rest = body;
+  let topic = String::from_utf8(take_bytes(&mut rest)?.to_vec())
+    .map_err(|_| invalid("the topic isn't valid UTF-8"))?;
+  let packet_id = match qos {
+    0 => None,
+    1 => Some(u16::from_be_bytes(take(&mut rest)?)),
+    // Castiel subscribes at QoS 0, which caps what the broker sends
+    _ => return Err(invalid("a message was sent at QoS 2")),
+  };
+
+  Ok(Packet::Publish {
+    message: Message {
+      topic,
+      payload: rest.to_vec(),
+      retain,
+    },
+    packet_id,
+  })
+}
+
+/// Appends `bytes` prefixed with their length, which is how strings are sent.
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+  let length = u16::try_from(bytes.len())
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "a string is too long"))?;
+  buffer.extend_from_slice(&length.to_be_bytes());
+  buffer.extend_from_slice(bytes);
+  Ok(())
+}
+
+/// Removes bytes prefixed with their length from the start of `buffer`.
+fn take_bytes<'a>(buffer: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+  let length = usize::from(u16::from_be_bytes(take(buffer)?));
+  if buffer.len() < length {
+    return Err(invalid("the packet ended early"));
+  }
+  let (bytes, rest) = buffer.split_at(length);
+  *buffer = rest;
+  Ok(bytes)
+}
+
+/// Removes the first `N` bytes of `buffer`.
+fn take<const N: usize>(buffer: &mut &[u8]) -> io::Result<[u8; N]> {
+  let (bytes, rest) = buffer
+    .split_first_chunk::<N>()
+    .ok_or_else(|| invalid("the packet ended early"))?;
+  *buffer = rest;
+  Ok(*bytes)
+}
+
+/// Describes the return code of a refused CONNECT.
+fn refusal_reason(code: u8) -> String {
+  match code {
+    1 => "unsupported protocol version".to_string(),
+    2 => "client ID rejected".to_string(),
+    3 => "server unavailable".to_string(),
+    4 => "bad username or password".to_string(),
+    5 => "not authorized".to_string(),
+    code => format!("return code {code}"),
+  }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn settings(username: Option<&str>, password: Option<&str>) -> MqttSettings {
+    MqttSettings {
+      host: "localhost".to_string(),
+      port: 1883,
+      client_id: "castiel-test".to_string(),
+      username: username.map(str::to_string),
+      password: password.map(str::to_string),
+      topic_prefix: "castiel".to_string(),
+      commands: false,
+      command_devices: Vec::new(),
+      discovery: false,
+      discovery_prefix: "homeassistant".to_string(),
+      keep_alive_seconds: 30,
+    }
+  }
+
+  fn will() -> Message {
+    Message {
+      topic: "castiel/status".to_string(),
+      payload: b"offline".to_vec(),
+      retain: true,
+    }
+  }
+
+  /// Prefixes `bytes` with their length, as strings are sent.
+  fn string(bytes: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    put_bytes(&mut buffer, bytes).unwrap();
+    buffer
+  }
+
+  #[test]
+  fn remaining_lengths_are_encoded_seven_bits_at_a_time() {
+    // The examples in section 2.2.3 of the specification
+    let cases: [(usize, &[u8]); 8] = [
+      (0, &[0x00]),
+      (127, &[0x7F]),
+      (128, &[0x80, 0x01]),
+      (16_383, &[0xFF, 0x7F]),
+      (16_384, &[0x80, 0x80, 0x01]),
+      (2_097_151, &[0xFF, 0xFF, 0x7F]),
+      (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+      (MAX_REMAINING_LENGTH, &[0xFF, 0xFF, 0xFF, 0x7F]),
+    ];
+    for (length, expected) in cases {
+      let mut buffer = Vec::new();
+      put_remaining_length(&mut buffer, length);
+      assert_eq!(buffer, expected, "{length}");
+    }
+  }
+
+  #[tokio::test]
+  async fn packets_are_read_back_as_written() {
+    for length in [0, 127, 128, 16_383, 16_384, MAX_PACKET_SIZE] {
+      let body: Vec<u8> = (0..length).map(|i| i as u8).collect();
+      let packet = encode_packet(PUBLISH, &body).unwrap();
+      let (header, read) = read_packet(&mut packet.as_slice()).await.unwrap();
+      assert_eq!(header, PUBLISH);
+      assert_eq!(read, body, "{length}");
+    }
+  }
+
+  #[tokio::test]
+  async fn malformed_or_oversized_packets_are_refused() {
+    let too_long = [PUBLISH, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+    let err = read_packet(&mut too_long.as_slice()).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let mut too_large = vec![PUBLISH];
+    put_remaining_length(&mut too_large, MAX_PACKET_SIZE + 1);
+    let err = read_packet(&mut too_large.as_slice()).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let truncated = [PUBLISH, 0x05, 0x00];
+    let err = read_packet(&mut truncated.as_slice()).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    assert!(encode_packet(PUBLISH, &vec![0; MAX_REMAINING_LENGTH + 1]).is_err());
+  }
+
+  #[test]
+  fn publishes_are_parsed_at_qos_0_and_1() {
+    let mut body = string(b"castiel/abc/command/play");
+    body.extend_from_slice(b"payload");
+    let Packet::Publish { message, packet_id } = parse_publish(PUBLISH | 0x01, &body).unwrap()
+    else {
+      panic!("expected a PUBLISH");
+    };
+    assert_eq!(message.topic, "castiel/abc/command/play");
+    assert_eq!(message.payload, b"payload");
+    assert!(message.retain);
+    assert_eq!(packet_id, None);
+
+    let mut body = string(b"castiel/abc/command/stop");
+    body.extend_from_slice(&7u16.to_be_bytes());
+    let Packet::Publish { message, packet_id } = parse_publish(PUBLISH | 0x02, &body).unwrap()
+    else {
+      panic!("expected a PUBLISH");
+    };
+    assert!(message.payload.is_empty());
+    assert!(!message.retain);
+    assert_eq!(packet_id, Some(7));
+  }
+
+  #[test]
+  fn malformed_publishes_are_refused() {
+    let body = string(b"castiel/abc/command/play");
+    assert!(parse_publish(PUBLISH | 0x04, &body).is_err());
+    // The topic is longer than the packet
+    assert!(parse_publish(PUBLISH, &body[..10]).is_err());
+    // QoS 1 without a packet ID
+    assert!(parse_publish(PUBLISH | 0x02, &body).is_err());
+    assert!(parse_publish(PUBLISH, &string(&[0xFF, 0xFE])).is_err());
+  }
+
+  #[test]
+  fn connect_bodies_carry_the_will_and_credentials() {
+    let header = |flags: u8| {
+      let mut body = string(b"MQTT");
+      body.extend_from_slice(&[4, flags, 0, 30]);
+      body.extend(string(b"castiel-test"));
+      body.extend(string(b"castiel/status"));
+      body.extend(string(b"offline"));
+      body
+    };
+
+    let body = connect_body(&settings(None, None), &will()).unwrap();
+    assert_eq!(body, header(0x2E));
+
+    let mut expected = header(0xEE);
+    expected.extend(string(b"user"));
+    expected.extend(string(b"secret"));
+    let body = connect_body(&settings(Some("user"), Some("secret")), &will()).unwrap();
+    assert_eq!(body, expected);
+
+    let mut expected = header(0xAE);
+    expected.extend(string(b"user"));
+    let body = connect_body(&settings(Some("user"), None), &will()).unwrap();
+    assert_eq!(body, expected);
+
+    // A password without a username isn't allowed, so it isn't sent
+    let body = connect_body(&settings(None, Some("secret")), &will()).unwrap();
+    assert_eq!(body, header(0x2E));
+  }
+
+  /// Run with `cargo test -- --ignored` while a broker such as mosquitto listens on
+  /// `MQTT_TEST_HOST` (localhost by default) on port 1883.
+  #[tokio::test]
+  #[ignore = "needs an MQTT broker"]
+  async fn messages_round_trip_through_a_broker() {
+    let mut settings = settings(None, None);
+    if let Ok(host) = std::env::var("MQTT_TEST_HOST") {
+      settings.host = host;
+    }
+    let (mut reader, mut writer) = connect(&settings, &will()).await.unwrap();
+
+    let topic = format!("castiel-test/{}", std::process::id());
+    writer
+      .subscribe(1, std::slice::from_ref(&topic))
+      .await
+      .unwrap();
+    let Packet::SubAck(codes) = reader.read().await.unwrap() else {
+      panic!("expected a SUBACK");
+    };
+    assert_eq!(codes, [0]);
+
+    let message = Message {
+      topic: topic.clone(),
+      payload: b"hello".to_vec(),
+      retain: false,
+    };
+    writer.publish(&message).await.unwrap();
+    let Packet::Publish { message, .. } = reader.read().await.unwrap() else {
+      panic!("expected a PUBLISH");
+    };
+    assert_eq!(message.topic, topic);
+    assert_eq!(message.payload, b"hello");
+
+    writer.ping().await.unwrap();
+    assert!(matches!(reader.read().await.unwrap(), Packet::PingResp));
+    writer.disconnect().await.unwrap();
+  }
+}
@@ -0,0 +1,633 @@
+//! Publishes the status of devices to an MQTT broker and takes commands for them from it, for home
+//! automation systems such as Home Assistant.
+//!
+//! Each device has its own topics under `topic_prefix`, named after its Cast device ID:
+//!
+//! - `<prefix>/<device>/state` holds a JSON summary of the device's status.
+//! - `<prefix>/<device>/availability` is `online` while the device answers and `offline` otherwise.
+//! - `<prefix>/<device>/command/<command>` takes the commands `play`, `pause`, `stop`, `volume`,
+//!   with a level from 0 to 1, and `start-media`, with a POST /api/start-media body left without
+//!   `ip_address` and `port`. Commands are only taken with `commands` on.
+//!
+//! `<prefix>/status` is `online` while Castiel is connected, and set to `offline` by the broker
+//! when Castiel drops off. Status is published whenever the background status snapshot is
+//! refreshed, so only while `status_refresh_seconds` is set, and as retained messages, so that
+//! subscribers get the latest status straight away. With `discovery` on, Home Assistant discovery
+//! payloads describe each device as a set of entities.
+//!
+//! Commands aren't authenticated beyond what the broker requires, so Castiel's API roles don't
+//! apply to them. They can be limited to the devices in `command_devices`, and each is recorded
+//! in the audit log, including those refused.
+
+mod client;
+
+use std::{
+  collections::BTreeMap,
+  io,
+  sync::{Mutex, PoisonError},
+  time::Duration,
+};
+
+use serde_json::{Value, json};
+use tokio::{
+  sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+  time::Instant,
+};
+
+use crate::{
+  aggregate::{self, DeviceReport, StatusSnapshot},
+  cast,
+  config::MqttSettings,
+  devices::{
+    AppSelector, DeviceAddress, blocking,
+    discovery::DiscoveredDevice,
+    media::{self, PlaybackCommand, StartMediaData},
+    status::{AppStatus, DeviceStatus, MediaStatus},
+    validation::FieldError,
+  },
+  errors::CastielError,
+  state::AppState,
+};
+use client::{Message, Packet, PacketWriter};
+
+/// How long to wait before reconnecting to the broker the first time. Each further attempt waits
+/// twice as long as the one before.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest wait between attempts to reconnect.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// The connection to the MQTT broker.
+pub struct Mqtt {
+  settings: MqttSettings,
+  /// Messages waiting to be published.
+  outgoing: UnboundedSender<Message>,
+  /// The receiving end of `outgoing`, until the connection task takes it.
+  pending: Mutex<Option<UnboundedReceiver<Message>>>,
+}
+
+impl Mqtt {
+  pub fn new(settings: MqttSettings) -> Self {
+    let (outgoing, pending) = mpsc::unbounded_channel();
+    Self {
+      settings,
+      outgoing,
+      pending: Mutex::new(Some(pending)),
+    }
+  }
+
+  /// Publishes the status of the device in `report`, along with its discovery payloads.
+  pub fn publish_device(&self, report: &DeviceReport) {
+    let key = device_key(&report.device);
+    if self.settings.discovery {
+      self.publish_discovery(&key, &report.device);
+    }
+
+    let availability = match report.device_status {
+      Some(_) => ONLINE,
+      None => OFFLINE,
+    };
+    self.publish(self.topic(&format!("{key}/availability")), availability);
+    self.publish(
+      self.topic(&format!("{key}/state")),
+      state_payload(report).to_string(),
+    );
+  }
+
+  /// Publishes the Home Assistant discovery payloads for `device`: sensors for its player state
+  /// and app, and a number for its volume and buttons for playback if it takes commands, or a
+  /// sensor for its volume if not.
+  fn publish_discovery(&self, key: &str, device: &DiscoveredDevice) {
+    let node_id = format!("castiel_{key}");
+    let state_topic = self.topic(&format!("{key}/state"));
+    let command_topic = |command: &str| self.topic(&format!("{key}/command/{command}"));
+
+    let mut entities = vec![
+      (
+        "sensor",
+        "player_state",
+        json!({
+          "name": "Player state",
+          "state_topic": state_topic,
+          "value_template": "{{ value_json.player_state }}",
+          "json_attributes_topic": state_topic,
+          "icon": "mdi:cast",
+        }),
+      ),
+      (
+        "sensor",
+        "app",
+        json!({
+          "name": "App",
+          "state_topic": state_topic,
+          "value_template": "{{ value_json.app_name }}",
+          "icon": "mdi:application",
+        }),
+      ),
+    ];
+    if self.accepts_commands(device) {
+      entities.extend([
+        (
+          "number",
+          "volume",
+          json!({
+            "name": "Volume",
+            "state_topic": state_topic,
+            "value_template": "{{ value_json.volume }}",
+            "command_topic": command_topic("volume"),
+            "min": 0,
+            "max": 1,
+            "step": 0.01,
+            "mode": "slider",
+            "icon": "mdi:volume-high",
+          }),
+        ),
+        (
+          "button",
+          "play",
+          json!({ "name": "Play", "command_topic": command_topic("play"), "icon": "mdi:play" }),
+        ),
+        (
+          "button",
+          "pause",
+          json!({ "name": "Pause", "command_topic": command_topic("pause"), "icon": "mdi:pause" }),
+        ),
+        (
+          "button",
+          "stop",
+          json!({ "name": "Stop", "command_topic": command_topic("stop"), "icon": "mdi:stop" }),
+        ),
+      ]);
+    } else {
+      entities.push((
+        "sensor",
+        "volume",
+        json!({
+          "name": "Volume",
+          "state_topic": state_topic,
+          "value_template": "{{ value_json.volume }}",
+          "icon": "mdi:volume-high",
+        }),
+      ));
+    }
+
+    let name = device
+      .friendly_name
+      .clone()
+      .unwrap_or_else(|| format!("{}:{}", device.ip_address, device.port));
+    for (component, object_id, mut config) in entities {
+      config["unique_id"] = format!("{node_id}_{object_id}").into();
+      config["availability_mode"] = "all".into();
+      config["availability"] = json!([
+        { "topic": self.topic("status") },
+        { "topic": self.topic(&format!("{key}/availability")) },
+      ]);
+      config["device"] = json!({
+        "identifiers": [node_id],
+        "name": name,
+        "manufacturer": "Google",
+        "model": device.model_name,
+      });
+      config["origin"] = json!({ "name": "Castiel", "sw_version": env!("CARGO_PKG_VERSION") });
+
+      self.publish(
+        format!(
+          "{}/{component}/{node_id}/{object_id}/config",
+          self.settings.discovery_prefix
+        ),
+        config.to_string(),
+      );
+    }
+  }
+
+  /// Whether commands are taken for `device`.
+  fn accepts_commands(&self, device: &DiscoveredDevice) -> bool {
+    let devices = &self.settings.command_devices;
+    self.settings.commands
+      && (devices.is_empty()
+        || devices.iter().any(|allowed| {
+          *allowed == device.ip_address
+            || *allowed == format!("{}:{}", device.ip_address, device.port)
+            || *allowed == device_key(device)
+        }))
+  }
+
+  /// Queues a retained message for publishing.
+  fn publish(&self, topic: String, payload: impl Into<Vec<u8>>) {
+    // The connection task only stops along with Castiel
+    let _ = self.outgoing.send(Message {
+      topic,
+      payload: payload.into(),
+      retain: true,
+    });
+  }
+
+  /// The topic `name` under the topic prefix.
+  fn topic(&self, name: &str) -> String {
+    format!("{}/{name}", self.settings.topic_prefix)
+  }
+}
+
+/// Publishes the status of every device in `snapshot`, if MQTT is configured.
+pub fn publish_status(state: &AppState, snapshot: &StatusSnapshot) {
+  if let Some(mqtt) = &state.mqtt {
+    for report in &snapshot.devices {
+      mqtt.publish_device(report);
+    }
+  }
+}
+
+/// Spawns a background task which keeps Castiel connected to the broker, if MQTT is configured.
+pub fn spawn(state: AppState) {
+  let Some(mqtt) = state.mqtt.clone() else {
+    return;
+  };
+  let Some(outgoing) = mqtt
+    .pending
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .take()
+  else {
+    return;
+  };
+  if state.settings().status_refresh_seconds == 0 {
+    tracing::warn!(
+      "Device status is only published to MQTT while status_refresh_seconds is set, but it is 0"
+    );
+  }
+
+  tokio::spawn(async move {
+    // The latest message for every topic, published again whenever Castiel reconnects in case the
+    // broker lost them
+    let mut retained = BTreeMap::new();
+    let mut outgoing = outgoing;
+    let mut delay = INITIAL_RECONNECT_DELAY;
+    loop {
+      let settings = &mqtt.settings;
+      match connection(&state, &mqtt, &mut outgoing, &mut retained, &mut delay).await {
+        Ok(()) => return,
+        Err(err) => tracing::warn!(
+          "MQTT connection to {}:{} failed, reconnecting in {}s: {err}",
+          settings.host,
+          settings.port,
+          delay.as_secs()
+        ),
+      }
+
+      // Keep only the latest message for each topic while disconnected, rather than a backlog
+      let reconnect = tokio::time::sleep(delay);
+      tokio::pin!(reconnect);
+      loop {
+        tokio::select! {
+          () = &mut reconnect => break,
+          message = outgoing.recv() => match message {
+            Some(message) => {
+              retained.insert(message.topic, message.payload);
+            }
+            None => return,
+          },
+        }
+      }
+      delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+  });
+}
+
+/// Connects to the broker and publishes messages and runs commands until the connection fails.
+///
+/// Returns `Ok` once there is nothing left to publish, which only happens as Castiel stops.
+async fn connection(
+  state: &AppState,
+  mqtt: &Mqtt,
+  outgoing: &mut UnboundedReceiver<Message>,
+  retained: &mut BTreeMap<String, Vec<u8>>,
+  delay: &mut Duration,
+) -> io::Result<()> {
+  let settings = &mqtt.settings;
+  let status = |payload: &str| Message {
+    topic: mqtt.topic("status"),
+    payload: payload.into(),
+    retain: true,
+  };
+  let (mut reader, mut writer) = client::connect(settings, &status(OFFLINE)).await?;
+  tracing::info!(
+    "Connected to MQTT broker {}:{}",
+    settings.host,
+    settings.port
+  );
+  *delay = INITIAL_RECONNECT_DELAY;
+
+  // Reading a packet can't be cancelled part way, so packets are read by a task of their own
+  let (packets_tx, mut packets) = mpsc::channel(16);
+  let reading = tokio::spawn(async move {
+    loop {
+      let packet = reader.read().await;
+      let failed = packet.is_err();
+      if packets_tx.send(packet).await.is_err() || failed {
+        break;
+      }
+    }
+  });
+
+  let result = async {
+    let discovery_status = format!("{}/status", settings.discovery_prefix);
+    let mut filters = Vec::new();
+    if settings.commands {
+      filters.push(mqtt.topic("+/command/+"));
+    }
+    if settings.discovery {
+      // Home Assistant announces itself here when it starts, and needs discovery payloads again
+      filters.push(discovery_status.clone());
+    }
+    if !filters.is_empty() {
+      writer.subscribe(1, &filters).await?;
+    }
+    writer.publish(&status(ONLINE)).await?;
+    publish_retained(&mut writer, retained, |_| true).await?;
+
+    let keep_alive = Duration::from_secs(settings.keep_alive_seconds.max(1).into());
+    let mut pings = tokio::time::interval_at(Instant::now() + keep_alive, keep_alive);
+    let mut awaiting_ping = false;
+    loop {
+      tokio::select! {
+        message = outgoing.recv() => {
+          let Some(message) = message else {
+            return writer.disconnect().await;
+          };
+          if retained.get(&message.topic) != Some(&message.payload) {
+            writer.publish(&message).await?;
+            retained.insert(message.topic, message.payload);
+          }
+        }
+        packet = packets.recv() => match packet {
+          Some(Ok(Packet::Publish { message, packet_id })) => {
+            if let Some(packet_id) = packet_id {
+              writer.acknowledge(packet_id).await?;
+            }
+            if message.topic == discovery_status {
+              if message.payload == ONLINE.as_bytes() {
+                let prefix = format!("{}/", settings.discovery_prefix);
+                publish_retained(&mut writer, retained, |topic| topic.starts_with(&prefix)).await?;
+              }
+            } else if message.retain {
+              // Retained commands were sent before Castiel subscribed, and may be long stale
+              tracing::debug!("Ignoring retained MQTT command on {}", message.topic);
+            } else {
+              handle_command(state, mqtt, message);
+            }
+          }
+          Some(Ok(Packet::SubAck(codes))) => {
+            if codes.contains(&client::SUBSCRIPTION_FAILED) {
+              tracing::warn!("The MQTT broker refused to subscribe Castiel to {filters:?}");
+            }
+          }
+          Some(Ok(Packet::PingResp)) => awaiting_ping = false,
+          Some(Ok(Packet::Other)) => {}
+          Some(Err(err)) => return Err(err),
+          None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        },
+        _ = pings.tick() => {
+          if awaiting_ping {
+            return Err(io::Error::new(
+              io::ErrorKind::TimedOut,
+              "the broker stopped answering pings",
+            ));
+          }
+          writer.ping().await?;
+          awaiting_ping = true;
+        }
+      }
+    }
+  }
+  .await;
+
+  reading.abort();
+  result
+}
+
+/// Publishes the messages in `retained` whose topic matches `filter`.
+async fn publish_retained(
+  writer: &mut PacketWriter,
+  retained: &BTreeMap<String, Vec<u8>>,
+  filter: impl Fn(&str) -> bool,
+) -> io::Result<()> {
+  for (topic, payload) in retained.iter().filter(|(topic, _)| filter(topic)) {
+    writer
+      .publish(&Message {
+        topic: topic.clone(),
+        payload: payload.clone(),
+        retain: true,
+      })
+      .await?;
+  }
+  Ok(())
+}
+
+/// Runs the command in `message` in the background, then publishes the device's new status.
+fn handle_command(state: &AppState, mqtt: &Mqtt, message: Message) {
+  let parts = message
+    .topic
+    .strip_prefix(&format!("{}/", mqtt.settings.topic_prefix))
+    .and_then(|name| name.split_once("/command/"));
+  let Some((key, command)) = parts else {
+    return;
+  };
+  let Some(device) = state
+    .devices
+    .devices()
+    .into_iter()
+    .find(|device| device_key(device) == key)
+  else {
+    tracing::warn!("Ignoring MQTT command {command} for unknown device {key}");
+    return;
+  };
+
+  let allowed = mqtt.accepts_commands(&device);
+  let (state, command) = (state.clone(), command.to_string());
+  let payload = String::from_utf8_lossy(&message.payload).into_owned();
+  tokio::spawn(async move {
+    let (ip, port) = (device.ip_address.clone(), device.port);
+    let result = if allowed {
+      run_command(&state, &device, &command, &payload).await
+    } else {
+      Err(CastielError::Forbidden(
+        "MQTT commands aren't allowed on this device".to_string(),
+      ))
+    }
+    .map_err(|err| err.at_device(&ip, port));
+    match &result {
+      Ok(()) => tracing::info!("Ran MQTT command {command} on {ip}:{port}"),
+      Err(err) => tracing::warn!("MQTT command {command} failed on {ip}:{port}: {err}"),
+    }
+
+    let payload = match serde_json::from_str(&payload) {
+      Ok(payload) => Some(payload),
+      Err(_) if payload.is_empty() => None,
+      Err(_) => Some(Value::String(payload)),
+    };
+    state
+      .audit
+      .record_command(
+        "mqtt",
+        format!("MQTT {command}"),
+        format!("{ip}:{port}"),
+        payload,
+        &result,
+      )
+      .await;
+
+    // Show the effect of the command straight away, rather than after the next refresh
+    if let Some(mqtt) = &state.mqtt {
+      mqtt.publish_device(&aggregate::report_device(&state, device).await);
+    }
+  });
+}
+
+async fn run_command(
+  state: &AppState,
+  device: &DiscoveredDevice,
+  command: &str,
+  payload: &str,
+) -> Result<(), CastielError> {
+  let device_addr = DeviceAddress {
+    ip: device.ip_address.clone(),
+    port: device.port,
+  };
+
+  match command {
+    "play" | "pause" => {
+      let command = match command {
+        "play" => PlaybackCommand::Play,
+        _ => PlaybackCommand::Pause,
+      };
+      blocking(move || {
+        media::control_playback(&device_addr, &AppSelector::default(), command).map(|_| ())
+      })
+      .await
+    }
+    "stop" => cast::stop_media(state, device_addr, AppSelector::default()).await,
+    "volume" => {
+      let level = payload
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|level| (0.0..=1.0).contains(level))
+        .ok_or_else(|| {
+          CastielError::ValidationFailed(vec![FieldError::new(
+            "volume",
+            "expected a level between 0 and 1",
+          )])
+        })?;
+      blocking(move || media::set_volume_at_device(&device_addr, level)).await
+    }
+    "start-media" => {
+      let Value::Object(mut fields) = serde_json::from_str(payload)? else {
+        return Err(CastielError::ValidationFailed(vec![FieldError::new(
+          "media",
+          "expected a JSON object",
+        )]));
+      };
+      fields.insert("ip_address".to_string(), device_addr.ip.into());
+      fields.insert("port".to_string(), device_addr.port.into());
+      let media_data: StartMediaData = serde_json::from_value(Value::Object(fields))?;
+      cast::start_media(state, media_data).await
+    }
+    _ => Err(CastielError::ValidationFailed(vec![FieldError::new(
+      "command",
+      format!("expected play, pause, stop, volume or start-media, not {command}"),
+    )])),
+  }
+}
+
+/// The name of a device in topics: its Cast device ID, or its address if it has none.
+fn device_key(device: &DiscoveredDevice) -> String {
+  let key = match &device.id {
+    Some(id) if !id.is_empty() => id.clone(),
+    _ => format!("{}_{}", device.ip_address, device.port),
+  };
+  // Keep to characters which are valid in topics and Home Assistant IDs
+  key
+    .chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+      _ => '_',
+    })
+    .collect()
+}
+
+/// Summarises the status in `report` for the device's state topic.
+fn state_payload(report: &DeviceReport) -> Value {
+  let device_status = report.device_status.as_ref();
+  let app = device_status.and_then(DeviceStatus::app_status);
+  let media = report.media_status.as_ref();
+  // Levels are reported as 32-bit floats, which would show as e.g. 0.30000001192092896
+  let volume =
+    device_status.map(|status| (f64::from(status.volume().level()) * 100.0).round() / 100.0);
+
+  json!({
+    "address": format!("{}:{}", report.device.ip_address, report.device.port),
+    "name": report.device.friendly_name,
+    "online": device_status.is_some(),
+    "volume": volume,
+    "muted": device_status.map(|status| status.volume().muted()),
+    "app_id": app.map(AppStatus::id),
+    "app_name": app.map(AppStatus::display_name),
+    "player_state": media.map(|media| format!("{:?}", media.player_state()).to_lowercase()),
+    "content_id": media.and_then(MediaStatus::content_id),
+    "title": media.and_then(MediaStatus::title),
+    "error": report.error,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+
+  fn mqtt(commands: bool, command_devices: &[&str]) -> Mqtt {
+    Mqtt::new(MqttSettings {
+      host: "localhost".to_string(),
+      port: 1883,
+      client_id: "castiel".to_string(),
+      username: None,
+      password: None,
+      topic_prefix: "castiel".to_string(),
+      commands,
+      command_devices: command_devices.iter().map(ToString::to_string).collect(),
+      discovery: true,
+      discovery_prefix: "homeassistant".to_string(),
+      keep_alive_seconds: 30,
+    })
+  }
+
+  fn device(ip: &str, id: &str) -> DiscoveredDevice {
+    DiscoveredDevice {
+      ip_address: ip.to_string(),
+      port: 8009,
+      fullname: format!("Chromecast-{id}._googlecast._tcp.local."),
+      id: Some(id.to_string()),
+      model_name: None,
+      friendly_name: None,
+      txt_properties: HashMap::new(),
+    }
+  }
+
+  #[test]
+  fn commands_are_off_unless_enabled() {
+    assert!(!mqtt(false, &[]).accepts_commands(&device("192.168.1.20", "abc")));
+    assert!(mqtt(true, &[]).accepts_commands(&device("192.168.1.20", "abc")));
+  }
+
+  #[test]
+  fn commands_are_limited_to_the_allowed_devices() {
+    let mqtt = mqtt(true, &["192.168.1.20", "192.168.1.21:8009", "kitchen-id"]);
+    assert!(mqtt.accepts_commands(&device("192.168.1.20", "abc")));
+    assert!(mqtt.accepts_commands(&device("192.168.1.21", "abc")));
+    assert!(mqtt.accepts_commands(&device("192.168.1.22", "kitchen-id")));
+    assert!(!mqtt.accepts_commands(&device("192.168.1.23", "abc")));
+  }
+}
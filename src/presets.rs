@@ -0,0 +1,446 @@
+//! Named presets which recall a cast configuration: media for a single device, or a scene setting
+//! up the media and volume of several devices at once.
+//!
+//! Presets are saved in the state directory. Media is kept as the body sent to
+//! POST /api/start-media, and checked both when a preset is saved and when it is activated.
+//! Devices can't be changed together in a single transaction, so activation instead checks every
+//! device's part of the preset before any device is touched, then sets up every device
+//! concurrently and reports how each one went.
+
+use std::{
+  collections::BTreeMap,
+  sync::{Arc, Mutex, PoisonError},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::{
+  auth::Caller,
+  cast,
+  devices::{
+    DeviceAddress,
+    media::{self, StartMediaData},
+    validation::FieldError,
+  },
+  errors::{CastielError, ErrorBody},
+  state::AppState,
+  storage::{Collection, StateStore},
+};
+
+/// The longest preset name accepted.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// A saved cast configuration. Holds either `media` or a `scene`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+  pub name: String,
+  /// The media to cast, as sent to POST /api/start-media.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub media: Option<Value>,
+  /// What to do on each of several devices.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub scene: Vec<SceneDevice>,
+}
+
+/// A device's part in a scene.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SceneDevice {
+  pub ip: String,
+  pub port: u16,
+  /// The media to cast, as sent to POST /api/start-media but without `ip_address` and `port`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub media: Option<Value>,
+  /// The volume to set, from 0 to 1.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub volume: Option<f32>,
+}
+
+/// What activating a preset does to one device.
+struct DeviceStep {
+  device: DeviceAddress,
+  media: Option<StartMediaData>,
+  volume: Option<f32>,
+}
+
+impl Preset {
+  /// Checks the preset, returning what it does to each device.
+  ///
+  /// Every problem found is reported at once through [`CastielError::ValidationFailed`].
+  fn steps(&self) -> Result<Vec<DeviceStep>, CastielError> {
+    let mut errors = Vec::new();
+    let mut steps = Vec::new();
+
+    let name = self.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+      errors.push(FieldError::new(
+        "name",
+        format!("expected between 1 and {MAX_NAME_LENGTH} characters"),
+      ));
+    }
+
+    match (&self.media, self.scene.is_empty()) {
+      (Some(media), true) => match parse_media("media", media.clone()) {
+        Ok(media) => steps.push(DeviceStep {
+          device: DeviceAddress {
+            ip: media.ip_address.clone(),
+            port: media.port,
+          },
+          media: Some(media),
+          volume: None,
+        }),
+        Err(err) => errors.push(err),
+      },
+      (None, false) => {
+        for scene_device in &self.scene {
+          let address = format!("{}:{}", scene_device.ip, scene_device.port);
+          if steps.iter().any(|step: &DeviceStep| {
+            step.device.ip == scene_device.ip && step.device.port == scene_device.port
+          }) {
+            errors.push(FieldError::new(
+              "scene",
+              format!("{address} appears more than once"),
+            ));
+            continue;
+          }
+          if scene_device.media.is_none() && scene_device.volume.is_none() {
+            errors.push(FieldError::new(
+              "scene",
+              format!("{address} needs media, a volume or both"),
+            ));
+          }
+          if let Some(volume) = scene_device.volume
+            && !(0.0..=1.0).contains(&volume)
+          {
+            errors.push(FieldError::new(
+              "scene",
+              format!("{address} volume must be between 0 and 1"),
+            ));
+          }
+
+          let media = match scene_device.media.clone() {
+            Some(Value::Object(mut fields)) => {
+              fields.insert("ip_address".to_string(), scene_device.ip.clone().into());
+              fields.insert("port".to_string(), scene_device.port.into());
+              match parse_media("scene", Value::Object(fields)) {
+                Ok(media) => Some(media),
+                Err(mut err) => {
+                  err.message = format!("{address} {}", err.message);
+                  errors.push(err);
+                  None
+                }
+              }
+            }
+            Some(_) => {
+              errors.push(FieldError::new(
+                "scene",
+                format!("{address} media must be an object"),
+              ));
+              None
+            }
+            None => None,
+          };
+          steps.push(DeviceStep {
+            device: DeviceAddress {
+              ip: scene_device.ip.clone(),
+              port: scene_device.port,
+            },
+            media,
+            volume: scene_device.volume,
+          });
+        }
+      }
+      _ => errors.push(FieldError::new("media", "expected either media or a scene")),
+    }
+
+    if errors.is_empty() {
+      Ok(steps)
+    } else {
+      Err(CastielError::ValidationFailed(errors))
+    }
+  }
+
+  /// The `ip:port` addresses of the devices the preset acts on.
+  fn devices(&self) -> Vec<(String, u16)> {
+    match &self.media {
+      Some(media) => {
+        let ip = media["ip_address"].as_str().unwrap_or_default().to_string();
+        let port = media["port"].as_u64().unwrap_or_default();
+        vec![(ip, u16::try_from(port).unwrap_or_default())]
+      }
+      None => self
+        .scene
+        .iter()
+        .map(|device| (device.ip.clone(), device.port))
+        .collect(),
+    }
+  }
+
+  /// Fails with [`CastielError::Forbidden`] unless `caller` may access every device in the preset.
+  fn check_access(&self, caller: &Caller) -> Result<(), CastielError> {
+    self
+      .devices()
+      .iter()
+      .try_for_each(|(ip, port)| caller.check_device(ip, *port))
+  }
+}
+
+/// Parses and checks a start-media body, reporting problems against `field`.
+fn parse_media(field: &'static str, media: Value) -> Result<StartMediaData, FieldError> {
+  let media: StartMediaData =
+    serde_json::from_value(media).map_err(|err| FieldError::new(field, err.to_string()))?;
+  match media.clone().validate() {
+    Ok(_) => Ok(media),
+    Err(CastielError::ValidationFailed(errors)) => Err(FieldError::new(
+      field,
+      errors
+        .iter()
+        .map(|err| format!("{} {}", err.field, err.message))
+        .collect::<Vec<_>>()
+        .join("; "),
+    )),
+    Err(err) => Err(FieldError::new(field, err.to_string())),
+  }
+}
+
+/// The presets as they are saved, keyed by name.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedPresets(BTreeMap<String, Preset>);
+
+impl Collection for SavedPresets {
+  const NAME: &'static str = "presets";
+  const VERSION: u32 = 1;
+}
+
+/// The saved presets.
+pub struct PresetStore {
+  store: Arc<StateStore>,
+  presets: Mutex<BTreeMap<String, Preset>>,
+  /// Held while a preset is activated, so that activations don't interleave.
+  activating: tokio::sync::Mutex<()>,
+}
+
+/// The result of activating a preset.
+#[derive(Debug, Serialize)]
+pub struct ActivationReport {
+  pub preset: String,
+  /// Whether every device was set up.
+  pub ok: bool,
+  /// One result per device, in the order the preset lists them.
+  pub devices: Vec<DeviceResult>,
+}
+
+/// The result of setting up one device.
+#[derive(Debug, Serialize)]
+pub struct DeviceResult {
+  /// The `ip:port` address of the device.
+  pub device: String,
+  pub ok: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<ErrorBody>,
+}
+
+impl PresetStore {
+  /// Loads the presets saved in `store`.
+  pub fn load(store: Arc<StateStore>) -> Result<Self, CastielError> {
+    let SavedPresets(presets) = store.load()?;
+    Ok(Self {
+      store,
+      presets: Mutex::new(presets),
+      activating: tokio::sync::Mutex::new(()),
+    })
+  }
+
+  /// Returns the presets whose devices `caller` may all access, sorted by name.
+  pub fn presets(&self, caller: &Caller) -> Vec<Preset> {
+    self
+      .presets
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .values()
+      .filter(|preset| {
+        preset
+          .devices()
+          .iter()
+          .all(|(ip, port)| caller.can_access(ip, *port))
+      })
+      .cloned()
+      .collect()
+  }
+
+  /// Saves `preset`, replacing any preset with the same name.
+  pub fn save(&self, mut preset: Preset, caller: &Caller) -> Result<Preset, CastielError> {
+    preset.steps()?;
+    preset.check_access(caller)?;
+    preset.name = preset.name.trim().to_string();
+
+    let mut presets = self.presets.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(existing) = presets.get(&preset.name) {
+      // A caller can't overwrite a preset for devices they couldn't otherwise touch
+      existing.check_access(caller)?;
+    }
+    let mut updated = presets.clone();
+    updated.insert(preset.name.clone(), preset.clone());
+    self.store.save(&SavedPresets(updated.clone()))?;
+    *presets = updated;
+
+    tracing::info!("Saved preset {}", preset.name);
+    Ok(preset)
+  }
+
+  /// Deletes the preset called `name`.
+  pub fn delete(&self, name: &str, caller: &Caller) -> Result<(), CastielError> {
+    let name = name.trim();
+    let mut presets = self.presets.lock().unwrap_or_else(PoisonError::into_inner);
+    let preset = presets
+      .get(name)
+      .ok_or_else(|| CastielError::PresetNotFound(name.to_string()))?;
+    preset.check_access(caller)?;
+
+    let mut updated = presets.clone();
+    updated.remove(name);
+    self.store.save(&SavedPresets(updated.clone()))?;
+    *presets = updated;
+
+    tracing::info!("Deleted preset {name}");
+    Ok(())
+  }
+
+  /// Returns the preset called `name`. Names are saved trimmed, so `name` is trimmed too.
+  fn get(&self, name: &str) -> Result<Preset, CastielError> {
+    let name = name.trim();
+    self
+      .presets
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get(name)
+      .cloned()
+      .ok_or_else(|| CastielError::PresetNotFound(name.to_string()))
+  }
+}
+
+/// Activates the preset called `name`, setting the volume and starting the media on each of its
+/// devices.
+///
+/// Nothing is changed unless the whole preset is valid and `caller` may access every device in
+/// it. After that, a device which fails is reported in the result rather than failing the request.
+pub async fn activate(
+  state: &AppState,
+  name: &str,
+  caller: &Caller,
+) -> Result<ActivationReport, CastielError> {
+  let preset = state.presets.get(name)?;
+  preset.check_access(caller)?;
+  let steps = preset.steps()?;
+
+  let _activating = state.presets.activating.lock().await;
+  tracing::info!("Activating preset {}", preset.name);
+  let mut results = JoinSet::new();
+  for (index, step) in steps.into_iter().enumerate() {
+    let state = state.clone();
+    results.spawn(async move {
+      let device = format!("{}:{}", step.device.ip, step.device.port);
+      let result = apply(&state, step).await;
+      if let Err(err) = &result {
+        tracing::warn!("Preset failed to set up {device}: {err}");
+      }
+      (
+        index,
+        DeviceResult {
+          device,
+          ok: result.is_ok(),
+          error: result.err().map(|err| err.to_body()),
+        },
+      )
+    });
+  }
+  let mut devices = results.join_all().await;
+  devices.sort_by_key(|(index, _)| *index);
+  let devices: Vec<DeviceResult> = devices.into_iter().map(|(_, result)| result).collect();
+
+  Ok(ActivationReport {
+    preset: preset.name,
+    ok: devices.iter().all(|device| device.ok),
+    devices,
+  })
+}
+
+/// Sets up one device: the volume first, so that media doesn't start at the old volume.
+async fn apply(state: &AppState, step: DeviceStep) -> Result<(), CastielError> {
+  let DeviceStep {
+    device,
+    media,
+    volume,
+  } = step;
+
+  if let Some(volume) = volume {
+    tokio::task::spawn_blocking(move || media::set_volume_at_device(&device, volume))
+      .await
+      .map_err(|_| CastielError::InternalError)??;
+  }
+  if let Some(media) = media {
+    cast::start_media(state, media).await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn preset(name: &str) -> Preset {
+    Preset {
+      name: name.to_string(),
+      media: None,
+      scene: vec![SceneDevice {
+        ip: "192.168.1.20".to_string(),
+        port: 8009,
+        media: None,
+        volume: Some(0.5),
+      }],
+    }
+  }
+
+  fn name_errors(preset: &Preset) -> usize {
+    match preset.steps() {
+      Ok(_) => 0,
+      Err(CastielError::ValidationFailed(errors)) => {
+        errors.iter().filter(|error| error.field == "name").count()
+      }
+      Err(err) => panic!("unexpected error: {err}"),
+    }
+  }
+
+  #[test]
+  fn names_are_limited_in_characters() {
+    assert_eq!(name_errors(&preset(&"é".repeat(MAX_NAME_LENGTH))), 0);
+    assert_eq!(name_errors(&preset(&"é".repeat(MAX_NAME_LENGTH + 1))), 1);
+    assert_eq!(name_errors(&preset("  ")), 1);
+  }
+
+  #[test]
+  fn presets_are_found_by_their_trimmed_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = PresetStore::load(Arc::new(StateStore::open(dir.path()).unwrap())).unwrap();
+    let caller = Caller::unrestricted();
+
+    let saved = store.save(preset("  Movie night "), &caller).unwrap();
+    assert_eq!(saved.name, "Movie night");
+    assert_eq!(store.get(" Movie night  ").unwrap().name, "Movie night");
+    store.delete("Movie night ", &caller).unwrap();
+    assert!(matches!(
+      store.get("Movie night"),
+      Err(CastielError::PresetNotFound(_))
+    ));
+  }
+
+  #[test]
+  fn scenes_and_media_are_exclusive() {
+    let mut both = preset("both");
+    both.media = Some(json!({ "ip_address": "192.168.1.20", "port": 8009 }));
+    assert!(both.steps().is_err());
+  }
+}
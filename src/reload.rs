@@ -0,0 +1,302 @@
+//! Watches Castiel's settings file and applies changes without a restart.
+//!
+//! A reload is triggered whenever the settings file changes on disk or, on Unix platforms, when
+//! the process receives `SIGHUP`.
+
+use std::{
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+
+use crate::{clock::unix_now, config::CastielSettings, state::AppState};
+
+/// How long to wait for further file events before reloading, since editors often write a file
+/// in several steps.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(250);
+
+/// Describes the outcome of the most recent settings reload.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadReport {
+  /// When the reload happened, in seconds since the Unix epoch.
+  pub reloaded_at: u64,
+  /// The settings that changed and were applied.
+  pub applied: Vec<String>,
+  /// The settings that changed but only take effect after a restart.
+  pub pending_restart: Vec<String>,
+}
+
+/// Spawns a background task that reloads settings when the settings file changes or `SIGHUP` is
+/// received.
+pub fn spawn_config_watcher(state: AppState) {
+  let (trigger_tx, mut trigger_rx) = unbounded_channel::<()>();
+
+  // Failing to watch the file only disables automatic reloads, so it is not fatal.
+  let watcher = create_file_watcher(&state.config_path, trigger_tx.clone())
+    .inspect_err(|err| tracing::warn!("Failed to watch settings file for changes: {err}"))
+    .ok();
+
+  #[cfg(unix)]
+  spawn_sighup_listener(trigger_tx);
+
+  tokio::spawn(async move {
+    // The watcher stops delivering events once dropped, so keep it alive for the task's lifetime
+    let _watcher = watcher;
+
+    while trigger_rx.recv().await.is_some() {
+      tokio::time::sleep(DEBOUNCE_DELAY).await;
+      while trigger_rx.try_recv().is_ok() { /* Collapse bursts of events into one reload */ }
+
+      reload_settings(&state);
+    }
+  });
+}
+
+/// Creates a watcher that sends on `trigger_tx` whenever the file at `config_path` changes.
+///
+/// The parent directory is watched rather than the file itself so that editors which replace the
+/// file on save are still picked up.
+fn create_file_watcher(
+  config_path: &Path,
+  trigger_tx: UnboundedSender<()>,
+) -> notify::Result<RecommendedWatcher> {
+  let file_name = config_path.file_name().map(ToOwned::to_owned);
+  let watch_dir = match config_path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+    _ => PathBuf::from("."),
+  };
+
+  let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+    let Ok(event) = res else { return };
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+      return;
+    }
+
+    let touches_config = event
+      .paths
+      .iter()
+      .any(|path| path.file_name() == file_name.as_deref());
+    if touches_config {
+      let _ = trigger_tx.send(());
+    }
+  })?;
+  watcher.watch(&watch_dir, RecursiveMode::NonRecursive)?;
+
+  Ok(watcher)
+}
+
+#[cfg(unix)]
+fn spawn_sighup_listener(trigger_tx: UnboundedSender<()>) {
+  use tokio::signal::unix::{SignalKind, signal};
+
+  let mut hangup = match signal(SignalKind::hangup()) {
+    Ok(hangup) => hangup,
+    Err(err) => {
+      tracing::warn!("Failed to listen for SIGHUP: {err}");
+      return;
+    }
+  };
+
+  tokio::spawn(async move {
+    while hangup.recv().await.is_some() {
+      tracing::info!("Received SIGHUP, reloading settings");
+      if trigger_tx.send(()).is_err() {
+        break;
+      }
+    }
+  });
+}
+
+/// Re-reads the settings file and applies any changes to `state`.
+///
+/// If the file cannot be read or parsed the active settings are kept.
+fn reload_settings(state: &AppState) {
+  let mut new_settings = match CastielSettings::load(&state.config_path) {
+    Ok(settings) => settings,
+    Err(err) => {
+      tracing::warn!("Failed to reload settings, keeping active settings: {err}");
+      return;
+    }
+  };
+  let old_settings = state.settings();
+
+  let pending_restart = keep_restart_only_settings(&old_settings, &mut new_settings);
+  if old_settings.log_level != new_settings.log_level {
+    // This also replaces any filter set through the API
+    if let Err(err) = state.log_handle.set(&new_settings.log_level) {
+      tracing::warn!("Invalid log_level, keeping the active log filter: {err}");
+      new_settings.log_level = old_settings.log_level.clone();
+    }
+  }
+
+  let applied = old_settings.changed_keys(&new_settings);
+  if applied.is_empty() && pending_restart.is_empty() {
+    tracing::debug!("Settings file changed but no settings differ");
+    return;
+  }
+
+  if !applied.is_empty() {
+    tracing::info!("Reloaded settings, applied: {}", applied.join(", "));
+  }
+  if !pending_restart.is_empty() {
+    tracing::warn!(
+      "Settings changed that require a restart to take effect: {}",
+      pending_restart.join(", ")
+    );
+  }
+  tracing::debug!("Active settings: {new_settings:?}");
+
+  let reloaded_at = unix_now();
+
+  *state
+    .settings
+    .write()
+    .unwrap_or_else(std::sync::PoisonError::into_inner) = new_settings;
+  *state
+    .last_reload
+    .write()
+    .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(ReloadReport {
+    reloaded_at,
+    applied,
+    pending_restart,
+  });
+}
+
+/// Puts the active value back in `new_settings` for every changed setting which only takes effect
+/// after a restart, so that the settings keep describing what is running. Returns the names of
+/// those settings.
+fn keep_restart_only_settings(
+  old_settings: &CastielSettings,
+  new_settings: &mut CastielSettings,
+) -> Vec<String> {
+  let mut pending_restart = Vec::new();
+  if new_settings.port != old_settings.port {
+    // The listener is bound at startup
+    pending_restart.push("port".to_string());
+    new_settings.port = old_settings.port;
+  }
+  if new_settings.host != old_settings.host {
+    pending_restart.push("host".to_string());
+    new_settings.host = old_settings.host.clone();
+  }
+  if new_settings.log_format != old_settings.log_format {
+    // The log format is chosen when logging is initialized, unlike the log level
+    pending_restart.push("log_format".to_string());
+    new_settings.log_format = old_settings.log_format;
+  }
+  if new_settings.log_file != old_settings.log_file {
+    pending_restart.push("log_file".to_string());
+    new_settings.log_file = old_settings.log_file.clone();
+  }
+  if new_settings.tls != old_settings.tls {
+    // Certificates are loaded when the listener is bound
+    pending_restart.push("tls".to_string());
+    new_settings.tls = old_settings.tls.clone();
+  }
+  if new_settings.state_dir != old_settings.state_dir {
+    // Device pins and, by default, the audit log are kept in the state directory too
+    pending_restart.push("state_dir".to_string());
+    new_settings.state_dir = old_settings.state_dir.clone();
+  }
+  if new_settings.audit.file != old_settings.audit.file {
+    // The audit log is loaded at startup, though its retention settings apply immediately
+    pending_restart.push("audit.file".to_string());
+    new_settings.audit.file = old_settings.audit.file.clone();
+  }
+  if new_settings.mqtt != old_settings.mqtt {
+    // The broker connection is made at startup
+    pending_restart.push("mqtt".to_string());
+    new_settings.mqtt = old_settings.mqtt.clone();
+  }
+  // Likewise the relay listener, though the rest of the relay settings apply immediately
+  match (new_settings.relay.as_mut(), old_settings.relay.as_ref()) {
+    (Some(new), Some(old)) => {
+      if new.port != old.port {
+        pending_restart.push("relay.port".to_string());
+        new.port = old.port;
+      }
+    }
+    (None, None) => {}
+    _ => {
+      pending_restart.push("relay".to_string());
+      new_settings.relay = old_settings.relay.clone();
+    }
+  }
+  pending_restart
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::RelaySettings;
+
+  fn relay(port: u16, ffmpeg_path: &str) -> RelaySettings {
+    RelaySettings {
+      port,
+      advertised_host: None,
+      ffmpeg_path: ffmpeg_path.to_string(),
+      idle_timeout_seconds: 60,
+      sources: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn restart_only_settings_keep_their_active_values() {
+    let old = CastielSettings::default();
+    let mut new = CastielSettings {
+      port: 4000,
+      host: "0.0.0.0".to_string(),
+      log_level: "DEBUG".to_string(),
+      status_refresh_seconds: 30,
+      ..CastielSettings::default()
+    };
+
+    let pending_restart = keep_restart_only_settings(&old, &mut new);
+    assert_eq!(pending_restart, ["port", "host"]);
+    assert_eq!(new.port, old.port);
+    assert_eq!(new.host, old.host);
+    assert_eq!(
+      old.changed_keys(&new),
+      ["log_level", "status_refresh_seconds"]
+    );
+  }
+
+  #[test]
+  fn relay_port_changes_wait_for_a_restart() {
+    let old = CastielSettings {
+      relay: Some(relay(8090, "ffmpeg")),
+      ..CastielSettings::default()
+    };
+    let mut new = CastielSettings {
+      relay: Some(relay(8091, "/usr/bin/ffmpeg")),
+      ..CastielSettings::default()
+    };
+
+    assert_eq!(keep_restart_only_settings(&old, &mut new), ["relay.port"]);
+    let relay = new.relay.as_ref().unwrap();
+    assert_eq!(relay.port, 8090);
+    assert_eq!(relay.ffmpeg_path, "/usr/bin/ffmpeg");
+    assert_eq!(old.changed_keys(&new), ["relay"]);
+  }
+
+  #[test]
+  fn adding_or_removing_the_relay_waits_for_a_restart() {
+    let without = CastielSettings::default();
+    let with = CastielSettings {
+      relay: Some(relay(8090, "ffmpeg")),
+      ..CastielSettings::default()
+    };
+
+    let mut new = with.clone();
+    assert_eq!(keep_restart_only_settings(&without, &mut new), ["relay"]);
+    assert!(new.relay.is_none());
+    assert!(without.changed_keys(&new).is_empty());
+
+    let mut new = without.clone();
+    assert_eq!(keep_restart_only_settings(&with, &mut new), ["relay"]);
+    assert_eq!(new.relay, with.relay);
+  }
+}
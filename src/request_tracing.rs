@@ -0,0 +1,135 @@
+//! Gives every request an ID and a tracing span, so that its log lines can be correlated.
+//!
+//! The ID is taken from the request's `X-Request-Id` header when a proxy or client has already
+//! assigned one, generated otherwise, and sent back in the response's `X-Request-Id` header. The
+//! request's span carries the ID, method, route and, once a handler checks it, the device acted
+//! on. Its latency is logged when the response is sent.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use axum::{
+  body::Body,
+  extract::{MatchedPath, Request},
+  http::{HeaderName, HeaderValue},
+  middleware::Next,
+  response::Response,
+};
+use tower_http::{
+  LatencyUnit,
+  classify::{ServerErrorsAsFailures, SharedClassifier},
+  trace::{DefaultOnResponse, MakeSpan, TraceLayer},
+};
+use tracing::{Level, Span};
+
+/// The header request IDs are read from and returned in.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The longest request ID accepted from a client. Longer IDs are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The ID of a request, stored in its extensions.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Middleware assigning every request an ID and returning it in the response.
+pub async fn assign_request_id(mut request: Request, next: Next) -> Response {
+  let request_id = request
+    .headers()
+    .get(&REQUEST_ID_HEADER)
+    .and_then(|value| value.to_str().ok())
+    .filter(|id| is_valid_request_id(id))
+    .map_or_else(generate_request_id, ToString::to_string);
+  request
+    .extensions_mut()
+    .insert(RequestId(request_id.clone()));
+
+  let mut response = next.run(request).await;
+  if let Ok(value) = HeaderValue::from_str(&request_id) {
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+  }
+  response
+}
+
+/// Creates the layer wrapping every request in a span and logging its response and latency.
+pub fn trace_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>, RequestSpan> {
+  TraceLayer::new_for_http()
+    .make_span_with(RequestSpan)
+    .on_response(
+      DefaultOnResponse::new()
+        .level(Level::INFO)
+        .latency_unit(LatencyUnit::Millis),
+    )
+}
+
+/// Records the device a request acts on in the request's span.
+pub fn record_device(ip: &str, port: u16) {
+  Span::current().record("device", format!("{ip}:{port}"));
+}
+
+/// Creates the span of each request.
+#[derive(Debug, Clone)]
+pub struct RequestSpan;
+
+impl MakeSpan<Body> for RequestSpan {
+  fn make_span(&mut self, request: &Request) -> Span {
+    let request_id = request
+      .extensions()
+      .get::<RequestId>()
+      .map(|id| id.0.as_str())
+      .unwrap_or_default();
+    let route = request
+      .extensions()
+      .get::<MatchedPath>()
+      .map_or("fallback", MatchedPath::as_str);
+
+    tracing::info_span!(
+      "request",
+      request_id,
+      method = %request.method(),
+      route,
+      path = request.uri().path(),
+      device = tracing::field::Empty,
+    )
+  }
+}
+
+/// Accepts IDs of printable ASCII, which can be echoed back in a header and logged safely.
+fn is_valid_request_id(id: &str) -> bool {
+  !id.is_empty()
+    && id.len() <= MAX_REQUEST_ID_LENGTH
+    && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// A fresh request ID: 64 bits from the system's secure random generator.
+fn generate_request_id() -> String {
+  let mut bytes = [0; 8];
+  if SystemRandom::new().fill(&mut bytes).is_err() {
+    // Request IDs only correlate log lines, so a counter is unique enough
+    static FALLBACK: AtomicU64 = AtomicU64::new(0);
+    bytes = FALLBACK.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+  }
+  bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn generated_request_ids_are_random_and_valid() {
+    let (first, second) = (generate_request_id(), generate_request_id());
+    assert_eq!(first.len(), 16);
+    assert!(is_valid_request_id(&first));
+    assert_ne!(first, second);
+  }
+
+  #[test]
+  fn request_ids_must_be_short_printable_ascii() {
+    assert!(is_valid_request_id("abc-123"));
+    assert!(!is_valid_request_id(""));
+    assert!(!is_valid_request_id("has space"));
+    assert!(!is_valid_request_id("caf\u{e9}"));
+    assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+  }
+}
@@ -2,57 +2,137 @@
 
 use axum::{
   Json, Router,
+  extract::{Query, State},
+  http::header,
+  middleware,
+  response::IntoResponse,
   routing::{get, post},
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use tower_http::{
   services::{ServeDir, ServeFile},
   set_status::SetStatus,
 };
 
 use crate::{
+  aggregate::{self, StatusSnapshot},
+  audit::{self, AuditPage, AuditQuery},
+  auth::{self, Caller},
+  cast,
+  config::{CastielSettings, Role},
+  devices::validation::FieldError,
   devices::{
-    self, DeviceAddress,
+    self, AppRequest, DeviceAddress,
     discovery::DiscoveredDevice,
-    media::StartMediaData,
+    media::{CustomAppData, StartMediaData},
+    pinning::PinnedDevice,
     status::{DeviceStatus, MediaStatus},
+    tracks::{ActiveTracksRequest, TextTrackStyleRequest},
+    youtube::YouTubeQueueData,
   },
   errors::CastielError,
+  extract::ApiJson,
+  health, metrics,
+  presets::{self, ActivationReport, Preset},
+  reload::ReloadReport,
+  request_tracing,
+  state::AppState,
+  webhooks::{Delivery, DeliveryQuery},
 };
 
 /// Creates the main application router.
-pub fn create_router() -> Router {
+pub fn create_router(state: AppState) -> Router {
   // Static file server for frontend.
   let serve_dir = create_static_fileserver();
 
-  Router::new()
+  // Routes are grouped by the role needed to use them
+  let viewer_routes = Router::new()
     .route("/api/chromecasts", get(get_chromecasts))
     .route("/api/version", get(get_version))
-    .route("/api/start-media", post(start_media))
-    .route("/api/stop-media", post(stop_media))
     .route("/api/device-status", post(check_device_status))
     .route("/api/media-status", post(check_media_status))
+    .route("/api/status", get(get_status))
+    .route("/api/presets", get(get_presets))
+    .route("/metrics", get(get_metrics))
+    .route_layer(middleware::from_fn_with_state(
+      Role::Viewer,
+      auth::require_role,
+    ));
+  let operator_routes = Router::new()
+    .route("/api/start-media", post(start_media))
+    .route("/api/stop-media", post(stop_media))
+    .route("/api/start-app", post(start_app))
+    .route("/api/youtube-queue", post(queue_youtube_video))
+    .route("/api/set-active-tracks", post(set_active_tracks))
+    .route("/api/set-text-track-style", post(set_text_track_style))
+    .route("/api/save-preset", post(save_preset))
+    .route("/api/delete-preset", post(delete_preset))
+    .route("/api/activate-preset", post(activate_preset))
+    .route_layer(middleware::from_fn_with_state(
+      Role::Operator,
+      auth::require_role,
+    ))
+    // Outside of the role check, so that refused calls are recorded too
+    .route_layer(middleware::from_fn_with_state(state.clone(), audit::record));
+  let admin_routes = Router::new()
+    .route("/api/config", get(get_config))
+    .route("/api/device-pins", get(get_device_pins))
+    .route("/api/reset-device-pin", post(reset_device_pin))
+    .route("/api/log-filter", get(get_log_filter))
+    .route("/api/set-log-filter", post(set_log_filter))
+    .route("/api/audit", get(get_audit))
+    .route("/api/webhook-deliveries", get(get_webhook_deliveries))
+    .route_layer(middleware::from_fn_with_state(
+      Role::Admin,
+      auth::require_role,
+    ))
+    .route_layer(middleware::from_fn_with_state(state.clone(), audit::record));
+
+  Router::new()
+    .merge(viewer_routes)
+    .merge(operator_routes)
+    .merge(admin_routes)
     .fallback_service(serve_dir)
+    .layer(middleware::from_fn_with_state(
+      state.clone(),
+      auth::authenticate,
+    ))
+    // Probes can't authenticate, so the health routes are added outside of authentication
+    .merge(health::create_routes())
+    .layer(middleware::from_fn(metrics::track_requests))
+    .layer(request_tracing::trace_layer())
+    .layer(middleware::from_fn(request_tracing::assign_request_id))
+    .with_state(state)
 }
 
-/// Creates the static fileserver service.
+/// The directory the frontend is served from.
 ///
 /// The directory `./frontend/dist` relative to the crate root is used as the development path served,
 /// but during release `./dist` relative to the binary is used instead.
-fn create_static_fileserver() -> ServeDir<SetStatus<ServeFile>> {
+pub fn frontend_dir() -> &'static Path {
   if cfg!(debug_assertions) {
-    ServeDir::new("frontend/dist").not_found_service(ServeFile::new("frontend/dist/index.html"))
+    Path::new("frontend/dist")
   } else {
-    ServeDir::new("dist").not_found_service(ServeFile::new("dist/index.html"))
+    Path::new("dist")
   }
 }
 
+/// Creates the static fileserver service.
+fn create_static_fileserver() -> ServeDir<SetStatus<ServeFile>> {
+  let dir = frontend_dir();
+  ServeDir::new(dir).not_found_service(ServeFile::new(dir.join("index.html")))
+}
+
 /// Handler for the GET /api/chromecasts endpoint.
 ///
 /// Runs device discovery and returns a list of discovered Chromecast devices as JSON.
-async fn get_chromecasts() -> Result<Json<Vec<DiscoveredDevice>>, CastielError> {
-  // Search for Chromecasts for 2 seconds
-  let devices = devices::discovery::find_chromecasts(1)?;
+async fn get_chromecasts(
+  State(state): State<AppState>,
+  caller: Caller,
+) -> Result<Json<Vec<DiscoveredDevice>>, CastielError> {
+  let mut devices = aggregate::discover_devices(&state).await?;
+  devices.retain(|device| caller.can_access(&device.ip_address, device.port));
 
   // Log the discovered devices
   let device_count = devices.len();
@@ -67,36 +147,210 @@ async fn get_chromecasts() -> Result<Json<Vec<DiscoveredDevice>>, CastielError>
 /// Handler for the POST /api/send-media endpoint.
 ///
 /// Receives media data from the frontend and initiates the media sending process.
-async fn start_media(Json(media_data): Json<StartMediaData>) -> Result<(), CastielError> {
-  devices::media::start_from_data(media_data)?;
+async fn start_media(
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(media_data): ApiJson<StartMediaData>,
+) -> Result<(), CastielError> {
+  let (ip, port) = (media_data.ip_address.clone(), media_data.port);
+  caller.check_device(&ip, port)?;
+  cast::start_media(&state, media_data)
+    .await
+    .map_err(|err| err.at_device(&ip, port))
+}
+
+/// Handler for the POST /api/youtube-queue endpoint.
+///
+/// Adds a video to the queue of the YouTube receiver, launching the receiver if needed.
+async fn queue_youtube_video(
+  caller: Caller,
+  ApiJson(queue_data): ApiJson<YouTubeQueueData>,
+) -> Result<(), CastielError> {
+  let (ip, port) = (queue_data.ip_address.clone(), queue_data.port);
+  caller.check_device(&ip, port)?;
+  let queue_data = queue_data
+    .validate()
+    .map_err(|err| err.at_device(&ip, port))?;
+  devices::youtube::queue_video(&ip, port, &queue_data.media_url, &queue_data.position)
+    .await
+    .map_err(|err| err.at_device(&ip, port))?;
   Ok(())
 }
 
-/// Handler for the POST /api/stop-media endpoint.
+/// Handler for the POST /api/start-app endpoint.
 ///
-/// Receives a device address from the frontend and stops media on that device.
-async fn stop_media(Json(device_addr): Json<DeviceAddress>) -> Result<(), CastielError> {
-  devices::media::stop_media_at_device(&device_addr)?;
+/// Launches a receiver app registered in the settings and sends it an optional custom message.
+async fn start_app(
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(app_data): ApiJson<CustomAppData>,
+) -> Result<(), CastielError> {
+  let (ip, port) = (app_data.ip_address.clone(), app_data.port);
+  caller.check_device(&ip, port)?;
+  let receiver_apps = state.settings().receiver_apps;
+  devices::blocking(move || devices::media::start_custom_app(app_data, &receiver_apps))
+    .await
+    .map_err(|err| err.at_device(&ip, port))?;
   Ok(())
 }
 
+/// Handler for the POST /api/stop-media endpoint.
+///
+/// Receives a device address from the frontend and stops media on that device. A specific app can
+/// be targeted with `session_id` or `app_id`, otherwise the foreground app is stopped.
+async fn stop_media(
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(request): ApiJson<AppRequest>,
+) -> Result<(), CastielError> {
+  let (ip, port) = (request.device.ip.clone(), request.device.port);
+  caller.check_device(&ip, port)?;
+  cast::stop_media(&state, request.device, request.app)
+    .await
+    .map_err(|err| err.at_device(&ip, port))
+}
+
 /// Handler for the GET /api/device-status endpoint.
 ///
 /// Checks device status from the provided device address and returns it as JSON.
 async fn check_device_status(
-  Json(device_addr): Json<DeviceAddress>,
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(device_addr): ApiJson<DeviceAddress>,
 ) -> Result<Json<DeviceStatus>, CastielError> {
-  let status = devices::status::get_device_status(&device_addr.ip, device_addr.port)?;
+  let (ip, port) = (device_addr.ip.clone(), device_addr.port);
+  caller.check_device(&ip, port)?;
+  let receiver_apps = state.settings().receiver_apps;
+  let status = devices::blocking(move || {
+    devices::status::get_device_status(&device_addr.ip, device_addr.port, &receiver_apps)
+  })
+  .await
+  .map_err(|err| err.at_device(&ip, port))?;
   Ok(Json(status))
 }
 
+/// Handler for the POST /api/media-status endpoint.
+///
+/// Returns the media status of the app targeted by `session_id` or `app_id`, or of the
+/// foreground app if neither is given.
 async fn check_media_status(
-  Json(device_addr): Json<DeviceAddress>,
+  caller: Caller,
+  ApiJson(request): ApiJson<AppRequest>,
 ) -> Result<Json<MediaStatus>, CastielError> {
-  let status = devices::status::get_media_status(&device_addr.ip, device_addr.port)?;
+  let (ip, port) = (request.device.ip.clone(), request.device.port);
+  caller.check_device(&ip, port)?;
+  let status = devices::blocking(move || {
+    devices::status::get_media_status(&request.device.ip, request.device.port, &request.app)
+  })
+  .await
+  .map_err(|err| err.at_device(&ip, port))?;
   Ok(Json(status))
 }
 
+/// Handler for the POST /api/set-active-tracks endpoint.
+///
+/// Enables the listed text and audio tracks of the current media and disables the rest, then
+/// returns the updated media status.
+async fn set_active_tracks(
+  caller: Caller,
+  ApiJson(request): ApiJson<ActiveTracksRequest>,
+) -> Result<Json<MediaStatus>, CastielError> {
+  let (ip, port) = (request.target.device.ip.clone(), request.target.device.port);
+  caller.check_device(&ip, port)?;
+  let status = devices::blocking(move || devices::tracks::set_active_tracks(&request))
+    .await
+    .map_err(|err| err.at_device(&ip, port))?;
+  Ok(Json(status))
+}
+
+/// Handler for the POST /api/set-text-track-style endpoint.
+///
+/// Changes how text tracks of the current media are drawn and returns the updated media status.
+async fn set_text_track_style(
+  caller: Caller,
+  ApiJson(request): ApiJson<TextTrackStyleRequest>,
+) -> Result<Json<MediaStatus>, CastielError> {
+  let (ip, port) = (request.target.device.ip.clone(), request.target.device.port);
+  caller.check_device(&ip, port)?;
+  let status = devices::blocking(move || devices::tracks::set_text_track_style(&request))
+    .await
+    .map_err(|err| err.at_device(&ip, port))?;
+  Ok(Json(status))
+}
+
+/// Handler for the GET /api/presets endpoint.
+///
+/// Returns the presets whose devices the caller may access, sorted by name.
+async fn get_presets(State(state): State<AppState>, caller: Caller) -> Json<Vec<Preset>> {
+  Json(state.presets.presets(&caller))
+}
+
+/// Handler for the POST /api/save-preset endpoint.
+///
+/// Saves a preset, replacing any preset with the same name, and returns it as saved.
+async fn save_preset(
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(preset): ApiJson<Preset>,
+) -> Result<Json<Preset>, CastielError> {
+  Ok(Json(state.presets.save(preset, &caller)?))
+}
+
+#[derive(Deserialize)]
+struct PresetRequest {
+  name: String,
+}
+
+/// Handler for the POST /api/delete-preset endpoint.
+async fn delete_preset(
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(request): ApiJson<PresetRequest>,
+) -> Result<(), CastielError> {
+  state.presets.delete(&request.name, &caller)
+}
+
+/// Handler for the POST /api/activate-preset endpoint.
+///
+/// Applies a preset to its devices and reports how each one went.
+async fn activate_preset(
+  State(state): State<AppState>,
+  caller: Caller,
+  ApiJson(request): ApiJson<PresetRequest>,
+) -> Result<Json<ActivationReport>, CastielError> {
+  Ok(Json(
+    presets::activate(&state, &request.name, &caller).await?,
+  ))
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+  /// Serve the background snapshot instead of querying devices, if one is available.
+  #[serde(default)]
+  cached: bool,
+}
+
+/// Handler for the GET /api/status endpoint.
+///
+/// Returns the device and media status of every known device. Devices which fail to respond are
+/// reported with an inline error instead of failing the whole request.
+async fn get_status(
+  State(state): State<AppState>,
+  caller: Caller,
+  Query(query): Query<StatusQuery>,
+) -> Json<StatusSnapshot> {
+  let mut snapshot = if query.cached {
+    aggregate::latest_status(&state).await
+  } else {
+    aggregate::collect_status(&state).await
+  };
+
+  snapshot
+    .devices
+    .retain(|report| caller.can_access(&report.device.ip_address, report.device.port));
+  Json(snapshot)
+}
+
 #[derive(Serialize)]
 struct VersionResponse {
   version: String,
@@ -109,3 +363,130 @@ async fn get_version() -> Result<Json<VersionResponse>, CastielError> {
     version: VERSION.to_string(),
   }))
 }
+
+#[derive(Serialize)]
+struct ConfigResponse {
+  /// The path of the settings file being watched.
+  path: String,
+  /// The settings currently in effect, with secrets hidden.
+  settings: CastielSettings,
+  /// What changed during the most recent reload, if any has happened.
+  last_reload: Option<ReloadReport>,
+}
+
+/// Handler for the GET /api/config endpoint.
+///
+/// Returns the active settings along with a summary of the last live reload.
+async fn get_config(State(state): State<AppState>) -> Result<Json<ConfigResponse>, CastielError> {
+  let last_reload = state
+    .last_reload
+    .read()
+    .unwrap_or_else(std::sync::PoisonError::into_inner)
+    .clone();
+
+  Ok(Json(ConfigResponse {
+    path: state.config_path.display().to_string(),
+    settings: state.settings().redacted(),
+    last_reload,
+  }))
+}
+
+/// Handler for the GET /api/device-pins endpoint.
+///
+/// Returns the certificate pinned for each device, along with any different certificate a device
+/// has presented since.
+async fn get_device_pins(
+  State(state): State<AppState>,
+) -> Result<Json<Vec<PinnedDevice>>, CastielError> {
+  Ok(Json(state.device_pins.pins()))
+}
+
+#[derive(Deserialize)]
+struct ResetDevicePinRequest {
+  /// The device whose pin to forget, as listed by GET /api/device-pins.
+  device: String,
+}
+
+/// Handler for the POST /api/reset-device-pin endpoint.
+///
+/// Forgets a device's pinned certificate, so that the certificate it presents next is pinned.
+async fn reset_device_pin(
+  State(state): State<AppState>,
+  ApiJson(request): ApiJson<ResetDevicePinRequest>,
+) -> Result<(), CastielError> {
+  state.device_pins.reset(&request.device)
+}
+
+#[derive(Serialize)]
+struct LogFilterResponse {
+  /// The log filter in effect.
+  filter: String,
+  /// The `log_level` from the settings file, which replaces the active filter whenever it changes.
+  configured: String,
+}
+
+/// Handler for the GET /api/log-filter endpoint.
+async fn get_log_filter(State(state): State<AppState>) -> Json<LogFilterResponse> {
+  Json(LogFilterResponse {
+    filter: state.log_handle.current(),
+    configured: state.settings().log_level.clone(),
+  })
+}
+
+#[derive(Deserialize)]
+struct SetLogFilterRequest {
+  /// Comma-separated directives, e.g. `info,castiel::devices=trace,mdns_sd=warn`.
+  filter: String,
+}
+
+/// Handler for the POST /api/set-log-filter endpoint.
+///
+/// Changes the log filter until Castiel restarts or `log_level` is changed in the settings file.
+async fn set_log_filter(
+  State(state): State<AppState>,
+  ApiJson(request): ApiJson<SetLogFilterRequest>,
+) -> Result<Json<LogFilterResponse>, CastielError> {
+  state
+    .log_handle
+    .set(&request.filter)
+    .map_err(|err| CastielError::ValidationFailed(vec![FieldError::new("filter", err)]))?;
+  tracing::info!("Log filter changed to {:?}", state.log_handle.current());
+
+  Ok(get_log_filter(State(state)).await)
+}
+
+/// Handler for the GET /api/audit endpoint.
+///
+/// Returns the newest recorded API calls, optionally filtered by device or caller. Older entries
+/// are fetched by passing the response's `next_before` as `before`.
+async fn get_audit(
+  State(state): State<AppState>,
+  Query(query): Query<AuditQuery>,
+) -> Json<AuditPage> {
+  Json(state.audit.page(&query))
+}
+
+/// Handler for the GET /api/webhook-deliveries endpoint.
+///
+/// Returns the status of recent webhook deliveries, newest first, optionally filtered by webhook
+/// or status.
+async fn get_webhook_deliveries(
+  State(state): State<AppState>,
+  Query(query): Query<DeliveryQuery>,
+) -> Json<Vec<Delivery>> {
+  Json(state.webhooks.deliveries(&query))
+}
+
+/// Handler for the GET /metrics endpoint.
+///
+/// Returns Castiel's metrics in the Prometheus text format, with per-device gauges for the devices
+/// the caller may access.
+async fn get_metrics(State(state): State<AppState>, caller: Caller) -> impl IntoResponse {
+  (
+    [(
+      header::CONTENT_TYPE,
+      "text/plain; version=0.0.4; charset=utf-8",
+    )],
+    metrics::render(&state, &caller).await,
+  )
+}
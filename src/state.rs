@@ -0,0 +1,116 @@
+//! Defines the state shared between Castiel's API handlers and background tasks.
+
+use std::{
+  collections::HashMap,
+  path::PathBuf,
+  sync::{Arc, Mutex, RwLock},
+  time::Instant,
+};
+
+use tokio::task::AbortHandle;
+
+use crate::{
+  aggregate::{DiscoveryScan, StatusSnapshot},
+  audit::AuditLog,
+  config::{CastielSettings, SharedSettings},
+  devices::{pinning::PinStore, registry::DeviceRegistry},
+  logging::LogReloadHandle,
+  mqtt::Mqtt,
+  presets::PresetStore,
+  relay::RelayRegistry,
+  reload::ReloadReport,
+  storage::StateStore,
+  webhooks::Webhooks,
+};
+
+/// Shared application state, cheaply cloneable and handed to every API handler.
+#[derive(Clone)]
+pub struct AppState {
+  /// The path of the settings file the active settings were loaded from.
+  pub config_path: Arc<PathBuf>,
+  /// The currently active settings.
+  pub settings: SharedSettings,
+  /// A summary of the most recent settings reload, if one has happened.
+  pub last_reload: Arc<RwLock<Option<ReloadReport>>>,
+  /// Handle for changing the log level at runtime.
+  pub log_handle: LogReloadHandle,
+  /// The devices found by discovery so far.
+  pub devices: Arc<DeviceRegistry>,
+  /// The outcome of the most recent discovery scan, if one has run.
+  pub last_discovery: Arc<RwLock<Option<DiscoveryScan>>>,
+  /// The most recent background status snapshot, if background refreshes are enabled.
+  pub status_snapshot: Arc<RwLock<Option<StatusSnapshot>>>,
+  /// The tasks keeping auto-restarting media playing, keyed by device address.
+  pub live_watches: Arc<Mutex<HashMap<String, AbortHandle>>>,
+  /// The media being relayed to devices.
+  pub relays: Arc<RelayRegistry>,
+  /// The saved presets and scenes.
+  pub presets: Arc<PresetStore>,
+  /// The certificates pinned for each device.
+  pub device_pins: Arc<PinStore>,
+  /// Recent deliveries of events to webhooks.
+  pub webhooks: Arc<Webhooks>,
+  /// The record of state-changing API calls.
+  pub audit: Arc<AuditLog>,
+  /// The connection to the MQTT broker, if one is configured.
+  pub mqtt: Option<Arc<Mqtt>>,
+  /// When Castiel started.
+  pub started_at: Instant,
+}
+
+impl AppState {
+  pub fn new(config_path: PathBuf, settings: CastielSettings, log_handle: LogReloadHandle) -> Self {
+    let relay_port = settings.relay.as_ref().map(|relay| relay.port);
+    let audit_file = settings.audit.file.clone();
+    let state_dir = settings.state_dir.clone();
+    let mqtt = settings.mqtt.clone().map(|mqtt| Arc::new(Mqtt::new(mqtt)));
+    let settings = Arc::new(RwLock::new(settings));
+    let store = StateStore::open(&state_dir).unwrap_or_else(|err| {
+      panic!(
+        "Failed to open the state directory {}: {err}",
+        state_dir.display()
+      )
+    });
+    let store = Arc::new(store);
+    let devices = DeviceRegistry::load(store.clone())
+      .unwrap_or_else(|err| panic!("Failed to load known devices: {err}"));
+    let presets = PresetStore::load(store.clone())
+      .unwrap_or_else(|err| panic!("Failed to load presets: {err}"));
+    let devices = Arc::new(devices);
+    let device_pins = PinStore::load(store.clone(), settings.clone(), devices.clone())
+      .unwrap_or_else(|err| panic!("Failed to load device pins: {err}"));
+    let audit_file = audit_file.unwrap_or_else(|| store.file_path("audit-log.jsonl"));
+    let audit = AuditLog::load(&audit_file, settings.clone()).unwrap_or_else(|err| {
+      panic!(
+        "Failed to load the audit log from {}: {err}",
+        audit_file.display()
+      )
+    });
+    Self {
+      config_path: Arc::new(config_path),
+      settings,
+      last_reload: Arc::new(RwLock::new(None)),
+      log_handle,
+      devices,
+      last_discovery: Arc::new(RwLock::new(None)),
+      status_snapshot: Arc::new(RwLock::new(None)),
+      live_watches: Arc::new(Mutex::new(HashMap::new())),
+      relays: Arc::new(RelayRegistry::new(relay_port)),
+      presets: Arc::new(presets),
+      device_pins: Arc::new(device_pins),
+      webhooks: Arc::new(Webhooks::default()),
+      audit: Arc::new(audit),
+      mqtt,
+      started_at: Instant::now(),
+    }
+  }
+
+  /// Returns a copy of the currently active settings.
+  pub fn settings(&self) -> CastielSettings {
+    self
+      .settings
+      .read()
+      .unwrap_or_else(std::sync::PoisonError::into_inner)
+      .clone()
+  }
+}
@@ -0,0 +1,227 @@
+//! Keeps Castiel's state in a directory of JSON files, so that it survives restarts and redeploys
+//! with the directory on a volume.
+//!
+//! Each kind of state is a [`Collection`] saved in its own file along with the schema version it
+//! was written in. Files written in an older version are migrated when they are loaded, keeping a
+//! copy of the original, and files written by a newer version of Castiel are refused rather than
+//! risk losing what they hold.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::{Mutex, PoisonError},
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::errors::CastielError;
+
+/// A kind of state kept in the state directory.
+pub trait Collection: Serialize + DeserializeOwned + Default {
+  /// The name of the collection's file, without its extension.
+  const NAME: &'static str;
+  /// The schema version this build writes. Increase it whenever the stored format changes, and
+  /// teach [`Collection::migrate`] to upgrade the previous version.
+  const VERSION: u32;
+
+  /// Upgrades `data` stored in schema `version` to `version + 1`.
+  fn migrate(version: u32, data: Value) -> Result<Value, String> {
+    let _ = data;
+    Err(format!("no migration from version {version} is known"))
+  }
+}
+
+/// The contents of a collection's file.
+#[derive(Serialize, Deserialize)]
+struct StoredFile<T> {
+  version: u32,
+  data: T,
+}
+
+/// The directory state is kept in.
+pub struct StateStore {
+  dir: PathBuf,
+  /// Held while writing, so that concurrent saves can't share a temporary file.
+  writing: Mutex<()>,
+}
+
+impl StateStore {
+  /// Opens the state directory at `dir`, creating it if it doesn't exist yet.
+  pub fn open(dir: &Path) -> Result<Self, CastielError> {
+    fs::create_dir_all(dir)?;
+    Ok(Self {
+      dir: dir.to_path_buf(),
+      writing: Mutex::new(()),
+    })
+  }
+
+  /// Loads collection `C`, starting empty if it hasn't been saved yet.
+  pub fn load<C: Collection>(&self) -> Result<C, CastielError> {
+    let path = self.path_of(C::NAME);
+    let contents = match fs::read_to_string(&path) {
+      Ok(contents) => contents,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(C::default()),
+      Err(err) => return Err(err.into()),
+    };
+    let stored: StoredFile<Value> = serde_json::from_str(&contents)?;
+
+    if stored.version > C::VERSION {
+      return Err(CastielError::StateError(format!(
+        "{} was written in schema version {}, but only versions up to {} are supported",
+        path.display(),
+        stored.version,
+        C::VERSION
+      )));
+    }
+    if stored.version == C::VERSION {
+      return Ok(serde_json::from_value(stored.data)?);
+    }
+
+    let mut data = stored.data;
+    for version in stored.version..C::VERSION {
+      data = C::migrate(version, data).map_err(|err| {
+        CastielError::StateError(format!(
+          "failed to migrate {} from schema version {version}: {err}",
+          path.display()
+        ))
+      })?;
+    }
+    let migrated: C = serde_json::from_value(data)?;
+
+    // Keep the original in case the migration loses something
+    fs::copy(
+      &path,
+      self.path_of(&format!("{}.v{}", C::NAME, stored.version)),
+    )?;
+    self.save(&migrated)?;
+    tracing::info!(
+      "Migrated {} from schema version {} to {}",
+      path.display(),
+      stored.version,
+      C::VERSION
+    );
+    Ok(migrated)
+  }
+
+  /// Saves collection `C`, replacing what was saved before.
+  pub fn save<C: Collection>(&self, data: &C) -> Result<(), CastielError> {
+    let path = self.path_of(C::NAME);
+    let contents = serde_json::to_vec_pretty(&StoredFile {
+      version: C::VERSION,
+      data,
+    })?;
+
+    let _writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+    // Write to a temporary file first so a crash can't leave a truncated file behind
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, &path)?;
+    Ok(())
+  }
+
+  /// The path of a file kept in the state directory in its own format, such as the audit log.
+  pub fn file_path(&self, file_name: &str) -> PathBuf {
+    self.dir.join(file_name)
+  }
+
+  fn path_of(&self, name: &str) -> PathBuf {
+    self.dir.join(format!("{name}.json"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  /// A collection which stored a bare list of names in version 1.
+  #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+  struct Names {
+    names: Vec<String>,
+  }
+
+  impl Collection for Names {
+    const NAME: &'static str = "names";
+    const VERSION: u32 = 2;
+
+    fn migrate(version: u32, data: Value) -> Result<Value, String> {
+      match version {
+        1 => Ok(json!({ "names": data })),
+        version => Err(format!("no migration from version {version} is known")),
+      }
+    }
+  }
+
+  fn names(names: &[&str]) -> Names {
+    Names {
+      names: names.iter().map(ToString::to_string).collect(),
+    }
+  }
+
+  fn write(dir: &Path, file_name: &str, contents: &Value) {
+    fs::write(dir.join(file_name), contents.to_string()).unwrap();
+  }
+
+  fn read(dir: &Path, file_name: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(dir.join(file_name)).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn collections_start_empty_and_are_saved_with_their_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = StateStore::open(&dir.path().join("state")).unwrap();
+    assert_eq!(store.load::<Names>().unwrap(), Names::default());
+
+    store.save(&names(&["kitchen"])).unwrap();
+    assert_eq!(
+      read(&dir.path().join("state"), "names.json"),
+      json!({ "version": 2, "data": { "names": ["kitchen"] } })
+    );
+    assert_eq!(store.load::<Names>().unwrap(), names(&["kitchen"]));
+  }
+
+  #[test]
+  fn older_versions_are_migrated_keeping_a_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = json!({ "version": 1, "data": ["kitchen", "lounge"] });
+    write(dir.path(), "names.json", &original);
+
+    let store = StateStore::open(dir.path()).unwrap();
+    assert_eq!(
+      store.load::<Names>().unwrap(),
+      names(&["kitchen", "lounge"])
+    );
+    assert_eq!(read(dir.path(), "names.v1.json"), original);
+    assert_eq!(
+      read(dir.path(), "names.json"),
+      json!({ "version": 2, "data": { "names": ["kitchen", "lounge"] } })
+    );
+  }
+
+  #[test]
+  fn failed_migrations_leave_the_file_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = json!({ "version": 0, "data": ["kitchen"] });
+    write(dir.path(), "names.json", &original);
+
+    let store = StateStore::open(dir.path()).unwrap();
+    let err = store.load::<Names>().unwrap_err();
+    assert!(matches!(&err, CastielError::StateError(message) if message.contains("version 0")));
+    assert_eq!(read(dir.path(), "names.json"), original);
+    assert!(!dir.path().join("names.v0.json").exists());
+  }
+
+  #[test]
+  fn newer_versions_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = json!({ "version": 3, "data": { "names": [], "rooms": [] } });
+    write(dir.path(), "names.json", &original);
+
+    let store = StateStore::open(dir.path()).unwrap();
+    let err = store.load::<Names>().unwrap_err();
+    assert!(matches!(&err, CastielError::StateError(message) if message.contains("version 3")));
+    assert_eq!(read(dir.path(), "names.json"), original);
+  }
+}
@@ -0,0 +1,320 @@
+//! Serves the API and UI over HTTPS.
+//!
+//! Certificates are read from PEM files. When asked to, Castiel generates a self-signed ECDSA
+//! certificate for its own host names on first run, which browsers accept once trusted by hand.
+//! A plain HTTP listener can redirect browsers to HTTPS.
+
+use std::{
+  error::Error,
+  io::Write,
+  net::{SocketAddr, UdpSocket},
+  path::Path,
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use axum::{
+  Router,
+  extract::Request,
+  http::{Uri, header, uri::Authority},
+  response::{IntoResponse, Redirect, Response},
+};
+use rcgen::{
+  CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, KeyPair,
+  PKCS_ECDSA_P256_SHA256,
+};
+use rustls::{
+  ServerConfig,
+  pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  sync::mpsc,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::config::TlsSettings;
+
+/// How long a client may take to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long generated certificates are valid for. Some browsers reject longer lifetimes.
+const CERTIFICATE_LIFETIME: Duration = Duration::from_secs(825 * 24 * 60 * 60);
+
+/// Loads the certificate and key described by `settings`, first generating a self-signed pair if
+/// enabled and neither file exists.
+pub fn load_server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+  if settings.self_signed && !settings.cert_path.exists() && !settings.key_path.exists() {
+    let hostnames = certificate_hostnames(&settings.hostnames);
+    tracing::info!(
+      "Generating a self-signed certificate at {} for {}",
+      settings.cert_path.display(),
+      hostnames.join(", ")
+    );
+    let (cert_pem, key_pem) = generate_self_signed(&hostnames, SystemTime::now())?;
+    std::fs::write(&settings.cert_path, cert_pem)?;
+    write_private(&settings.key_path, key_pem.as_bytes())?;
+  }
+
+  let certs = CertificateDer::pem_file_iter(&settings.cert_path)
+    .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+    .map_err(|err| format!("failed to read {}: {err}", settings.cert_path.display()))?;
+  let key = PrivateKeyDer::from_pem_file(&settings.key_path)
+    .map_err(|err| format!("failed to read {}: {err}", settings.key_path.display()))?;
+
+  let mut config = ServerConfig::builder()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+  config.alpn_protocols = vec![b"http/1.1".to_vec()];
+  Ok(Arc::new(config))
+}
+
+/// A listener accepting TLS connections, for serving with [`axum::serve`].
+///
+/// Handshakes run in their own tasks, so that slow clients don't hold up others.
+pub struct TlsListener {
+  local_addr: SocketAddr,
+  connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+  pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> std::io::Result<Self> {
+    let local_addr = listener.local_addr()?;
+    let acceptor = TlsAcceptor::from(config);
+    let (sender, connections) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+      loop {
+        let (stream, addr) = match listener.accept().await {
+          Ok(connection) => connection,
+          Err(err) => {
+            tracing::warn!("Failed to accept connection: {err}");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+          }
+        };
+
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+          match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+              let _ = sender.send((stream, addr)).await;
+            }
+            Ok(Err(err)) => tracing::debug!("TLS handshake with {addr} failed: {err}"),
+            Err(_) => tracing::debug!("TLS handshake with {addr} timed out"),
+          }
+        });
+      }
+    });
+
+    Ok(Self {
+      local_addr,
+      connections,
+    })
+  }
+}
+
+impl axum::serve::Listener for TlsListener {
+  type Io = TlsStream<TcpStream>;
+  type Addr = SocketAddr;
+
+  async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+    match self.connections.recv().await {
+      Some(connection) => connection,
+      // The accept loop never stops while the listener is alive
+      None => std::future::pending().await,
+    }
+  }
+
+  fn local_addr(&self) -> std::io::Result<Self::Addr> {
+    Ok(self.local_addr)
+  }
+}
+
+/// Creates a router redirecting every request to the same path over HTTPS on `https_port`.
+pub fn create_redirect_router(https_port: u16) -> Router {
+  Router::new().fallback(move |request: Request| async move { redirect(&request, https_port) })
+}
+
+fn redirect(request: &Request, https_port: u16) -> Response {
+  let host = request
+    .headers()
+    .get(header::HOST)
+    .and_then(|host| host.to_str().ok())
+    .and_then(|host| host.parse::<Authority>().ok());
+  let Some(host) = host else {
+    return (
+      axum::http::StatusCode::BAD_REQUEST,
+      "HTTPS is required, but the request has no Host header to redirect to",
+    )
+      .into_response();
+  };
+
+  let path = request
+    .uri()
+    .path_and_query()
+    .map_or("/", |path| path.as_str());
+  let authority = match https_port {
+    443 => host.host().to_string(),
+    port => format!("{}:{port}", host.host()),
+  };
+  match Uri::builder()
+    .scheme("https")
+    .authority(authority)
+    .path_and_query(path)
+    .build()
+  {
+    Ok(uri) => Redirect::permanent(&uri.to_string()).into_response(),
+    Err(_) => axum::http::StatusCode::BAD_REQUEST.into_response(),
+  }
+}
+
+/// The names and addresses a generated certificate is issued for: `extra` plus those Castiel can
+/// find for its own host.
+fn certificate_hostnames(extra: &[String]) -> Vec<String> {
+  let mut hostnames = vec![
+    "localhost".to_string(),
+    "127.0.0.1".to_string(),
+    "::1".to_string(),
+  ];
+  if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+    let hostname = hostname.trim();
+    if !hostname.is_empty() {
+      hostnames.push(hostname.to_string());
+      hostnames.push(format!("{hostname}.local"));
+    }
+  }
+  // Connecting a UDP socket picks the outgoing interface without sending anything
+  if let Ok(address) = UdpSocket::bind("0.0.0.0:0")
+    .and_then(|socket| socket.connect("192.0.2.1:9").map(|()| socket))
+    .and_then(|socket| socket.local_addr())
+  {
+    hostnames.push(address.ip().to_string());
+  }
+
+  for name in extra {
+    if !hostnames.contains(name) {
+      hostnames.push(name.clone());
+    }
+  }
+  hostnames.dedup();
+  hostnames
+}
+
+/// Generates a self-signed certificate for `hostnames` with a new P-256 key, valid from an hour
+/// before `now`, returning both in PEM format.
+fn generate_self_signed(
+  hostnames: &[String],
+  now: SystemTime,
+) -> Result<(String, String), Box<dyn Error>> {
+  let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+
+  // Host names which parse as IP addresses become IP address SANs
+  let mut params = CertificateParams::new(hostnames)?;
+  params.distinguished_name = DistinguishedName::new();
+  params
+    .distinguished_name
+    .push(DnType::CommonName, "Castiel");
+  params.not_before = (now - Duration::from_secs(60 * 60)).into();
+  params.not_after = (now + CERTIFICATE_LIFETIME).into();
+  params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+  let certificate = params.self_signed(&key_pair)?;
+
+  Ok((certificate.pem(), key_pair.serialize_pem()))
+}
+
+/// Writes `contents` to a new file at `path` which only the current user can read.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+  use rustls::{
+    CertificateError, RootCertStore,
+    client::{WebPkiServerVerifier, danger::ServerCertVerifier},
+    pki_types::{ServerName, UnixTime},
+  };
+
+  use super::*;
+
+  /// Generates a certificate at `now` and checks it for `hostname` at `at` with webpki, trusting
+  /// the certificate itself.
+  fn verify(now: SystemTime, hostname: &str, at: SystemTime) -> Result<(), rustls::Error> {
+    let hostnames = ["localhost", "castiel.local", "127.0.0.1", "::1"].map(ToString::to_string);
+    let (cert_pem, key_pem) = generate_self_signed(&hostnames, now).unwrap();
+    let certificate = CertificateDer::from_pem_slice(cert_pem.as_bytes()).unwrap();
+    PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate.clone()).unwrap();
+    let verifier = WebPkiServerVerifier::builder_with_provider(
+      Arc::new(roots),
+      Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+    )
+    .build()
+    .unwrap();
+
+    let at = at.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    verifier
+      .verify_server_cert(
+        &certificate,
+        &[],
+        &ServerName::try_from(hostname).unwrap(),
+        &[],
+        UnixTime::since_unix_epoch(at),
+      )
+      .map(|_| ())
+  }
+
+  fn days(days: u64) -> Duration {
+    Duration::from_secs(days * 24 * 60 * 60)
+  }
+
+  #[test]
+  fn generated_certificates_cover_their_host_names_and_addresses() {
+    let now = SystemTime::now();
+    for hostname in ["localhost", "castiel.local", "127.0.0.1", "::1"] {
+      assert!(verify(now, hostname, now).is_ok(), "{hostname}");
+    }
+    assert!(matches!(
+      verify(now, "example.com", now),
+      Err(rustls::Error::InvalidCertificate(
+        CertificateError::NotValidForNameContext { .. }
+      ))
+    ));
+  }
+
+  #[test]
+  fn generated_certificates_are_valid_for_their_lifetime() {
+    let now = SystemTime::now();
+    assert!(verify(now, "localhost", now - Duration::from_secs(30 * 60)).is_ok());
+    assert!(verify(now, "localhost", now + CERTIFICATE_LIFETIME - days(1)).is_ok());
+    assert!(matches!(
+      verify(now, "localhost", now - days(1)),
+      Err(rustls::Error::InvalidCertificate(
+        CertificateError::NotValidYetContext { .. }
+      ))
+    ));
+    assert!(matches!(
+      verify(now, "localhost", now + CERTIFICATE_LIFETIME + days(1)),
+      Err(rustls::Error::InvalidCertificate(
+        CertificateError::ExpiredContext { .. }
+      ))
+    ));
+  }
+
+  #[test]
+  fn certificates_expiring_after_2049_use_generalized_time() {
+    // 2049-06-01, so the certificate expires in 2051
+    let now = SystemTime::UNIX_EPOCH + days(28_976);
+    assert!(verify(now, "localhost", now + days(365)).is_ok());
+    assert!(verify(now, "localhost", now + CERTIFICATE_LIFETIME - days(1)).is_ok());
+    assert!(verify(now, "localhost", now + CERTIFICATE_LIFETIME + days(1)).is_err());
+  }
+}
@@ -0,0 +1,564 @@
+//! Posts events about devices and playback to the webhooks configured in the settings.
+//!
+//! Each event is sent as a JSON object to every webhook subscribed to it. Deliveries are signed
+//! with HMAC-SHA256 when the webhook has a secret, and retried with exponential backoff while the
+//! receiver can't be reached or answers with a server error. The status of recent deliveries is
+//! kept in memory for `/api/webhook-deliveries`.
+//!
+//! Devices being lost, apps changing and media errors are noticed by comparing background status
+//! snapshots, so they are only sent while `status_refresh_seconds` is set.
+
+use std::{
+  collections::VecDeque,
+  sync::{
+    Mutex, PoisonError,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::Duration,
+};
+
+use aws_lc_rs::hmac;
+use reqwest::{Client, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::{
+  aggregate::{DeviceReport, StatusSnapshot},
+  clock::unix_now,
+  config::{WebhookConfig, WebhookEvent},
+  devices::{
+    discovery::DiscoveredDevice,
+    status::{DeviceStatus, IdleReason, PlayerState},
+  },
+  state::AppState,
+};
+
+/// How many deliveries are kept for the API, newest first.
+const MAX_KEPT_DELIVERIES: usize = 200;
+
+/// How long to wait for a webhook to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before the first retry. Each retry waits twice as long as the one before.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Something which happened, as posted to webhooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+  pub event: WebhookEvent,
+  /// When the event happened, as a Unix timestamp.
+  pub timestamp: u64,
+  /// The device the event happened on.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub device: Option<EventDevice>,
+  /// Event-specific details.
+  #[serde(skip_serializing_if = "Value::is_null")]
+  pub details: Value,
+}
+
+/// The device an event happened on.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventDevice {
+  /// The `ip:port` address of the device.
+  pub address: String,
+  /// The device's friendly name, if it was discovered.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+}
+
+impl Event {
+  pub fn new(event: WebhookEvent, device: Option<EventDevice>, details: Value) -> Self {
+    Self {
+      event,
+      timestamp: unix_now(),
+      device,
+      details,
+    }
+  }
+}
+
+impl From<&DiscoveredDevice> for EventDevice {
+  fn from(device: &DiscoveredDevice) -> Self {
+    Self {
+      address: format!("{}:{}", device.ip_address, device.port),
+      name: device.friendly_name.clone(),
+    }
+  }
+}
+
+/// Where a delivery has got to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+  /// The event hasn't been delivered yet, and will be tried again.
+  Pending,
+  Delivered,
+  /// Every attempt failed, or the webhook rejected the event.
+  Failed,
+}
+
+/// An attempt to send an event to a webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+  /// Also sent in the `X-Castiel-Delivery` header.
+  pub id: u64,
+  /// The name of the webhook.
+  pub webhook: String,
+  pub event: WebhookEvent,
+  /// The `ip:port` address of the device the event happened on.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub device: Option<String>,
+  pub status: DeliveryStatus,
+  pub attempts: u32,
+  /// The HTTP status of the most recent response, if the webhook answered.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_status_code: Option<u16>,
+  /// Why the most recent attempt failed.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_error: Option<String>,
+  /// When the event happened, as a Unix timestamp.
+  pub created_at: u64,
+  /// When the delivery was last attempted, as a Unix timestamp.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_attempt_at: Option<u64>,
+}
+
+/// Which deliveries to return from [`Webhooks::deliveries`].
+#[derive(Debug, Default, Deserialize)]
+pub struct DeliveryQuery {
+  /// Only return deliveries to this webhook.
+  pub webhook: Option<String>,
+  /// Only return deliveries with this status.
+  pub status: Option<DeliveryStatus>,
+}
+
+/// Sends events to webhooks and keeps track of recent deliveries.
+pub struct Webhooks {
+  client: Client,
+  next_id: AtomicU64,
+  deliveries: Mutex<VecDeque<Delivery>>,
+}
+
+impl Default for Webhooks {
+  fn default() -> Self {
+    let client = Client::builder()
+      .timeout(REQUEST_TIMEOUT)
+      .build()
+      .unwrap_or_default();
+
+    Self {
+      client,
+      next_id: AtomicU64::new(1),
+      deliveries: Mutex::new(VecDeque::new()),
+    }
+  }
+}
+
+impl Webhooks {
+  /// Returns the recent deliveries matching `query`, newest first.
+  pub fn deliveries(&self, query: &DeliveryQuery) -> Vec<Delivery> {
+    self
+      .deliveries
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .iter()
+      .filter(|delivery| {
+        query
+          .webhook
+          .as_ref()
+          .is_none_or(|webhook| delivery.webhook == *webhook)
+      })
+      .filter(|delivery| query.status.is_none_or(|status| delivery.status == status))
+      .cloned()
+      .collect()
+  }
+
+  /// Adds a pending delivery of `event` to `webhook`, returning its ID.
+  fn start_delivery(&self, webhook: &WebhookConfig, event: &Event) -> u64 {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let mut deliveries = self
+      .deliveries
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    deliveries.push_front(Delivery {
+      id,
+      webhook: webhook.name.clone(),
+      event: event.event,
+      device: event.device.as_ref().map(|device| device.address.clone()),
+      status: DeliveryStatus::Pending,
+      attempts: 0,
+      last_status_code: None,
+      last_error: None,
+      created_at: event.timestamp,
+      last_attempt_at: None,
+    });
+    deliveries.truncate(MAX_KEPT_DELIVERIES);
+    id
+  }
+
+  /// Records the outcome of an attempt to deliver delivery `id`.
+  fn record_attempt(
+    &self,
+    id: u64,
+    status: DeliveryStatus,
+    status_code: Option<StatusCode>,
+    error: Option<String>,
+  ) {
+    let mut deliveries = self
+      .deliveries
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    // The delivery may already have been pushed out by newer ones
+    if let Some(delivery) = deliveries.iter_mut().find(|delivery| delivery.id == id) {
+      delivery.status = status;
+      delivery.attempts += 1;
+      delivery.last_status_code = status_code.map(|code| code.as_u16());
+      delivery.last_error = error;
+      delivery.last_attempt_at = Some(unix_now());
+    }
+  }
+}
+
+/// Sends `event` to every webhook subscribed to it, in the background.
+pub fn notify(state: &AppState, event: Event) {
+  let webhooks: Vec<WebhookConfig> = state
+    .settings()
+    .webhooks
+    .into_iter()
+    .filter(|webhook| webhook.events.is_empty() || webhook.events.contains(&event.event))
+    .collect();
+  if webhooks.is_empty() {
+    return;
+  }
+
+  let body = match serde_json::to_vec(&event) {
+    Ok(body) => body,
+    Err(err) => {
+      tracing::error!("Failed to serialize {:?} event: {err}", event.event);
+      return;
+    }
+  };
+  for webhook in webhooks {
+    let id = state.webhooks.start_delivery(&webhook, &event);
+    tokio::spawn(deliver(
+      state.clone(),
+      webhook,
+      id,
+      event.event,
+      body.clone(),
+    ));
+  }
+}
+
+/// Posts `body` to `webhook`, retrying until it is accepted or `max_attempts` is reached.
+async fn deliver(
+  state: AppState,
+  webhook: WebhookConfig,
+  id: u64,
+  event: WebhookEvent,
+  body: Vec<u8>,
+) {
+  let event_name = serde_json::to_value(event)
+    .ok()
+    .and_then(|name| name.as_str().map(str::to_string))
+    .unwrap_or_default();
+  let signature = webhook.secret.as_ref().map(|secret| sign(secret, &body));
+  let mut backoff = INITIAL_BACKOFF;
+
+  for attempt in 1..=webhook.max_attempts.max(1) {
+    let mut request = state
+      .webhooks
+      .client
+      .post(&webhook.url)
+      .header(header::CONTENT_TYPE, "application/json")
+      .header("X-Castiel-Event", &event_name)
+      .header("X-Castiel-Delivery", id.to_string())
+      .body(body.clone());
+    if let Some(signature) = &signature {
+      request = request.header("X-Castiel-Signature", signature);
+    }
+
+    let (retry, status_code, error) = match request.send().await {
+      Ok(response) if response.status().is_success() => {
+        tracing::debug!("Delivered {event_name} event to webhook {}", webhook.name);
+        state
+          .webhooks
+          .record_attempt(id, DeliveryStatus::Delivered, Some(response.status()), None);
+        return;
+      }
+      Ok(response) => {
+        let status = response.status();
+        // Other client errors mean the webhook will never accept the event
+        let retry = status.is_server_error()
+          || matches!(
+            status,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+          );
+        (
+          retry,
+          Some(status),
+          format!("the webhook answered {status}"),
+        )
+      }
+      // The error can quote the URL, which may be a credential
+      Err(err) => (true, None, describe_error(&err)),
+    };
+
+    let give_up = !retry || attempt >= webhook.max_attempts;
+    let status = if give_up {
+      DeliveryStatus::Failed
+    } else {
+      DeliveryStatus::Pending
+    };
+    state
+      .webhooks
+      .record_attempt(id, status, status_code, Some(error.clone()));
+    if give_up {
+      tracing::warn!(
+        "Failed to deliver {event_name} event to webhook {} after {attempt} attempts: {error}",
+        webhook.name
+      );
+      return;
+    }
+
+    tokio::time::sleep(backoff).await;
+    backoff = (backoff * 2).min(MAX_BACKOFF);
+  }
+}
+
+/// Describes a request error without the URL it was sent to.
+fn describe_error(err: &reqwest::Error) -> String {
+  if err.is_timeout() {
+    "the request timed out".to_string()
+  } else if err.is_connect() {
+    "the webhook could not be reached".to_string()
+  } else {
+    "the request failed".to_string()
+  }
+}
+
+/// The `X-Castiel-Signature` header value for `body`: `sha256=` followed by the hex-encoded
+/// HMAC-SHA256 of the body, keyed with `secret`.
+fn sign(secret: &str, body: &[u8]) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+  let tag = hmac::sign(&key, body);
+  let hex: String = tag
+    .as_ref()
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect();
+  format!("sha256={hex}")
+}
+
+/// Sends events for the changes between two background status snapshots.
+pub fn notify_status_changes(
+  state: &AppState,
+  previous: &StatusSnapshot,
+  current: &StatusSnapshot,
+) {
+  for event in status_change_events(previous, current) {
+    notify(state, event);
+  }
+}
+
+/// The events for the changes between two background status snapshots. Devices missing from
+/// either snapshot are left out.
+fn status_change_events(previous: &StatusSnapshot, current: &StatusSnapshot) -> Vec<Event> {
+  let mut events = Vec::new();
+  for report in &current.devices {
+    let Some(before) = previous
+      .devices
+      .iter()
+      .find(|before| before.device.fullname == report.device.fullname)
+    else {
+      continue;
+    };
+    let device = EventDevice::from(&report.device);
+
+    match (&before.device_status, &report.device_status) {
+      (Some(_), None) => {
+        events.push(Event::new(
+          WebhookEvent::DeviceLost,
+          Some(device),
+          json!({ "error": report.error }),
+        ));
+        continue;
+      }
+      (Some(before_status), Some(status)) => {
+        let app_of = |status: &DeviceStatus| {
+          status
+            .app_status()
+            .map(|app| json!({ "id": app.id(), "name": app.display_name() }))
+        };
+        let app_id = |status: &DeviceStatus| status.app_status().map(|app| app.id().to_string());
+        // Receivers report their name once loaded, which isn't a change of app
+        if app_id(before_status) != app_id(status) {
+          let (from, to) = (app_of(before_status), app_of(status));
+          events.push(Event::new(
+            WebhookEvent::AppChanged,
+            Some(device.clone()),
+            json!({ "from": from, "to": to }),
+          ));
+        }
+      }
+      _ => {}
+    }
+
+    let failed = |report: &DeviceReport| {
+      report.media_status.as_ref().is_some_and(|media| {
+        media.player_state() == PlayerState::Idle
+          && matches!(media.idle_reason(), Some(IdleReason::Error))
+      })
+    };
+    if failed(report) && !failed(before) {
+      let content_id = report
+        .media_status
+        .as_ref()
+        .and_then(|media| media.content_id());
+      events.push(Event::new(
+        WebhookEvent::MediaError,
+        Some(device),
+        json!({ "content_id": content_id }),
+      ));
+    }
+  }
+  events
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::devices::status::MediaStatus;
+
+  fn report(name: &str, app: Option<(&str, &str)>, media: Option<MediaStatus>) -> DeviceReport {
+    let applications: Vec<Value> = app
+      .into_iter()
+      .map(|(id, display_name)| {
+        json!({
+          "appId": id,
+          "displayName": display_name,
+          "sessionId": "session",
+          "transportId": "transport",
+        })
+      })
+      .collect();
+    DeviceReport {
+      device: DiscoveredDevice {
+        ip_address: "192.168.1.20".to_string(),
+        port: 8009,
+        fullname: format!("{name}._googlecast._tcp.local."),
+        id: None,
+        model_name: None,
+        friendly_name: Some(name.to_string()),
+        txt_properties: HashMap::new(),
+      },
+      device_status: Some(DeviceStatus::from_reply(
+        json!({ "applications": applications }),
+      )),
+      media_status: media,
+      error: None,
+    }
+  }
+
+  fn lost(name: &str) -> DeviceReport {
+    DeviceReport {
+      device_status: None,
+      ..report(name, None, None)
+    }
+  }
+
+  fn failed_media(content_id: &str) -> MediaStatus {
+    MediaStatus::from_reply(json!([{
+      "mediaSessionId": 1,
+      "playerState": "IDLE",
+      "idleReason": "ERROR",
+      "media": { "contentId": content_id },
+    }]))
+  }
+
+  fn snapshot(devices: Vec<DeviceReport>) -> StatusSnapshot {
+    StatusSnapshot {
+      generated_at: 0,
+      devices,
+    }
+  }
+
+  fn kinds(events: &[Event]) -> Vec<WebhookEvent> {
+    events.iter().map(|event| event.event).collect()
+  }
+
+  #[test]
+  fn signatures_are_the_hex_encoded_hmac_of_the_body() {
+    // RFC 4231, test case 2
+    assert_eq!(
+      sign("Jefe", b"what do ya want for nothing?"),
+      "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert_ne!(sign("Jefe", b"{}"), sign("other", b"{}"));
+  }
+
+  #[test]
+  fn devices_which_stop_answering_are_lost() {
+    let previous = snapshot(vec![report("Kitchen", None, None), lost("Lounge")]);
+    let current = snapshot(vec![lost("Kitchen"), lost("Lounge")]);
+
+    let events = status_change_events(&previous, &current);
+    assert_eq!(kinds(&events), [WebhookEvent::DeviceLost]);
+    let device = events[0].device.as_ref().unwrap();
+    assert_eq!(device.address, "192.168.1.20:8009");
+    assert_eq!(device.name.as_deref(), Some("Kitchen"));
+  }
+
+  #[test]
+  fn app_changes_are_compared_by_app_id() {
+    let previous = snapshot(vec![
+      report("Kitchen", Some(("E8C28D3C", "Backdrop")), None),
+      report("Lounge", Some(("CC1AD845", "")), None),
+    ]);
+    // The Lounge receiver only reported its name once loaded
+    let current = snapshot(vec![
+      report(
+        "Kitchen",
+        Some(("CC1AD845", "Default Media Receiver")),
+        None,
+      ),
+      report("Lounge", Some(("CC1AD845", "Default Media Receiver")), None),
+    ]);
+
+    let events = status_change_events(&previous, &current);
+    assert_eq!(kinds(&events), [WebhookEvent::AppChanged]);
+    assert_eq!(events[0].details["from"]["id"], "E8C28D3C");
+    assert_eq!(events[0].details["to"]["id"], "CC1AD845");
+    assert_eq!(events[0].details["to"]["name"], "Default Media Receiver");
+  }
+
+  #[test]
+  fn media_errors_are_sent_once() {
+    let app = Some(("CC1AD845", "Default Media Receiver"));
+    let playing = MediaStatus::from_reply(json!([{ "playerState": "PLAYING" }]));
+    let previous = snapshot(vec![report("Kitchen", app, Some(playing))]);
+    let current = snapshot(vec![report(
+      "Kitchen",
+      app,
+      Some(failed_media("http://example.com/a.mp4")),
+    )]);
+
+    let events = status_change_events(&previous, &current);
+    assert_eq!(kinds(&events), [WebhookEvent::MediaError]);
+    assert_eq!(events[0].details["content_id"], "http://example.com/a.mp4");
+
+    assert!(status_change_events(&current, &current).is_empty());
+  }
+
+  #[test]
+  fn devices_missing_from_the_previous_snapshot_are_skipped() {
+    let previous = snapshot(Vec::new());
+    let current = snapshot(vec![lost("Kitchen")]);
+    assert!(status_change_events(&previous, &current).is_empty());
+  }
+}